@@ -1,27 +1,44 @@
 extern crate proc_macro;
 
 use proc_macro::TokenStream;
-use quote::quote;
-use syn::{parse_macro_input, ItemFn};
+use quote::{format_ident, quote};
+use syn::{parse_macro_input, FnArg, Ident, ItemFn, Pat, Signature, Type};
 
-#[proc_macro_attribute]
-pub fn check_authorization(_attr: TokenStream, item: TokenStream) -> TokenStream {
+/// The first argument of `sig` and the name of its type, which becomes the
+/// audit target of a rejected call if it is a collection name or a principal.
+fn first_arg(sig: &Signature) -> Option<(&Ident, String)> {
+    let FnArg::Typed(arg) = sig.inputs.first()? else {
+        return None;
+    };
+    let (Pat::Ident(pat), Type::Path(ty)) = (arg.pat.as_ref(), arg.ty.as_ref()) else {
+        return None;
+    };
+    let ty = ty.path.segments.last()?.ident.to_string();
+    Some((&pat.ident, ty))
+}
+
+/// Wraps the function body in a check that the caller holds `role`, as decided by
+/// `crate::database::users::has_role`. `canister_inspect_message` uses the same
+/// function, so both layers always agree on who may call what. Rejected calls
+/// are reported to `crate::database::users::reject`.
+fn guard(role: &str, item: TokenStream) -> TokenStream {
     let input = parse_macro_input!(item as ItemFn);
+    let role = format_ident!("{}", role);
 
-    let params = &input.sig.inputs;
-    let name = &input.sig.ident;
-    let return_type = &input.sig.output;
+    let vis = &input.vis;
+    let sig = &input.sig;
     let body = &input.block;
+    let method = sig.ident.to_string();
+    let target = match first_arg(sig) {
+        Some((arg, ty)) if ty == "String" => quote!(&#arg),
+        Some((arg, ty)) if ty == "Principal" => quote!(&#arg.to_text()),
+        _ => quote!(""),
+    };
     let output = quote! {
-        fn #name(#params) #return_type {
+        #vis #sig {
             let caller = ic_cdk::caller();
-            let owner_principal = OWNER.with(|owner| owner.borrow().clone());
-            let admins = ADMINS.with(|admins| {
-                admins.borrow().iter().map(|(k, _)| k.0).collect::<Vec<_>>()
-            });
-
-            if (!(caller.to_string() == owner_principal || admins.contains(&caller))) {
-                return Err(Error::Unauthorized);
+            if !crate::database::users::has_role(&caller, crate::database::users::Role::#role) {
+                return Err(crate::database::users::reject(#method, &caller, #target));
             }
             #body
         }
@@ -30,24 +47,19 @@ pub fn check_authorization(_attr: TokenStream, item: TokenStream) -> TokenStream
     TokenStream::from(output)
 }
 
+/// Allows the owner, the canister-wide admins and the namespace admins.
 #[proc_macro_attribute]
-pub fn check_is_owner(_attr: TokenStream, item: TokenStream) -> TokenStream {
-    let input = parse_macro_input!(item as ItemFn);
+pub fn check_authorization(_attr: TokenStream, item: TokenStream) -> TokenStream {
+    guard("Admin", item)
+}
 
-    let params = &input.sig.inputs;
-    let name = &input.sig.ident;
-    let return_type = &input.sig.output;
-    let body = &input.block;
-    let output = quote! {
-        fn #name(#params) #return_type {
-            let caller = ic_cdk::caller();
-            let owner_principal = OWNER.with(|owner| owner.borrow().clone());
-            if caller.to_string() != owner_principal {
-                return Err(Error::Unauthorized);
-            }
-            #body
-        }
-    };
+#[proc_macro_attribute]
+pub fn check_is_owner(_attr: TokenStream, item: TokenStream) -> TokenStream {
+    guard("Owner", item)
+}
 
-    TokenStream::from(output)
+/// Allows the owner and the auditors.
+#[proc_macro_attribute]
+pub fn check_is_auditor(_attr: TokenStream, item: TokenStream) -> TokenStream {
+    guard("Auditor", item)
 }
@@ -0,0 +1,75 @@
+//! Generates `required_role`, which `canister_inspect_message` uses to admit
+//! ingress calls, from the authorization macros on the canister methods, so
+//! that it always agrees with the checks the methods make themselves.
+use std::fmt::Write;
+use std::path::Path;
+use std::{env, fs};
+
+/// The authorization macros and the role each of them requires.
+const GUARDS: &[(&str, &str)] = &[
+    ("check_authorization", "Admin"),
+    ("check_is_owner", "Owner"),
+    ("check_is_auditor", "Auditor"),
+];
+
+/// Methods without an authorization macro: the HTTP gateway calls them
+/// anonymously, and they authenticate requests by API key instead.
+const UNGUARDED: &[&str] = &["http_request", "http_request_update"];
+
+fn has_attr(item: &syn::ItemFn, name: &str) -> bool {
+    item.attrs.iter().any(|attr| attr.path().is_ident(name))
+}
+
+/// Collects the `(method, role)` pairs of the canister methods in `path`.
+fn collect(path: &Path, roles: &mut Vec<(String, &'static str)>) {
+    if path.is_dir() {
+        let mut entries: Vec<_> = fs::read_dir(path)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        entries.sort();
+        for entry in entries {
+            collect(&entry, roles);
+        }
+        return;
+    }
+    if path.extension().is_none_or(|extension| extension != "rs") {
+        return;
+    }
+    let source = fs::read_to_string(path).unwrap();
+    let file = syn::parse_file(&source)
+        .unwrap_or_else(|error| panic!("failed to parse {}: {}", path.display(), error));
+    for item in file.items {
+        let syn::Item::Fn(item) = item else {
+            continue;
+        };
+        if !has_attr(&item, "update") && !has_attr(&item, "query") {
+            continue;
+        }
+        let method = item.sig.ident.to_string();
+        match GUARDS.iter().find(|(guard, _)| has_attr(&item, guard)) {
+            Some((_, role)) => roles.push((method, role)),
+            None if UNGUARDED.contains(&method.as_str()) => {}
+            None => panic!(
+                "{} in {} has no authorization macro",
+                method,
+                path.display()
+            ),
+        }
+    }
+}
+
+fn main() {
+    println!("cargo:rerun-if-changed=src");
+    let mut roles = vec![];
+    collect(Path::new("src"), &mut roles);
+
+    let mut code = String::from("fn required_role(method: &str) -> Option<Role> {\n");
+    code.push_str("    match method {\n");
+    for (method, role) in roles {
+        writeln!(code, "        {:?} => Some(Role::{}),", method, role).unwrap();
+    }
+    code.push_str("        _ => None,\n    }\n}\n");
+    let out = Path::new(&env::var("OUT_DIR").unwrap()).join("roles.rs");
+    fs::write(out, code).unwrap();
+}
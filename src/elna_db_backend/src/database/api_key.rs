@@ -0,0 +1,96 @@
+use crate::database::audit::{self, Operation};
+use crate::database::error::Error;
+use crate::database::memory::{get_api_keys_memory, Memory};
+use crate::database::users::StorablePrincipal;
+use candid::Principal;
+use elna_auth_macros::check_is_owner;
+use ic_cdk::api::management_canister::main::raw_rand;
+use ic_cdk::update;
+use ic_stable_structures::StableBTreeMap;
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+use std::fmt::Write;
+
+/// Prefix of every issued key, so leaked keys are easy to recognise.
+const KEY_PREFIX: &str = "elna_";
+
+thread_local! {
+    /// Maps the SHA-256 hash of each API key to the principal it acts as. The
+    /// keys themselves are only returned once, by `create_api_key`.
+    pub static API_KEYS: RefCell<StableBTreeMap<String, StorablePrincipal, Memory>> = RefCell::new(init_stable_data());
+}
+
+fn init_stable_data() -> StableBTreeMap<String, StorablePrincipal, Memory> {
+    StableBTreeMap::init(get_api_keys_memory())
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut hex, byte| {
+        let _ = write!(hex, "{:02x}", byte);
+        hex
+    })
+}
+
+fn hash(key: &str) -> String {
+    to_hex(&Sha256::digest(key.as_bytes()))
+}
+
+/// Resolves an API key to the principal it was issued for. The principal still
+/// needs the role required by the operation it is used for.
+pub fn principal_of(key: &str) -> Option<Principal> {
+    API_KEYS.with(|keys| keys.borrow().get(&hash(key)).map(|p| p.0))
+}
+
+/// Issues a new API key acting as `principal` over HTTP. Only the hash of the
+/// key is stored, so the returned key cannot be retrieved again.
+#[update]
+#[check_is_owner]
+async fn create_api_key(principal: Principal) -> Result<String, Error> {
+    let (bytes,) = raw_rand()
+        .await
+        .unwrap_or_else(|(_, message)| ic_cdk::trap(&message));
+    let key = format!("{}{}", KEY_PREFIX, to_hex(&bytes));
+    API_KEYS.with(|keys| {
+        keys.borrow_mut()
+            .insert(hash(&key), StorablePrincipal(principal))
+    });
+    let result = Ok(());
+    audit::record(Operation::CreateApiKey, &principal.to_text(), &result);
+    result.map(|()| key)
+}
+
+/// Revokes every API key issued for `principal`.
+#[update]
+#[check_is_owner]
+fn revoke_api_keys(principal: Principal) -> Result<(), Error> {
+    let result = API_KEYS.with(|keys| {
+        let mut keys = keys.borrow_mut();
+        let hashes = keys
+            .iter()
+            .filter(|(_, p)| p.0 == principal)
+            .map(|(hash, _)| hash)
+            .collect::<Vec<_>>();
+        if hashes.is_empty() {
+            return Err(Error::NotFound);
+        }
+        for hash in hashes {
+            keys.remove(&hash);
+        }
+        Ok(())
+    });
+    audit::record(Operation::RevokeApiKeys, &principal.to_text(), &result);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::hash;
+
+    #[test]
+    fn hash_is_hex_sha256() {
+        assert_eq!(
+            hash("abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+}
@@ -0,0 +1,364 @@
+use crate::database::clock;
+use crate::database::error::Error;
+use crate::database::memory::{get_audit_log_data_memory, get_audit_log_index_memory, Memory};
+use candid::{CandidType, Decode, Deserialize, Encode, Principal};
+use elna_auth_macros::check_is_auditor;
+use ic_cdk::query;
+use ic_stable_structures::{storable::Bound, StableLog, Storable};
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+/// Upper bound on the number of entries returned by a single `get_audit_log` call.
+const MAX_PAGE_SIZE: u64 = 500;
+
+/// Upper bound on the number of entries a single `get_audit_log` call reads,
+/// matching or not, so its cost does not grow with the log.
+const MAX_SCANNED_ENTRIES: u64 = 5_000;
+
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub enum Operation {
+    CreateCollection,
+    CreateIndex,
+    Insert,
+    BuildIndex,
+    DeleteCollection,
+    AddAdmin,
+    RemoveAdmin,
+    AddAuditor,
+    RemoveAuditor,
+    CreateNamespace,
+    DeleteNamespace,
+    AddNamespaceAdmin,
+    RemoveNamespaceAdmin,
+    SetQuota,
+    SetRateLimit,
+    CreateApiKey,
+    RevokeApiKeys,
+    ImportCollection,
+    CreateSnapshot,
+    RestoreSnapshot,
+    CloneSnapshot,
+    DeleteSnapshot,
+    RenameCollection,
+    CloneCollection,
+    SetAlias,
+    DeleteAlias,
+    TransferOwnership,
+    SetPrecision,
+    SetNormalize,
+}
+
+impl Operation {
+    /// The operation recorded for calls of the update method `method`, if it
+    /// mutates state.
+    pub fn for_method(method: &str) -> Option<Operation> {
+        let operation = match method {
+            "create_collection" | "create_collection_with_fields" => Operation::CreateCollection,
+            "create_index" => Operation::CreateIndex,
+            "insert"
+            | "insert_with_fields"
+            | "insert_batch"
+            | "insert_sparse"
+            | "insert_multi_vector"
+            | "commit_upload" => Operation::Insert,
+            "build_index" => Operation::BuildIndex,
+            "delete_collection" => Operation::DeleteCollection,
+            "add_admin" => Operation::AddAdmin,
+            "remove_admin" => Operation::RemoveAdmin,
+            "add_auditor" => Operation::AddAuditor,
+            "remove_auditor" => Operation::RemoveAuditor,
+            "create_namespace" => Operation::CreateNamespace,
+            "delete_namespace" => Operation::DeleteNamespace,
+            "add_namespace_admin" => Operation::AddNamespaceAdmin,
+            "remove_namespace_admin" => Operation::RemoveNamespaceAdmin,
+            "set_collection_quota" | "set_namespace_quota" => Operation::SetQuota,
+            "set_rate_limit" => Operation::SetRateLimit,
+            "create_api_key" => Operation::CreateApiKey,
+            "revoke_api_keys" => Operation::RevokeApiKeys,
+            "commit_import" => Operation::ImportCollection,
+            "create_snapshot" => Operation::CreateSnapshot,
+            "restore_snapshot" => Operation::RestoreSnapshot,
+            "clone_snapshot" => Operation::CloneSnapshot,
+            "delete_snapshot" => Operation::DeleteSnapshot,
+            "rename_collection" => Operation::RenameCollection,
+            "clone_collection" => Operation::CloneCollection,
+            "set_alias" => Operation::SetAlias,
+            "delete_alias" => Operation::DeleteAlias,
+            "transfer_ownership" => Operation::TransferOwnership,
+            "set_precision" => Operation::SetPrecision,
+            "set_normalize" => Operation::SetNormalize,
+            _ => return None,
+        };
+        Some(operation)
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, PartialEq, Debug)]
+pub struct AuditEntry {
+    pub id: u64,
+    pub caller: Principal,
+    /// Nanoseconds since the UNIX epoch, as reported by the IC.
+    pub timestamp: u64,
+    pub operation: Operation,
+    /// Collection name or principal the operation was applied to.
+    pub target: String,
+    /// `None` if the call succeeded, otherwise the error it returned.
+    pub error: Option<String>,
+}
+
+impl Storable for AuditEntry {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(&bytes, Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(CandidType, Deserialize, Clone, Default, Debug)]
+pub struct AuditFilter {
+    pub caller: Option<Principal>,
+    pub operation: Option<Operation>,
+    pub target: Option<String>,
+    /// Inclusive lower bound on the entry timestamp.
+    pub from: Option<u64>,
+    /// Exclusive upper bound on the entry timestamp.
+    pub to: Option<u64>,
+}
+
+impl AuditFilter {
+    pub fn matches(&self, entry: &AuditEntry) -> bool {
+        self.caller.is_none_or(|c| c == entry.caller)
+            && self.operation.is_none_or(|op| op == entry.operation)
+            && self.target.as_ref().is_none_or(|t| *t == entry.target)
+            && self.from.is_none_or(|from| entry.timestamp >= from)
+            && self.to.is_none_or(|to| entry.timestamp < to)
+    }
+}
+
+/// A page of matching entries, newest first.
+#[derive(CandidType, Deserialize, Clone, Default, Debug)]
+pub struct AuditPage {
+    pub entries: Vec<AuditEntry>,
+    /// The `before_id` to pass for the next page, or `None` once the log has
+    /// been read to the start. A page can be short or empty and still have a
+    /// next one if few of the entries read matched.
+    pub next_before_id: Option<u64>,
+}
+
+thread_local! {
+    pub static AUDIT_LOG: RefCell<StableLog<AuditEntry, Memory, Memory>> = RefCell::new(init_stable_log());
+}
+
+fn init_stable_log() -> StableLog<AuditEntry, Memory, Memory> {
+    StableLog::init(get_audit_log_index_memory(), get_audit_log_data_memory())
+        .expect("failed to initialize audit log")
+}
+
+/// Appends the outcome of a mutating call made by the current caller to the audit log.
+pub fn record<T>(operation: Operation, target: &str, result: &Result<T, Error>) {
+    record_as(ic_cdk::caller(), operation, target, result)
+}
+
+/// Like `record`, for calls made on behalf of `caller`, e.g. through an HTTP API key.
+pub fn record_as<T>(
+    caller: Principal,
+    operation: Operation,
+    target: &str,
+    result: &Result<T, Error>,
+) {
+    AUDIT_LOG.with(|log| {
+        let log = log.borrow_mut();
+        let entry = AuditEntry {
+            id: log.len(),
+            caller,
+            timestamp: clock::now(),
+            operation,
+            target: target.to_string(),
+            error: result.as_ref().err().map(|e| e.to_string()),
+        };
+        log.append(&entry).expect("failed to append to audit log");
+    })
+}
+
+/// Returns up to `limit` entries matching `filter` with an id below
+/// `before_id`, newest first, reading at most `MAX_SCANNED_ENTRIES` entries.
+fn page(filter: &AuditFilter, before_id: Option<u64>, limit: u64) -> AuditPage {
+    let limit = limit.min(MAX_PAGE_SIZE) as usize;
+    AUDIT_LOG.with(|log| {
+        let log = log.borrow();
+        let end = before_id.map_or(log.len(), |id| id.min(log.len()));
+        let start = end.saturating_sub(MAX_SCANNED_ENTRIES);
+        let mut page = AuditPage::default();
+        let mut next = start;
+        for id in (start..end).rev() {
+            if page.entries.len() == limit {
+                next = id + 1;
+                break;
+            }
+            let entry = log.get(id).expect("audit log entry out of range");
+            // Timestamps only grow along the log, so nothing older matches.
+            if filter.from.is_some_and(|from| entry.timestamp < from) {
+                next = 0;
+                break;
+            }
+            if filter.matches(&entry) {
+                page.entries.push(entry);
+            }
+        }
+        page.next_before_id = (next > 0).then_some(next);
+        page
+    })
+}
+
+/// Returns a page of the entries matching `filter`, newest first. Pass
+/// `before_id = None` for the first page and the returned `next_before_id`
+/// for the following ones.
+#[query]
+#[check_is_auditor]
+fn get_audit_log(
+    filter: AuditFilter,
+    before_id: Option<u64>,
+    limit: u64,
+) -> Result<AuditPage, Error> {
+    Ok(page(&filter, before_id, limit))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{page, record_as, AuditEntry, AuditFilter, Operation, AUDIT_LOG};
+    use crate::database::error::Error;
+    use crate::database::{operations, users};
+    use candid::Principal;
+
+    fn entries() -> Vec<AuditEntry> {
+        AUDIT_LOG.with(|log| log.borrow().iter().collect())
+    }
+
+    #[test]
+    fn records_outcomes_in_order() {
+        let caller = Principal::from_slice(&[1]);
+        record_as(caller, Operation::CreateCollection, "docs", &Ok(()));
+        record_as::<()>(caller, Operation::Insert, "docs", &Err(Error::NotFound));
+
+        let entries = entries();
+        assert_eq!(entries.len(), 2);
+        assert_eq!((entries[0].id, entries[1].id), (0, 1));
+        assert_eq!(entries[0].operation, Operation::CreateCollection);
+        assert_eq!(entries[0].error, None);
+        assert_eq!(entries[1].error, Some(Error::NotFound.to_string()));
+        assert!(entries[0].timestamp <= entries[1].timestamp);
+    }
+
+    #[test]
+    fn filters_match_every_given_field() {
+        let caller = Principal::from_slice(&[1]);
+        let entry = AuditEntry {
+            id: 0,
+            caller,
+            timestamp: 10,
+            operation: Operation::Insert,
+            target: "docs".to_string(),
+            error: None,
+        };
+        assert!(AuditFilter::default().matches(&entry));
+        let filter = AuditFilter {
+            caller: Some(caller),
+            operation: Some(Operation::Insert),
+            target: Some("docs".to_string()),
+            from: Some(10),
+            to: Some(11),
+        };
+        assert!(filter.matches(&entry));
+        for filter in [
+            AuditFilter {
+                caller: Some(Principal::anonymous()),
+                ..filter.clone()
+            },
+            AuditFilter {
+                operation: Some(Operation::BuildIndex),
+                ..filter.clone()
+            },
+            AuditFilter {
+                target: Some("other".to_string()),
+                ..filter.clone()
+            },
+            AuditFilter {
+                from: Some(11),
+                ..filter.clone()
+            },
+            AuditFilter {
+                to: Some(10),
+                ..filter.clone()
+            },
+        ] {
+            assert!(!filter.matches(&entry));
+        }
+    }
+
+    #[test]
+    fn pages_walk_back_from_the_cursor() {
+        let caller = Principal::from_slice(&[1]);
+        for target in ["a", "b", "a", "b", "a"] {
+            record_as(caller, Operation::Insert, target, &Ok(()));
+        }
+        let filter = AuditFilter {
+            target: Some("a".to_string()),
+            ..AuditFilter::default()
+        };
+
+        let first = page(&filter, None, 2);
+        let ids: Vec<u64> = first.entries.iter().map(|entry| entry.id).collect();
+        assert_eq!(ids, [4, 2]);
+        assert_eq!(first.next_before_id, Some(2));
+
+        let second = page(&filter, first.next_before_id, 2);
+        let ids: Vec<u64> = second.entries.iter().map(|entry| entry.id).collect();
+        assert_eq!(ids, [0]);
+        assert_eq!(second.next_before_id, None);
+
+        let all = page(&AuditFilter::default(), Some(3), 10);
+        assert_eq!(all.entries.len(), 3);
+        assert_eq!(all.next_before_id, None);
+    }
+
+    #[test]
+    fn records_calls_rejected_by_the_authorization_macros() {
+        let caller = Principal::from_slice(&[1]);
+        assert_eq!(
+            users::reject("add_admin", &caller, "aaaaa-aa"),
+            Error::Unauthorized
+        );
+        // Queries change nothing and are not recorded.
+        users::reject("get_admins", &caller, "");
+
+        let entries = entries();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].operation, Operation::AddAdmin);
+        assert_eq!(entries[0].target, "aaaaa-aa");
+        assert_eq!(entries[0].error, Some(Error::Unauthorized.to_string()));
+    }
+
+    #[test]
+    fn records_calls_failing_before_reaching_the_database() {
+        // Not an admin of any namespace, so the name cannot be scoped.
+        let caller = Principal::from_slice(&[1]);
+        let result = operations::insert(caller, "docs", vec![], vec![], String::new());
+        assert_eq!(result, Err(Error::Unauthorized));
+        let result = operations::rename_collection(caller, "docs", "papers");
+        assert_eq!(result, Err(Error::Unauthorized));
+
+        let entries = entries();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].operation, Operation::Insert);
+        assert_eq!(entries[0].target, "docs");
+        assert_eq!(entries[1].operation, Operation::RenameCollection);
+        assert_eq!(entries[1].target, "docs -> papers");
+        assert!(entries
+            .iter()
+            .all(|entry| entry.error == Some(Error::Unauthorized.to_string())));
+    }
+}
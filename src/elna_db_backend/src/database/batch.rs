@@ -0,0 +1,84 @@
+//! Batch inserts reporting the outcome of every record, either rejecting the
+//! whole batch at the first invalid record or inserting the valid ones.
+use crate::database::error::Error;
+use crate::database::sparse::SparseVector;
+use candid::CandidType;
+use serde::Deserialize;
+
+#[derive(CandidType, Deserialize, Clone, Copy, Default, PartialEq, Debug)]
+pub enum InsertMode {
+    /// Inserts nothing and fails with the error of the first invalid record.
+    #[default]
+    AllOrNothing,
+    /// Inserts the valid records and reports the invalid ones.
+    BestEffort,
+}
+
+/// What became of one record of a batch. Ids are insertion positions, as in
+/// exports.
+#[derive(CandidType, Deserialize, Clone, PartialEq, Debug)]
+pub enum RowOutcome {
+    Inserted {
+        id: u64,
+    },
+    /// The record has the document and vectors of the record `id`, stored
+    /// before or earlier in the batch.
+    SkippedDuplicate {
+        id: u64,
+    },
+    InvalidDimension,
+    InvalidValues(String),
+}
+
+impl From<Error> for RowOutcome {
+    fn from(error: Error) -> Self {
+        match error {
+            Error::DimensionMismatch => RowOutcome::InvalidDimension,
+            Error::InvalidVector { reason, .. } | Error::InvalidSparseVector(reason) => {
+                RowOutcome::InvalidValues(reason)
+            }
+            error => RowOutcome::InvalidValues(error.to_string()),
+        }
+    }
+}
+
+/// The outcome of every record of a batch, in order, with their counts.
+#[derive(CandidType, Deserialize, Clone, Default, PartialEq, Debug)]
+pub struct InsertReport {
+    pub inserted: u64,
+    pub skipped: u64,
+    pub rejected: u64,
+    pub rows: Vec<RowOutcome>,
+}
+
+impl InsertReport {
+    pub fn push(&mut self, outcome: RowOutcome) {
+        match outcome {
+            RowOutcome::Inserted { .. } => self.inserted += 1,
+            RowOutcome::SkippedDuplicate { .. } => self.skipped += 1,
+            _ => self.rejected += 1,
+        }
+        self.rows.push(outcome);
+    }
+}
+
+/// The vectors of a record as they are stored: rounded to the precision of
+/// the collection, the fields in order of name, sparse vectors normalized and
+/// missing sparse vectors and token bags empty.
+#[derive(PartialEq)]
+pub struct StoredVectors {
+    pub key: Vec<f32>,
+    pub fields: Vec<Vec<f32>>,
+    pub sparse: SparseVector,
+    pub tokens: Vec<Vec<f32>>,
+}
+
+/// One record of a batch, with the vectors of the named fields in the order
+/// of the batch.
+pub struct Record {
+    pub key: Vec<f32>,
+    pub fields: Vec<Vec<f32>>,
+    pub sparse: Option<SparseVector>,
+    pub tokens: Option<Vec<Vec<f32>>>,
+    pub value: String,
+}
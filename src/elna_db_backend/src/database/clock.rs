@@ -0,0 +1,14 @@
+/// Current time in nanoseconds since the UNIX epoch. Outside of a canister,
+/// e.g. in unit tests, the system clock is used instead of the IC time.
+#[cfg(target_arch = "wasm32")]
+pub fn now() -> u64 {
+    ic_cdk::api::time()
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub fn now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or_default()
+}
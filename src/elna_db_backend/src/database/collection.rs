@@ -1,9 +1,18 @@
-use super::index::{generate_index, Vector};
+use super::batch::{Record, StoredVectors};
+use super::clock;
+use super::error::Error;
+use super::export::{
+    ExportHeader, ExportPage, ExportRecord, FORMAT_VERSION, MAX_PAGE_BYTES, MAX_PAGE_RECORDS,
+};
+use super::field::{FieldQuery, VectorField, VectorFieldInfo, VectorFieldSpec, PRIMARY_FIELD};
+use super::graph::Graph;
+use super::index::{self, IndexParams, Metric, MultiVector, Precision, Vector};
+use super::quota::Quota;
+use super::sparse::{HybridQuery, SparseIndex, SparseVector};
+use candid::CandidType;
 use ciborium::de;
-use ic_stable_structures::{storable::Bound, Storable};
-use instant_distance::{HnswMap, Search};
 use serde::{Deserialize, Serialize};
-use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet};
 use std::{collections::HashSet, usize};
 
 #[derive(Serialize, Deserialize)]
@@ -15,59 +24,280 @@ pub struct Metadata {
 pub struct Collection {
     pub dimension: usize,
     pub metadata: Metadata,
-    inner: HnswMap<Vector, String>,
+    #[serde(default)]
+    pub quota: Quota,
+    #[serde(default)]
+    pub metric: Metric,
+    #[serde(default)]
+    pub index_params: IndexParams,
+    /// Format the vectors are stored in, changed with `set_precision`.
+    #[serde(default)]
+    pub precision: Precision,
+    /// Whether vectors are scaled to unit length when inserted and queried,
+    /// changed with `set_normalize`.
+    #[serde(default)]
+    pub normalize: bool,
+    /// Time of the last `build_index`, in nanoseconds since the UNIX epoch.
+    #[serde(default)]
+    pub last_built_at: Option<u64>,
+    /// The index over the first `graph.len()` vectors, kept in stable memory.
+    #[serde(default)]
+    graph: Graph,
     keys: Vec<Vector>,
     values: Vec<String>,
+    /// Named vectors stored for every record in addition to `keys`.
+    #[serde(default)]
+    fields: BTreeMap<String, VectorField>,
+    /// Sparse vectors, one per record once the first one is inserted.
+    #[serde(default)]
+    sparse: Option<SparseIndex>,
+    /// Bags of token vectors for late-interaction reranking, one per record
+    /// once the first one is inserted.
+    #[serde(default)]
+    tokens: Option<Vec<MultiVector>>,
 }
 
-impl Storable for Collection {
-    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
-        let mut bytes = vec![];
-        ciborium::ser::into_writer(self, &mut bytes).unwrap();
-        Cow::Owned(bytes)
-    }
+/// Rough per-point size of the HNSW neighbour lists in stable memory, used to
+/// estimate memory usage.
+const GRAPH_BYTES_PER_POINT: u64 = 256;
 
-    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
-        let canister_wasm: Collection = de::from_reader(bytes.as_ref()).unwrap();
-        canister_wasm
-    }
+/// Size of a sparse entry, held once in its vector and once in the inverted index.
+const SPARSE_BYTES_PER_ENTRY: u64 = 16;
 
-    const BOUND: Bound = Bound::Unbounded;
+#[derive(CandidType, Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct CollectionInfo {
+    pub dimension: u64,
+    pub metric: Metric,
+    pub precision: Precision,
+    pub normalize: bool,
+    pub vector_count: u64,
+    pub indexed_count: u64,
+    pub unindexed_count: u64,
+    pub document_bytes: u64,
+    pub file_count: u64,
+    pub index_params: IndexParams,
+    pub last_built_at: Option<u64>,
+    /// Approximate memory usage of the vectors and documents on the heap and of
+    /// the indexes in stable memory, in bytes.
+    pub memory_bytes: u64,
+    pub fields: Vec<VectorFieldInfo>,
+    /// Number of non-zero entries across the sparse vectors.
+    pub sparse_entries: u64,
+    /// Number of token vectors across the bags of the records.
+    pub token_count: u64,
 }
 
-// impl Storable for Collection {
-//     fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
-//         Decode!(&bytes, Self).unwrap()
-//     }
-
-//     fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
-//         std::borrow::Cow::Owned(Encode!(&self).unwrap())
-//     }
-
-//     const BOUND: Bound = Bound::Unbounded;
-// }
-
 impl Collection {
     pub fn new(keys: Vec<Vector>, values: Vec<String>, dimension: usize) -> Self {
         Collection {
-            keys: keys.clone(),
-            values: values.clone(),
-            inner: generate_index(keys, values),
+            graph: Graph::build(&keys, Metric::default(), &IndexParams::default()),
+            keys,
+            values,
             dimension,
             metadata: Metadata {
                 file_names: HashSet::new(),
             },
+            quota: Quota::default(),
+            metric: Metric::default(),
+            index_params: IndexParams::default(),
+            precision: Precision::default(),
+            normalize: false,
+            last_built_at: None,
+            fields: BTreeMap::new(),
+            sparse: None,
+            tokens: None,
+        }
+    }
+
+    /// Creates an empty collection with the named vector fields `specs`, which
+    /// must have passed `field::validate`.
+    pub fn with_fields(dimension: usize, metric: Metric, specs: &[VectorFieldSpec]) -> Self {
+        let mut collection = Self::new(vec![], vec![], dimension);
+        collection.metric = metric;
+        collection.fields = specs
+            .iter()
+            .map(|spec| (spec.name.clone(), VectorField::new(spec, vec![])))
+            .collect();
+        collection
+    }
+
+    /// Rebuilds a collection from the header and the records of an export.
+    /// `fields` holds the vectors of each field of the header, in its order,
+    /// and `sparse` and `tokens` the sparse and token vectors if the header
+    /// announces them.
+    /// `index` is the encoded index returned by `index_chunk`; without it the
+    /// collection starts out unindexed. The indexes of the fields are not
+    /// exported and are built over the same vectors as the primary one.
+    pub fn from_export(
+        header: &ExportHeader,
+        keys: Vec<Vector>,
+        fields: Vec<Vec<Vector>>,
+        sparse: Option<Vec<SparseVector>>,
+        tokens: Option<Vec<MultiVector>>,
+        values: Vec<String>,
+        index: Option<&[u8]>,
+    ) -> Result<Self, Error> {
+        super::field::validate(&header.fields)
+            .map_err(|_| Error::InvalidImport("invalid vector fields".to_string()))?;
+        if fields.len() != header.fields.len()
+            || fields.iter().any(|field| field.len() != keys.len())
+        {
+            return Err(Error::InvalidImport(
+                "vector fields do not match the records".to_string(),
+            ));
+        }
+        if sparse.as_ref().map(Vec::len).unwrap_or(keys.len()) != keys.len()
+            || sparse.is_some() != header.sparse
+        {
+            return Err(Error::InvalidImport(
+                "sparse vectors do not match the records".to_string(),
+            ));
+        }
+        if tokens.as_ref().map(Vec::len).unwrap_or(keys.len()) != keys.len()
+            || tokens.is_some() != header.multi_vector
+        {
+            return Err(Error::InvalidImport(
+                "token vectors do not match the records".to_string(),
+            ));
+        }
+        let graph = match index {
+            Some(bytes) => Graph::decode(bytes, keys.len())?,
+            None => Graph::default(),
+        };
+        let fields = header
+            .fields
+            .iter()
+            .zip(fields)
+            .map(|(spec, keys)| {
+                let mut field = VectorField::new(spec, keys);
+                field.graph = Graph::build(
+                    &field.keys[..graph.len()],
+                    field.metric.for_vectors(header.normalize),
+                    &header.index_params,
+                );
+                (spec.name.clone(), field)
+            })
+            .collect();
+
+        Ok(Collection {
+            dimension: header.dimension as usize,
+            metadata: Metadata {
+                file_names: header.file_names.iter().cloned().collect(),
+            },
+            quota: header.quota.clone(),
+            metric: header.metric,
+            index_params: header.index_params.clone(),
+            precision: header.precision,
+            normalize: header.normalize,
+            last_built_at: index.and(header.last_built_at),
+            graph,
+            keys,
+            values,
+            fields,
+            sparse: sparse.map(SparseIndex::from),
+            tokens,
+        })
+    }
+
+    /// Up to `length` bytes from `offset` of the HNSW index in the encoding of
+    /// `Graph::encode_range`, as accepted by `from_export`, and the encoded length.
+    pub fn index_chunk(&self, offset: u64, length: u64) -> (Vec<u8>, u64) {
+        (
+            self.graph.encode_range(offset, length),
+            self.graph.encoded_len(),
+        )
+    }
+
+    /// Encodes the collection. The indexes stay in stable memory and are
+    /// encoded by their handles only.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = vec![];
+        ciborium::ser::into_writer(self, &mut bytes).unwrap();
+        bytes
+    }
+
+    /// Decodes a collection encoded by `encode`. The result shares the indexes
+    /// of the encoded collection, so it must be copied before it is modified
+    /// and never cleared unless the encoded collection is discarded too.
+    /// Collections encoded before the index moved to stable memory decode
+    /// unindexed.
+    pub fn decode(bytes: &[u8]) -> Result<Self, Error> {
+        let mut collection: Collection = de::from_reader(bytes).map_err(|_| Error::MemoryError)?;
+        if collection.graph.is_empty() {
+            collection.last_built_at = None;
+        }
+        Ok(collection)
+    }
+
+    /// Length in bytes of the encodings of the indexes.
+    pub fn index_bytes(&self) -> u64 {
+        self.graph.encoded_len()
+            + self
+                .fields
+                .values()
+                .map(|field| field.graph.encoded_len())
+                .sum::<u64>()
+    }
+
+    /// Copies the collection, with a copy of its index if `with_index` is set.
+    pub fn copy(&self, with_index: bool) -> Self {
+        Collection {
+            dimension: self.dimension,
+            metadata: Metadata {
+                file_names: self.metadata.file_names.clone(),
+            },
+            quota: self.quota.clone(),
+            metric: self.metric,
+            index_params: self.index_params.clone(),
+            precision: self.precision,
+            normalize: self.normalize,
+            last_built_at: self.last_built_at.filter(|_| with_index),
+            graph: if with_index {
+                self.graph.copy()
+            } else {
+                Graph::default()
+            },
+            keys: self.keys.clone(),
+            values: self.values.clone(),
+            fields: self
+                .fields
+                .iter()
+                .map(|(name, field)| {
+                    let copy = VectorField {
+                        dimension: field.dimension,
+                        metric: field.metric,
+                        keys: field.keys.clone(),
+                        graph: if with_index {
+                            field.graph.copy()
+                        } else {
+                            Graph::default()
+                        },
+                    };
+                    (name.clone(), copy)
+                })
+                .collect(),
+            sparse: self.sparse.clone(),
+            tokens: self.tokens.clone(),
         }
     }
 
+    /// Number of vectors stored, indexed or not.
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn document_bytes(&self) -> u64 {
+        self.values.iter().map(|v| v.len() as u64).sum()
+    }
+
     pub fn append(
         &mut self,
         keys: &mut Vec<Vector>,
         values: &mut Vec<String>,
         file_name: String,
-    ) -> Result<(), String> {
+    ) -> Result<(), Error> {
         if keys.len() != values.len() {
-            return Err(String::from("length of keys not euqal to values'"));
+            return Err(Error::DimensionMismatch);
         }
         self.keys.append(keys);
         self.values.append(values);
@@ -76,19 +306,622 @@ impl Collection {
         Ok(())
     }
 
-    pub fn query(&self, key: &Vector, search: &mut Search, limit: i32) -> Vec<(f32, String)> {
-        let mut res: Vec<(f32, String)> = vec![];
-        let mut iter = self.inner.search(key, search);
-        for _ in 0..limit {
-            match iter.next() {
-                Some(v) => res.push((v.point.cos_sim(key), (*v.value).clone())),
-                None => break,
+    /// Fails unless `fields` holds, for every named field of the collection and
+    /// no other, `rows` vectors. Their dimensions are checked by
+    /// `prepare_record`.
+    pub fn check_fields(
+        &self,
+        rows: usize,
+        fields: &[(String, Vec<Vec<f32>>)],
+    ) -> Result<(), Error> {
+        let names = fields.iter().map(|(name, _)| name).collect::<BTreeSet<_>>();
+        if names.len() != fields.len() || !names.iter().copied().eq(self.fields.keys()) {
+            let expected = self.fields.keys().cloned().collect::<Vec<_>>();
+            return Err(Error::InvalidField(format!(
+                "expected vectors for the fields [{}]",
+                expected.join(", ")
+            )));
+        }
+        if fields.iter().any(|(_, vectors)| vectors.len() != rows) {
+            return Err(Error::DimensionMismatch);
+        }
+        Ok(())
+    }
+
+    /// Appends the vectors of the named fields for rows about to be appended
+    /// with `append`. `fields` must have passed `check_fields`.
+    pub fn append_fields(&mut self, fields: Vec<(String, Vec<Vec<f32>>)>) {
+        for (name, vectors) in fields {
+            if let Some(field) = self.fields.get_mut(&name) {
+                let precision = self.precision;
+                field.keys.extend(
+                    vectors
+                        .into_iter()
+                        .map(|vector| Vector::with_precision(vector, precision)),
+                );
+            }
+        }
+    }
+
+    /// Appends the sparse vectors of `rows` rows about to be appended with
+    /// `append`. Without `sparse` the rows get empty sparse vectors, if the
+    /// collection has any. `sparse` must hold `rows` normalized vectors.
+    pub fn append_sparse(&mut self, rows: usize, sparse: Option<Vec<SparseVector>>) {
+        if sparse.is_none() && self.sparse.is_none() {
+            return;
+        }
+        let existing = self.keys.len();
+        let index = self
+            .sparse
+            .get_or_insert_with(|| SparseIndex::with_empty_rows(existing));
+        for vector in sparse.unwrap_or_else(|| vec![SparseVector::default(); rows]) {
+            index.push(vector);
+        }
+    }
+
+    /// Appends the token vectors of `rows` rows about to be appended with
+    /// `append`, like `append_sparse`.
+    pub fn append_tokens(&mut self, rows: usize, tokens: Option<Vec<Vec<Vec<f32>>>>) {
+        if tokens.is_none() && self.tokens.is_none() {
+            return;
+        }
+        let existing = self.keys.len();
+        let bags = self
+            .tokens
+            .get_or_insert_with(|| vec![MultiVector::default(); existing]);
+        match tokens {
+            Some(tokens) => bags.extend(
+                tokens
+                    .into_iter()
+                    .map(|bag| MultiVector::with_precision(bag, self.precision)),
+            ),
+            None => bags.resize(existing + rows, MultiVector::default()),
+        }
+    }
+
+    /// Converts the stored vectors, including those of the named fields and
+    /// the token vectors, to `precision`. The indexes are kept as they are.
+    /// Fails with `Error::InvalidVector`, changing nothing, if a stored vector
+    /// does not pass `index::check` at `precision`, e.g. is out of its range.
+    pub fn set_precision(&mut self, precision: Precision) -> Result<(), Error> {
+        if precision == self.precision {
+            return Ok(());
+        }
+        let check = |values: &[f32], metric: Metric, row: usize| {
+            index::check(values, metric, precision).map_err(|reason| Error::InvalidVector {
+                row: row as u64,
+                reason: reason.to_string(),
+            })
+        };
+        for (row, key) in self.keys.iter().enumerate() {
+            check(&key.to_vec(), self.metric, row)?;
+        }
+        for field in self.fields.values() {
+            for (row, key) in field.keys.iter().enumerate() {
+                check(&key.to_vec(), field.metric, row)?;
+            }
+        }
+        for (row, bag) in self.tokens.iter().flatten().enumerate() {
+            for token in bag.to_vecs() {
+                check(&token, Metric::DotProduct, row)?;
+            }
+        }
+        for key in self.keys.iter_mut() {
+            *key = key.converted(precision);
+        }
+        for field in self.fields.values_mut() {
+            for key in field.keys.iter_mut() {
+                *key = key.converted(precision);
+            }
+        }
+        for bag in self.tokens.iter_mut().flatten() {
+            *bag = bag.converted(precision);
+        }
+        self.precision = precision;
+        Ok(())
+    }
+
+    /// Scales every stored vector, token vectors included, to unit length
+    /// when `normalize` is set, and rebuilds the indexes over the records they
+    /// covered, since the metric they rank by changes. Fails with
+    /// `Error::InvalidVector`, changing nothing, if a stored vector is zero.
+    /// Turning normalization off leaves the vectors as they are.
+    pub fn set_normalize(&mut self, normalize: bool) -> Result<(), Error> {
+        if normalize == self.normalize {
+            return Ok(());
+        }
+        if normalize {
+            let unit = |keys: &[Vector]| {
+                keys.iter()
+                    .enumerate()
+                    .map(|(row, key)| unit_vector(key.to_vec(), row))
+                    .collect::<Result<Vec<_>, _>>()
+            };
+            let keys = unit(&self.keys)?;
+            let fields = self
+                .fields
+                .values()
+                .map(|field| unit(&field.keys))
+                .collect::<Result<Vec<_>, _>>()?;
+            let tokens = self
+                .tokens
+                .iter()
+                .flatten()
+                .enumerate()
+                .map(|(row, bag)| {
+                    bag.to_vecs()
+                        .into_iter()
+                        .map(|token| unit_vector(token, row))
+                        .collect::<Result<Vec<_>, _>>()
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+
+            let precision = self.precision;
+            let store = |keys: Vec<Vec<f32>>| {
+                keys.into_iter()
+                    .map(|key| Vector::with_precision(key, precision))
+                    .collect()
+            };
+            self.keys = store(keys);
+            for (field, keys) in self.fields.values_mut().zip(fields) {
+                field.keys = store(keys);
+            }
+            if let Some(bags) = self.tokens.as_mut() {
+                *bags = tokens
+                    .into_iter()
+                    .map(|bag| MultiVector::with_precision(bag, precision))
+                    .collect();
+            }
+        }
+        self.normalize = normalize;
+        if !self.graph.is_empty() {
+            self.build_index_prefix(self.graph.len());
+        }
+        Ok(())
+    }
+
+    /// Checks `vector`, the `row`th of a batch, with `index::check` and
+    /// scales it to unit length if the collection normalizes vectors.
+    fn prepare(
+        &self,
+        vector: Vec<f32>,
+        metric: Metric,
+        precision: Precision,
+        row: usize,
+    ) -> Result<Vec<f32>, Error> {
+        index::check(&vector, metric, precision).map_err(|reason| Error::InvalidVector {
+            row: row as u64,
+            reason: reason.to_string(),
+        })?;
+        if !self.normalize {
+            return Ok(vector);
+        }
+        unit_vector(vector, row)
+    }
+
+    /// Checks and prepares `record`, the `row`th of a batch whose named field
+    /// vectors are those of `names`, failing with `Error::DimensionMismatch`
+    /// if one of its vectors has the wrong dimension, or with
+    /// `Error::InvalidVector` or `Error::InvalidSparseVector` if one holds
+    /// invalid values.
+    pub fn prepare_record(
+        &self,
+        row: usize,
+        names: &[String],
+        record: Record,
+    ) -> Result<Record, Error> {
+        let Record {
+            key,
+            fields,
+            sparse,
+            tokens,
+            value,
+        } = record;
+        let mismatched = key.len() != self.dimension
+            || fields
+                .iter()
+                .zip(names)
+                .any(|(vector, name)| vector.len() != self.fields[name].dimension)
+            || tokens
+                .iter()
+                .flatten()
+                .any(|token| token.len() != self.dimension);
+        if mismatched {
+            return Err(Error::DimensionMismatch);
+        }
+        let key = self.prepare(key, self.metric, self.precision, row)?;
+        let fields = fields
+            .into_iter()
+            .zip(names)
+            .map(|(vector, name)| {
+                self.prepare(vector, self.fields[name].metric, self.precision, row)
+            })
+            .collect::<Result<_, _>>()?;
+        let sparse = sparse.map(SparseVector::normalized).transpose()?;
+        let tokens = tokens
+            .map(|bag| {
+                bag.into_iter()
+                    .map(|token| self.prepare(token, Metric::DotProduct, self.precision, row))
+                    .collect::<Result<_, _>>()
+            })
+            .transpose()?;
+        Ok(Record {
+            key,
+            fields,
+            sparse,
+            tokens,
+            value,
+        })
+    }
+
+    /// The stored records whose document is one of `documents`, as `(id,
+    /// vectors, document)` triples.
+    pub fn records_with_documents(
+        &self,
+        documents: &HashSet<&str>,
+    ) -> Vec<(usize, StoredVectors, String)> {
+        self.values
+            .iter()
+            .enumerate()
+            .filter(|(_, value)| documents.contains(value.as_str()))
+            .map(|(id, value)| {
+                let vectors = StoredVectors {
+                    key: self.keys[id].to_vec(),
+                    fields: self.fields.values().map(|f| f.keys[id].to_vec()).collect(),
+                    sparse: self
+                        .sparse
+                        .as_ref()
+                        .map(|index| index.row(id).clone())
+                        .unwrap_or_default(),
+                    tokens: self
+                        .tokens
+                        .as_ref()
+                        .map(|bags| bags[id].to_vecs())
+                        .unwrap_or_default(),
+                };
+                (id, vectors, value.clone())
+            })
+            .collect()
+    }
+
+    /// The vectors `record`, prepared by `prepare_record` with the fields
+    /// `names`, would be stored as.
+    pub fn stored_vectors(&self, names: &[String], record: &Record) -> StoredVectors {
+        let round = |vector: &Vec<f32>| Vector::with_precision(vector.clone(), self.precision);
+        let mut fields = names.iter().zip(&record.fields).collect::<Vec<_>>();
+        fields.sort_by_key(|(name, _)| *name);
+        StoredVectors {
+            key: round(&record.key).to_vec(),
+            fields: fields
+                .into_iter()
+                .map(|(_, vector)| round(vector).to_vec())
+                .collect(),
+            sparse: record.sparse.clone().unwrap_or_default(),
+            tokens: record
+                .tokens
+                .as_ref()
+                .map(|bag| MultiVector::with_precision(bag.clone(), self.precision).to_vecs())
+                .unwrap_or_default(),
+        }
+    }
+
+    /// The dimension, metric, vectors and index of the field `name`.
+    fn field(&self, name: &str) -> Option<(usize, Metric, &[Vector], &Graph)> {
+        if name == PRIMARY_FIELD {
+            return Some((self.dimension, self.metric, &self.keys, &self.graph));
+        }
+        let field = self.fields.get(name)?;
+        Some((field.dimension, field.metric, &field.keys, &field.graph))
+    }
+
+    /// Returns up to `limit` `(score, document)` pairs. A single field is
+    /// searched by its metric; several are combined by the weighted sum of
+    /// their scores over the candidates found in any of their indexes.
+    /// Fails with `IndexNotBuilt` if a field holding vectors has no index,
+    /// e.g. in a state loaded from a release that kept indexes on the heap.
+    pub fn query_fields(
+        &self,
+        queries: Vec<FieldQuery>,
+        limit: i32,
+    ) -> Result<Vec<(f32, String)>, Error> {
+        let query = HybridQuery {
+            dense: queries,
+            sparse: None,
+            sparse_weight: 0.0,
+        };
+        self.query_hybrid(query, limit)
+    }
+
+    /// Like `query_fields`, with the dot product of the sparse query weighted
+    /// into the combined score. The sparse vectors need no index build, so
+    /// candidates from the sparse search include unindexed records.
+    pub fn query_hybrid(
+        &self,
+        query: HybridQuery,
+        limit: i32,
+    ) -> Result<Vec<(f32, String)>, Error> {
+        let sparse_weight = query.sparse_weight;
+        let sparse = query.sparse.map(SparseVector::normalized).transpose()?;
+        let targets = query
+            .dense
+            .into_iter()
+            .enumerate()
+            .map(|(row, query)| {
+                let (dimension, metric, keys, graph) = self
+                    .field(&query.field)
+                    .ok_or_else(|| Error::InvalidField(format!("unknown field {}", query.field)))?;
+                if query.vector.len() != dimension {
+                    return Err(Error::DimensionMismatch);
+                }
+                if graph.is_empty() && !keys.is_empty() {
+                    return Err(Error::IndexNotBuilt);
+                }
+                let vector = self.prepare(query.vector, metric, Precision::F32, row)?;
+                Ok((
+                    metric.for_vectors(self.normalize),
+                    keys,
+                    graph,
+                    Vector::from(vector),
+                    query.weight,
+                ))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let limit = limit.max(0) as usize;
+        let ef = self.index_params.ef_search.max(limit);
+
+        let ranked = match (targets.as_slice(), &sparse) {
+            ([], None) => vec![],
+            ([(metric, keys, graph, query, _)], None) => graph
+                .search(keys, *metric, query, ef)
+                .into_iter()
+                .map(|node| (metric.score(&keys[node], query), node))
+                .collect(),
+            _ => {
+                let mut nodes = BTreeSet::new();
+                for (metric, keys, graph, query, _) in &targets {
+                    nodes.extend(graph.search(keys, *metric, query, ef));
+                }
+                let sparse = sparse.as_ref().zip(self.sparse.as_ref());
+                if let Some((query, index)) = sparse {
+                    nodes.extend(index.search(query, ef));
+                }
+                let mut ranked = nodes
+                    .into_iter()
+                    .map(|node| {
+                        let score = targets
+                            .iter()
+                            .map(|(metric, keys, _, query, weight)| {
+                                weight * metric.score(&keys[node], query)
+                            })
+                            .sum::<f32>()
+                            + sparse.map_or(0.0, |(query, index)| {
+                                sparse_weight * index.score(node, query)
+                            });
+                        (score, node)
+                    })
+                    .collect::<Vec<_>>();
+                ranked.sort_by(|a, b| b.0.total_cmp(&a.0).then(a.1.cmp(&b.1)));
+                ranked
             }
+        };
+        Ok(ranked
+            .into_iter()
+            .take(limit)
+            .map(|(score, node)| (score, self.values[node].clone()))
+            .collect())
+    }
+
+    /// Returns up to `limit` `(score, document)` pairs for a bag of query
+    /// token vectors. Candidates are found in the HNSW index by the mean of
+    /// the query tokens, then reranked by MaxSim against the tokens of each
+    /// record. The rerank depth is `ef_search`.
+    pub fn query_multi_vector(
+        &self,
+        tokens: Vec<Vec<f32>>,
+        limit: i32,
+    ) -> Result<Vec<(f32, String)>, Error> {
+        if tokens.iter().any(|token| token.len() != self.dimension) {
+            return Err(Error::DimensionMismatch);
+        }
+        let tokens = tokens
+            .into_iter()
+            .enumerate()
+            .map(|(row, token)| self.prepare(token, Metric::DotProduct, Precision::F32, row))
+            .collect::<Result<Vec<_>, _>>()?;
+        if self.graph.is_empty() && !self.keys.is_empty() {
+            return Err(Error::IndexNotBuilt);
         }
+        let query = MultiVector::from(tokens);
+        let pooled = query.pooled().ok_or(Error::DimensionMismatch)?;
+        let pooled = self.prepare(pooled.to_vec(), self.metric, Precision::F32, 0)?;
+        let pooled = Vector::from(pooled);
+        let metric = self.metric.for_vectors(self.normalize);
+        let limit = limit.max(0) as usize;
+        let ef = self.index_params.ef_search.max(limit);
 
-        res
+        let mut ranked = self
+            .graph
+            .search(&self.keys, metric, &pooled, ef)
+            .into_iter()
+            .map(|node| {
+                let score = self
+                    .tokens
+                    .as_ref()
+                    .map_or(0.0, |bags| bags[node].max_sim(&query));
+                (score, node)
+            })
+            .collect::<Vec<_>>();
+        ranked.sort_by(|a, b| b.0.total_cmp(&a.0).then(a.1.cmp(&b.1)));
+        Ok(ranked
+            .into_iter()
+            .take(limit)
+            .map(|(score, node)| (score, self.values[node].clone()))
+            .collect())
     }
+
     pub fn build_index(&mut self) {
-        self.inner = generate_index(self.keys.clone(), self.values.clone())
+        self.build_index_prefix(self.keys.len());
+        self.last_built_at = Some(clock::now());
+    }
+
+    /// Replaces the indexes with ones over the first `count` records. Each old
+    /// index is freed once its replacement is written.
+    fn build_index_prefix(&mut self, count: usize) {
+        let count = count.min(self.keys.len());
+        let metric = self.metric.for_vectors(self.normalize);
+        let graph = Graph::build(&self.keys[..count], metric, &self.index_params);
+        std::mem::replace(&mut self.graph, graph).free();
+        for field in self.fields.values_mut() {
+            let metric = field.metric.for_vectors(self.normalize);
+            let graph = Graph::build(&field.keys[..count], metric, &self.index_params);
+            std::mem::replace(&mut field.graph, graph).free();
+        }
+    }
+
+    /// Drops the HNSW indexes, leaving every vector unindexed. Collections must
+    /// be cleared before they are discarded, to free their indexes.
+    pub fn clear_index(&mut self) {
+        self.graph.free();
+        for field in self.fields.values_mut() {
+            field.graph.free();
+        }
+        self.last_built_at = None;
     }
+
+    pub fn info(&self) -> CollectionInfo {
+        let component_bytes = self.precision.component_bytes() as u64;
+        let vector_bytes = self.dimension as u64 * component_bytes;
+        let vector_count = self.keys.len() as u64;
+        let indexed_count = self.graph.len() as u64;
+        let document_bytes = self.document_bytes();
+        let fields = self
+            .fields
+            .iter()
+            .map(|(name, field)| VectorFieldInfo {
+                name: name.clone(),
+                dimension: field.dimension as u64,
+                metric: field.metric,
+                indexed_count: field.graph.len() as u64,
+            })
+            .collect::<Vec<_>>();
+        let field_bytes: u64 = fields
+            .iter()
+            .map(|field| {
+                vector_count * field.dimension * component_bytes
+                    + field.indexed_count * GRAPH_BYTES_PER_POINT
+            })
+            .sum();
+        let sparse_entries = self.sparse.as_ref().map_or(0, SparseIndex::entry_count);
+        let token_count = self
+            .tokens
+            .iter()
+            .flatten()
+            .map(|bag| bag.token_count() as u64)
+            .sum();
+
+        CollectionInfo {
+            dimension: self.dimension as u64,
+            metric: self.metric,
+            precision: self.precision,
+            normalize: self.normalize,
+            vector_count,
+            indexed_count,
+            unindexed_count: vector_count.saturating_sub(indexed_count),
+            document_bytes,
+            file_count: self.metadata.file_names.len() as u64,
+            index_params: self.index_params.clone(),
+            last_built_at: self.last_built_at,
+            memory_bytes: vector_count * vector_bytes
+                + document_bytes
+                + indexed_count * GRAPH_BYTES_PER_POINT
+                + field_bytes
+                + sparse_entries * SPARSE_BYTES_PER_ENTRY
+                + token_count * vector_bytes,
+            fields,
+            sparse_entries,
+            token_count,
+        }
+    }
+
+    pub fn export_header(&self) -> ExportHeader {
+        let mut file_names = self.metadata.file_names.iter().cloned().collect::<Vec<_>>();
+        file_names.sort();
+        ExportHeader {
+            format_version: FORMAT_VERSION,
+            dimension: self.dimension as u64,
+            metric: self.metric,
+            index_params: self.index_params.clone(),
+            quota: self.quota.clone(),
+            file_names,
+            record_count: self.keys.len() as u64,
+            last_built_at: self.last_built_at,
+            fields: self
+                .fields
+                .iter()
+                .map(|(name, field)| VectorFieldSpec {
+                    name: name.clone(),
+                    dimension: field.dimension as u64,
+                    metric: field.metric,
+                })
+                .collect(),
+            sparse: self.sparse.is_some(),
+            multi_vector: self.tokens.is_some(),
+            precision: self.precision,
+            normalize: self.normalize,
+        }
+    }
+
+    /// Returns up to `limit` records starting at `offset`, cut short once the
+    /// page reaches `MAX_PAGE_BYTES`. A page holds at least one record unless
+    /// `offset` is past the end.
+    pub fn export(&self, offset: u64, limit: u64) -> ExportPage {
+        let limit = limit.min(MAX_PAGE_RECORDS);
+        let mut records = vec![];
+        let mut page_bytes = 0;
+        let mut next = offset;
+        while next < self.keys.len() as u64 && (records.len() as u64) < limit {
+            let idx = next as usize;
+            let dimensions =
+                self.dimension + self.fields.values().map(|f| f.dimension).sum::<usize>();
+            let sparse = self.sparse.as_ref().map(|index| index.row(idx).clone());
+            let sparse_bytes = sparse.as_ref().map_or(0, |vector| vector.indices.len() * 8);
+            let tokens = self.tokens.as_ref().map(|bags| bags[idx].to_vecs());
+            let token_count = tokens.as_ref().map_or(0, Vec::len);
+            let record_bytes = ((dimensions + token_count * self.dimension)
+                * std::mem::size_of::<f32>()
+                + sparse_bytes
+                + self.values[idx].len()) as u64;
+            if !records.is_empty() && page_bytes + record_bytes > MAX_PAGE_BYTES {
+                break;
+            }
+            page_bytes += record_bytes;
+            records.push(ExportRecord {
+                id: next,
+                vector: self.keys[idx].to_vec(),
+                fields: self
+                    .fields
+                    .values()
+                    .map(|field| field.keys[idx].to_vec())
+                    .collect(),
+                sparse,
+                tokens,
+                document: self.values[idx].clone(),
+            });
+            next += 1;
+        }
+
+        ExportPage {
+            header: self.export_header(),
+            records,
+            next_offset: (next < self.keys.len() as u64).then_some(next),
+        }
+    }
+}
+
+/// Scales `vector`, the `row`th of a batch, to unit length.
+fn unit_vector(vector: Vec<f32>, row: usize) -> Result<Vec<f32>, Error> {
+    index::normalized(vector).ok_or_else(|| Error::InvalidVector {
+        row: row as u64,
+        reason: "zero vector cannot be normalized".to_string(),
+    })
 }
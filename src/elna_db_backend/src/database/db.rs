@@ -1,19 +1,51 @@
-use super::collection::Collection;
+use super::batch::{InsertMode, InsertReport, Record, RowOutcome, StoredVectors};
+use super::collection::{Collection, CollectionInfo};
 use super::error::Error;
-use super::index::Vector;
+use super::export::{ExportPage, IndexChunk, MAX_PAGE_BYTES};
+use super::field::{self, FieldQuery, VectorFieldSpec};
+use super::index::{Metric, MultiVector, Precision, Vector};
+use super::namespace::{self, Namespace, NamespaceInfo, SEPARATOR};
+use super::quota::{Quota, Usage};
+use super::snapshot;
+use super::sparse::{HybridQuery, SparseVector};
+use candid::Principal;
 // use super::memory::Memory;
 // use ic_stable_structures::StableBTreeMap;
-use instant_distance::Search;
 use serde::{Deserialize, Serialize};
-use std::{cell::RefCell, collections::HashMap};
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+
+/// The namespace part of a collection or alias name, if it has one.
+fn namespace_of_name(name: &str) -> Option<&str> {
+    name.split_once(SEPARATOR).map(|(namespace, _)| namespace)
+}
 
 thread_local! {
     pub static DB: RefCell<Database> = RefCell::new(Database::new())
 }
 
+/// Records to insert, each list holding one entry per record. Records
+/// inserted without sparse or token vectors get empty ones if the collection
+/// has any.
+#[derive(Default)]
+pub struct Records {
+    pub keys: Vec<Vec<f32>>,
+    /// A `(field, vectors)` pair for every named field of the collection.
+    pub fields: Vec<(String, Vec<Vec<f32>>)>,
+    pub sparse: Option<Vec<SparseVector>>,
+    /// The bag of token vectors of each record.
+    pub tokens: Option<Vec<Vec<Vec<f32>>>>,
+    pub values: Vec<String>,
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct Database {
     pub collections: HashMap<String, Collection>,
+    #[serde(default)]
+    pub namespaces: HashMap<String, Namespace>,
+    /// Alternative names resolving to a collection, keyed by alias.
+    #[serde(default)]
+    pub aliases: HashMap<String, String>,
 }
 
 // #[derive(Serialize, Deserialize)]
@@ -44,13 +76,26 @@ impl Database {
     pub fn new() -> Self {
         Self {
             collections: HashMap::new(),
+            namespaces: HashMap::new(),
+            aliases: HashMap::new(),
         }
     }
 
+    /// Whether `name` is used by a collection or an alias.
+    pub fn name_taken(&self, name: &String) -> bool {
+        self.collections.contains_key(name) || self.aliases.contains_key(name)
+    }
+
     pub fn create_collection(&mut self, name: &String, dimension: usize) -> Result<(), Error> {
-        if self.collections.contains_key(name) {
+        if self.name_taken(name) {
             return Err(Error::UniqueViolation);
         }
+        if let Some((namespace, _)) = name.split_once(SEPARATOR) {
+            if let Some(ns) = self.namespaces.get(namespace) {
+                let usage = self.namespace_usage(namespace);
+                ns.quota.check_collections(usage.collections + 1)?;
+            }
+        }
         let keys: Vec<Vector> = vec![];
         let values: Vec<String> = vec![];
 
@@ -59,6 +104,24 @@ impl Database {
         Ok(())
     }
 
+    /// Creates a collection whose records have, besides their primary vector
+    /// of `dimension`, a vector for each of the named `fields`.
+    pub fn create_collection_with_fields(
+        &mut self,
+        name: &String,
+        dimension: usize,
+        metric: Metric,
+        fields: Vec<VectorFieldSpec>,
+    ) -> Result<(), Error> {
+        field::validate(&fields)?;
+        self.create_collection(name, dimension)?;
+        self.collections.insert(
+            name.to_string(),
+            Collection::with_fields(dimension, metric, &fields),
+        );
+        Ok(())
+    }
+
     pub fn insert_into_collection(
         &mut self,
         name: &String,
@@ -66,38 +129,340 @@ impl Database {
         values: Vec<String>,
         file_name: String,
     ) -> Result<(), Error> {
-        let collection = self.collections.get_mut(name).ok_or(Error::NotFound)?;
+        self.insert_with_fields(name, keys, vec![], values, file_name)
+    }
 
-        if keys.len() != values.len() {
-            return Err(Error::DimensionMismatch);
+    /// Inserts records given their primary vectors `keys`, the vectors of
+    /// every named field of the collection as `(field, vectors)` pairs, and
+    /// their documents.
+    pub fn insert_with_fields(
+        &mut self,
+        name: &String,
+        keys: Vec<Vec<f32>>,
+        fields: Vec<(String, Vec<Vec<f32>>)>,
+        values: Vec<String>,
+        file_name: String,
+    ) -> Result<(), Error> {
+        let records = Records {
+            keys,
+            fields,
+            values,
+            ..Records::default()
+        };
+        self.insert_records(name, records, file_name)
+    }
+
+    /// Inserts records given as bags of token vectors, each record's primary
+    /// vector being the mean of its tokens.
+    pub fn insert_multi_vector(
+        &mut self,
+        name: &String,
+        tokens: Vec<Vec<Vec<f32>>>,
+        values: Vec<String>,
+        file_name: String,
+    ) -> Result<(), Error> {
+        let dimension = self.collections.get(name).ok_or(Error::NotFound)?.dimension;
+        let mut keys = vec![];
+        for bag in &tokens {
+            if bag.iter().any(|token| token.len() != dimension) {
+                return Err(Error::DimensionMismatch);
+            }
+            let pooled = MultiVector::from(bag.clone())
+                .pooled()
+                .ok_or(Error::DimensionMismatch)?;
+            keys.push(pooled.to_vec());
         }
+        let records = Records {
+            keys,
+            tokens: Some(tokens),
+            values,
+            ..Records::default()
+        };
+        self.insert_records(name, records, file_name)
+    }
 
-        let all_same_length = keys.iter().all(|inner| inner.len() == collection.dimension);
+    /// Inserts every record of `records`, failing at the first invalid one
+    /// without inserting any.
+    pub fn insert_records(
+        &mut self,
+        name: &String,
+        records: Records,
+        file_name: String,
+    ) -> Result<(), Error> {
+        self.insert_rows(name, records, file_name, InsertMode::AllOrNothing, false)
+            .map(|_| ())
+    }
 
-        // println!("{}", all_same_length);
+    /// Inserts `records`, reporting the outcome of each. Records with the
+    /// document and vectors of a stored record, or of an earlier one in the
+    /// batch, are skipped. Invalid records fail the whole batch in
+    /// `InsertMode::AllOrNothing` and are left out in `InsertMode::BestEffort`.
+    /// Either way the batch fails without inserting any record if a list holds
+    /// the wrong number of entries or the valid records exceed a quota.
+    pub fn insert_batch(
+        &mut self,
+        name: &String,
+        records: Records,
+        file_name: String,
+        mode: InsertMode,
+    ) -> Result<InsertReport, Error> {
+        self.insert_rows(name, records, file_name, mode, true)
+    }
 
-        if !all_same_length {
+    /// Inserts `records` as `insert_batch` does, skipping duplicates only if
+    /// `skip_duplicates` is set.
+    fn insert_rows(
+        &mut self,
+        name: &String,
+        records: Records,
+        file_name: String,
+        mode: InsertMode,
+        skip_duplicates: bool,
+    ) -> Result<InsertReport, Error> {
+        let Records {
+            keys,
+            fields,
+            sparse,
+            tokens,
+            values,
+        } = records;
+        let collection = self.collections.get(name).ok_or(Error::NotFound)?;
+        let rows = keys.len();
+        let mismatched = values.len() != rows
+            || sparse.as_ref().is_some_and(|vectors| vectors.len() != rows)
+            || tokens.as_ref().is_some_and(|bags| bags.len() != rows);
+        if mismatched {
             return Err(Error::DimensionMismatch);
         }
+        collection.check_fields(rows, &fields)?;
+
+        let mut seen: HashMap<String, Vec<(usize, StoredVectors)>> = HashMap::new();
+        if skip_duplicates {
+            let documents = values.iter().map(String::as_str).collect::<HashSet<_>>();
+            for (id, vectors, value) in collection.records_with_documents(&documents) {
+                seen.entry(value).or_default().push((id, vectors));
+            }
+        }
+
+        let names = fields
+            .iter()
+            .map(|(name, _)| name.clone())
+            .collect::<Vec<_>>();
+        let mut accepted = Records {
+            fields: names.iter().map(|name| (name.clone(), vec![])).collect(),
+            sparse: sparse.as_ref().map(|_| vec![]),
+            tokens: tokens.as_ref().map(|_| vec![]),
+            ..Records::default()
+        };
+        let mut field_rows = fields
+            .into_iter()
+            .map(|(_, vectors)| vectors.into_iter())
+            .collect::<Vec<_>>();
+        let mut sparse_rows = sparse.map(Vec::into_iter);
+        let mut token_rows = tokens.map(Vec::into_iter);
+        let mut report = InsertReport::default();
+        for (row, (key, value)) in keys.into_iter().zip(values).enumerate() {
+            let record = Record {
+                key,
+                fields: field_rows.iter_mut().filter_map(Iterator::next).collect(),
+                sparse: sparse_rows.as_mut().and_then(Iterator::next),
+                tokens: token_rows.as_mut().and_then(Iterator::next),
+                value,
+            };
+            let record = match collection.prepare_record(row, &names, record) {
+                Ok(record) => record,
+                Err(error) if mode == InsertMode::AllOrNothing => return Err(error),
+                Err(error) => {
+                    report.push(RowOutcome::from(error));
+                    continue;
+                }
+            };
+            let id = collection.len() + accepted.keys.len();
+            if skip_duplicates {
+                // Compared as stored, since the precision may round the values.
+                let vectors = collection.stored_vectors(&names, &record);
+                let candidates = seen.entry(record.value.clone()).or_default();
+                if let Some(&(id, _)) = candidates.iter().find(|(_, stored)| *stored == vectors) {
+                    report.push(RowOutcome::SkippedDuplicate { id: id as u64 });
+                    continue;
+                }
+                candidates.push((id, vectors));
+            }
+            report.push(RowOutcome::Inserted { id: id as u64 });
+            accepted.keys.push(record.key);
+            for ((_, vectors), vector) in accepted.fields.iter_mut().zip(record.fields) {
+                vectors.push(vector);
+            }
+            if let (Some(vectors), Some(vector)) = (accepted.sparse.as_mut(), record.sparse) {
+                vectors.push(vector);
+            }
+            if let (Some(bags), Some(bag)) = (accepted.tokens.as_mut(), record.tokens) {
+                bags.push(bag);
+            }
+            accepted.values.push(record.value);
+        }
+
+        let Records {
+            keys,
+            fields,
+            sparse,
+            tokens,
+            mut values,
+        } = accepted;
+        let document_bytes = values.iter().map(|v| v.len() as u64).sum();
+        self.check_insert_quota(name, keys.len() as u64, document_bytes)?;
+
+        let collection = self.collections.get_mut(name).ok_or(Error::NotFound)?;
+        let precision = collection.precision;
+        let mut keys = keys
+            .into_iter()
+            .map(|key| Vector::with_precision(key, precision))
+            .collect();
+        collection.append_fields(fields);
+        collection.append_sparse(values.len(), sparse);
+        collection.append_tokens(values.len(), tokens);
+        collection.append(&mut keys, &mut values, file_name)?;
+        Ok(report)
+    }
+
+    /// Fails with `Error::QuotaExceeded` if adding `vectors` vectors holding
+    /// `document_bytes` bytes of documents to the collection would exceed the
+    /// quota of the collection or of the namespace it belongs to.
+    pub fn check_insert_quota(
+        &self,
+        name: &String,
+        vectors: u64,
+        document_bytes: u64,
+    ) -> Result<(), Error> {
+        let collection = self.collections.get(name).ok_or(Error::NotFound)?;
+        collection.quota.check_data(
+            collection.len() as u64 + vectors,
+            collection.document_bytes() + document_bytes,
+        )?;
+
+        if let Some((namespace, _)) = name.split_once(SEPARATOR) {
+            if let Some(ns) = self.namespaces.get(namespace) {
+                let usage = self.namespace_usage(namespace);
+                ns.quota.check_data(
+                    usage.vectors + vectors,
+                    usage.document_bytes + document_bytes,
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds a fully built collection, e.g. one restored by an import, subject
+    /// to the same uniqueness and quota checks as creating and filling it. A
+    /// rejected collection has its index freed.
+    pub fn import_collection(
+        &mut self,
+        name: &String,
+        mut collection: Collection,
+    ) -> Result<(), Error> {
+        if let Err(error) = self.check_import(name, &collection) {
+            collection.clear_index();
+            return Err(error);
+        }
+        self.collections.insert(name.to_string(), collection);
+        Ok(())
+    }
 
-        let mut points: Vec<Vector> = vec![];
-        let mut _values: Vec<String> = vec![];
+    fn check_import(&self, name: &String, collection: &Collection) -> Result<(), Error> {
+        if self.name_taken(name) {
+            return Err(Error::UniqueViolation);
+        }
+        let vectors = collection.len() as u64;
+        let document_bytes = collection.document_bytes();
+        collection.quota.check_data(vectors, document_bytes)?;
+        if let Some((namespace, _)) = name.split_once(SEPARATOR) {
+            if let Some(ns) = self.namespaces.get(namespace) {
+                let usage = self.namespace_usage(namespace);
+                ns.quota.check_collections(usage.collections + 1)?;
+                ns.quota.check_data(
+                    usage.vectors + vectors,
+                    usage.document_bytes + document_bytes,
+                )?;
+            }
+        }
+        Ok(())
+    }
 
-        for i in 0..keys.len() {
-            let key = &keys[i];
-            if key.len() != collection.dimension {
-                continue;
+    /// Replaces the collection `name`, or recreates it if it was deleted, with
+    /// `collection`. The current collection is kept if the replacement does not
+    /// fit the namespace quota.
+    pub fn restore_collection(
+        &mut self,
+        name: &String,
+        collection: Collection,
+    ) -> Result<(), Error> {
+        let current = self.collections.remove(name);
+        let result = self.import_collection(name, collection);
+        if let Some(mut current) = current {
+            if result.is_ok() {
+                current.clear_index();
+            } else {
+                self.collections.insert(name.to_string(), current);
             }
-            let point = Vector::from((*key).clone());
-            points.push(point);
-            _values.push(values[i].clone());
         }
+        result
+    }
+
+    /// Renames the collection `name` to `new_name` within its namespace. Moving
+    /// a collection to another namespace fails with `InvalidName`, since it
+    /// would bypass the target namespace's quota; clone it instead.
+    pub fn rename_collection(&mut self, name: &String, new_name: &String) -> Result<(), Error> {
+        if !self.collections.contains_key(name) {
+            return Err(Error::NotFound);
+        }
+        if namespace_of_name(name) != namespace_of_name(new_name) {
+            return Err(Error::InvalidName);
+        }
+        if self.name_taken(new_name) {
+            return Err(Error::UniqueViolation);
+        }
+        let collection = self.collections.remove(name).unwrap();
+        self.collections.insert(new_name.to_string(), collection);
+        for target in self.aliases.values_mut().filter(|target| *target == name) {
+            *target = new_name.to_string();
+        }
+        Ok(())
+    }
+
+    /// Copies the collection `name` to `new_name`, with its index if
+    /// `with_index` is set, otherwise unindexed.
+    pub fn clone_collection(
+        &mut self,
+        name: &String,
+        new_name: &String,
+        with_index: bool,
+    ) -> Result<(), Error> {
+        let collection = self.collections.get(name).ok_or(Error::NotFound)?;
+        if self.name_taken(new_name) {
+            return Err(Error::UniqueViolation);
+        }
+        let copy = collection.copy(with_index);
+        self.import_collection(new_name, copy)
+    }
 
-        let _ = collection.append(&mut points, &mut _values, file_name);
-        // collection.build_index();
+    pub fn set_collection_quota(&mut self, name: &String, quota: Quota) -> Result<(), Error> {
+        let collection = self.collections.get_mut(name).ok_or(Error::NotFound)?;
+        collection.quota = quota;
         Ok(())
     }
 
+    /// Converts the vectors of the collection `name` to `precision`.
+    pub fn set_precision(&mut self, name: &String, precision: Precision) -> Result<(), Error> {
+        let collection = self.collections.get_mut(name).ok_or(Error::NotFound)?;
+        collection.set_precision(precision)
+    }
+
+    /// Turns normalization of the vectors of the collection `name` on or off.
+    pub fn set_normalize(&mut self, name: &String, normalize: bool) -> Result<(), Error> {
+        let collection = self.collections.get_mut(name).ok_or(Error::NotFound)?;
+        collection.set_normalize(normalize)
+    }
+
     pub fn build_index(&mut self, name: &String) -> Result<(), Error> {
         let collection = self.collections.get_mut(name).ok_or(Error::NotFound)?;
 
@@ -105,42 +470,256 @@ impl Database {
         Ok(())
     }
 
-    pub fn query(
-        &mut self,
+    /// Searches one or more vector fields of the collection `name`, the primary
+    /// vector being the field `field::PRIMARY_FIELD`.
+    pub fn query_fields(
+        &self,
         name: &String,
-        q: Vec<f32>,
+        queries: Vec<FieldQuery>,
         limit: i32,
-    ) -> Result<Vec<(f32, String)>, String> {
-        // let collection = self.collections.get_mut(name).ok_or_else(|| Error::NotFound)?;
+    ) -> Result<Vec<(f32, String)>, Error> {
+        let collection = self.collections.get(name).ok_or(Error::NotFound)?;
+        collection.query_fields(queries, limit)
+    }
 
-        let collection = match self.collections.get(name) {
-            Some(value) => value,
-            None => return Err(Error::NotFound.to_string()),
-        };
+    /// Searches the collection `name` by dense fields and a sparse vector.
+    pub fn query_hybrid(
+        &self,
+        name: &String,
+        query: HybridQuery,
+        limit: i32,
+    ) -> Result<Vec<(f32, String)>, Error> {
+        let collection = self.collections.get(name).ok_or(Error::NotFound)?;
+        collection.query_hybrid(query, limit)
+    }
 
-        if q.len() != collection.dimension {
-            return Err(String::from("query malformed"));
-        }
+    /// Searches the collection `name` by a bag of query token vectors.
+    pub fn query_multi_vector(
+        &self,
+        name: &String,
+        tokens: Vec<Vec<f32>>,
+        limit: i32,
+    ) -> Result<Vec<(f32, String)>, Error> {
+        let collection = self.collections.get(name).ok_or(Error::NotFound)?;
+        collection.query_multi_vector(tokens, limit)
+    }
 
-        let mut search = Search::default();
-        let v = Vector::from(q);
-        let result = collection.query(&v, &mut search, limit);
+    /// Deletes the collection `name` along with the aliases pointing at it.
+    pub fn delete_collection(&mut self, name: &String) -> Result<(), Error> {
+        let mut collection = self.collections.remove(name).ok_or(Error::NotFound)?;
+        collection.clear_index();
+        self.aliases.retain(|_, target| target != name);
+        Ok(())
+    }
 
-        Ok(result)
+    /// Returns the collection `name` refers to: the target if it is an alias,
+    /// otherwise `name` itself.
+    pub fn resolve(&self, name: &String) -> String {
+        self.aliases.get(name).unwrap_or(name).to_string()
     }
 
-    pub fn delete_collection(&mut self, name: &String) -> Result<(), Error> {
-        if let Some(_) = self.collections.remove(name) {
-            Ok(())
-        } else {
-            Err(Error::NotFound)
+    /// Points `alias` at the collection `target`, creating the alias or
+    /// switching it over in one step. `target` must be in the same namespace
+    /// as the alias, so that writes through an alias never reach another
+    /// namespace's collections.
+    pub fn set_alias(&mut self, alias: &String, target: &String) -> Result<(), Error> {
+        if namespace_of_name(alias) != namespace_of_name(target) {
+            return Err(Error::InvalidName);
+        }
+        if self.collections.contains_key(alias) {
+            return Err(Error::UniqueViolation);
+        }
+        if !self.collections.contains_key(target) {
+            return Err(Error::NotFound);
         }
+        self.aliases.insert(alias.to_string(), target.to_string());
+        Ok(())
+    }
+
+    pub fn delete_alias(&mut self, alias: &String) -> Result<(), Error> {
+        self.aliases
+            .remove(alias)
+            .map(|_| ())
+            .ok_or(Error::NotFound)
+    }
+
+    /// Returns `(alias, collection)` pairs, limited to and relative to
+    /// `namespace` if one is given.
+    pub fn get_aliases_in(&self, namespace: Option<&str>) -> Vec<(String, String)> {
+        let relative = |name: &String| match namespace {
+            None => Some(name.to_string()),
+            Some(namespace) => match name.split_once(SEPARATOR) {
+                Some((ns, name)) if ns == namespace => Some(name.to_string()),
+                _ => None,
+            },
+        };
+        self.aliases
+            .iter()
+            .filter_map(|(alias, target)| Some((relative(alias)?, relative(target)?)))
+            .collect()
     }
 
     pub fn get_all_collections(&self) -> Vec<String> {
         self.collections.iter().map(|(id, _)| id.clone()).collect()
     }
 
+    /// Lists the collections visible from `namespace`, without the namespace prefix.
+    /// `None` lists every collection in the canister.
+    pub fn get_collections_in(&self, namespace: Option<&str>) -> Vec<String> {
+        let namespace = match namespace {
+            Some(namespace) => namespace,
+            None => return self.get_all_collections(),
+        };
+        self.collections
+            .keys()
+            .filter_map(|id| match id.split_once(SEPARATOR) {
+                Some((ns, name)) if ns == namespace => Some(name.to_string()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    pub fn create_namespace(&mut self, name: &String, quota: Quota) -> Result<(), Error> {
+        if name.is_empty() || name.contains(SEPARATOR) {
+            return Err(Error::InvalidName);
+        }
+        if self.namespaces.contains_key(name) {
+            return Err(Error::UniqueViolation);
+        }
+        let namespace = Namespace {
+            quota,
+            ..Default::default()
+        };
+        self.namespaces.insert(name.to_string(), namespace);
+        Ok(())
+    }
+
+    /// Deletes the namespace together with all of its collections.
+    pub fn delete_namespace(&mut self, name: &String) -> Result<(), Error> {
+        if self.namespaces.remove(name).is_none() {
+            return Err(Error::NotFound);
+        }
+        for collection in self.get_collections_in(Some(name.as_str())) {
+            if let Some(mut collection) = self
+                .collections
+                .remove(&format!("{}{}{}", name, SEPARATOR, collection))
+            {
+                collection.clear_index();
+            }
+        }
+        let prefix = format!("{}{}", name, SEPARATOR);
+        self.aliases
+            .retain(|alias, target| !alias.starts_with(&prefix) && !target.starts_with(&prefix));
+        snapshot::delete_namespace(name);
+        Ok(())
+    }
+
+    /// Adds `principal` as an admin of the namespace. A principal can administer
+    /// at most one namespace, which is the one its calls are scoped to.
+    pub fn add_namespace_admin(
+        &mut self,
+        name: &String,
+        principal: Principal,
+    ) -> Result<(), Error> {
+        if self.namespace_of(&principal).is_some() {
+            return Err(Error::UniqueViolation);
+        }
+        let namespace = self.namespaces.get_mut(name).ok_or(Error::NotFound)?;
+        namespace.admins.insert(principal);
+        Ok(())
+    }
+
+    pub fn remove_namespace_admin(
+        &mut self,
+        name: &String,
+        principal: Principal,
+    ) -> Result<(), Error> {
+        let namespace = self.namespaces.get_mut(name).ok_or(Error::NotFound)?;
+        if !namespace.admins.remove(&principal) {
+            return Err(Error::NotFound);
+        }
+        Ok(())
+    }
+
+    pub fn set_namespace_quota(&mut self, name: &String, quota: Quota) -> Result<(), Error> {
+        let namespace = self.namespaces.get_mut(name).ok_or(Error::NotFound)?;
+        namespace.quota = quota;
+        Ok(())
+    }
+
+    pub fn namespace_usage(&self, namespace: &str) -> Usage {
+        let mut usage = Usage::default();
+        for name in self.get_collections_in(Some(namespace)) {
+            let collection = &self.collections[&format!("{}{}{}", namespace, SEPARATOR, name)];
+            usage.collections += 1;
+            usage.vectors += collection.len() as u64;
+            usage.document_bytes += collection.document_bytes();
+        }
+        usage.snapshot_bytes = snapshot::namespace_bytes(namespace);
+        (usage.queries, usage.instructions) = namespace::metered_usage(namespace);
+        usage
+    }
+
+    /// Checks that a snapshot of `bytes` of the collection `name` fits in the
+    /// snapshot quota of its namespace.
+    pub fn check_snapshot_quota(&self, name: &str, bytes: u64) -> Result<(), Error> {
+        match name.split_once(SEPARATOR) {
+            Some((namespace, _)) => match self.namespaces.get(namespace) {
+                Some(ns) => ns
+                    .quota
+                    .check_snapshot_bytes(snapshot::namespace_bytes(namespace) + bytes),
+                None => Ok(()),
+            },
+            None => Ok(()),
+        }
+    }
+
+    pub fn namespace_of(&self, principal: &Principal) -> Option<String> {
+        self.namespaces
+            .iter()
+            .find(|(_, ns)| ns.admins.contains(principal))
+            .map(|(name, _)| name.clone())
+    }
+
+    pub fn get_namespaces(&self) -> Vec<NamespaceInfo> {
+        self.namespaces
+            .iter()
+            .map(|(name, ns)| NamespaceInfo {
+                name: name.clone(),
+                admins: ns.admins.iter().cloned().collect(),
+                quota: ns.quota.clone(),
+                usage: self.namespace_usage(name),
+                collections: self.get_collections_in(Some(name.as_str())),
+            })
+            .collect()
+    }
+
+    pub fn collection_info(&self, name: &String) -> Result<CollectionInfo, Error> {
+        let collection = self.collections.get(name).ok_or(Error::NotFound)?;
+        Ok(collection.info())
+    }
+
+    pub fn export_collection(
+        &self,
+        name: &String,
+        offset: u64,
+        limit: u64,
+    ) -> Result<ExportPage, Error> {
+        let collection = self.collections.get(name).ok_or(Error::NotFound)?;
+        Ok(collection.export(offset, limit))
+    }
+
+    pub fn export_index(
+        &self,
+        name: &String,
+        offset: u64,
+        length: u64,
+    ) -> Result<IndexChunk, Error> {
+        let collection = self.collections.get(name).ok_or(Error::NotFound)?;
+        let (bytes, total_bytes) = collection.index_chunk(offset, length.min(MAX_PAGE_BYTES));
+        Ok(IndexChunk { bytes, total_bytes })
+    }
+
     pub fn get_docs(&mut self, index_name: &String) -> Result<Vec<String>, Error> {
         let collection = match self.collections.get(index_name) {
             Some(value) => value,
@@ -152,7 +731,22 @@ impl Database {
 
 #[cfg(test)]
 mod tests {
-    use super::{Database, Error};
+    use super::{Collection, Database, Error, Metric, Quota, Records};
+    use crate::database::batch::{InsertMode, RowOutcome};
+    use crate::database::field::{FieldQuery, VectorFieldSpec};
+    use crate::database::index::Precision;
+    use crate::database::quota::QuotaKind;
+    use crate::database::sparse::{HybridQuery, SparseVector};
+    use candid::Principal;
+
+    /// A query of the primary vector alone.
+    fn primary(vector: Vec<f32>) -> Vec<FieldQuery> {
+        vec![FieldQuery {
+            field: "default".to_string(),
+            vector,
+            weight: 1.0,
+        }]
+    }
 
     #[test]
     fn create_collection() {
@@ -366,7 +960,7 @@ mod tests {
         let _ = db.build_index(&"test".to_string());
 
         let query_vec: Vec<f32> = vec![10.0, 12.5, 4.5];
-        let result = db.query(&"test".to_string(), query_vec, 1);
+        let result = db.query_fields(&"test".to_string(), primary(query_vec), 1);
         assert_eq!(result, Ok(vec![(0.9997943, "red".to_string())]));
     }
 
@@ -401,7 +995,901 @@ mod tests {
         let _ = db.build_index(&"test".to_string());
 
         let query_vec: Vec<f32> = vec![10.0, 30.5, 35.5];
-        let result = db.query(&"test".to_string(), query_vec, 1);
+        let result = db.query_fields(&"test".to_string(), primary(query_vec), 1);
         assert_eq!(result, Ok(vec![(0.9973914, "happy".to_string())]));
     }
+
+    #[test]
+    fn namespace_collections_are_scoped() {
+        let mut db = Database::new();
+        let _ = db.create_namespace(&"acme".to_string(), Quota::default());
+        let _ = db.create_collection(&"acme/docs".to_string(), 3);
+        let _ = db.create_collection(&"docs".to_string(), 3);
+
+        assert_eq!(
+            db.get_collections_in(Some("acme")),
+            vec!["docs".to_string()]
+        );
+        assert_eq!(db.get_collections_in(Some("other")), Vec::<String>::new());
+        assert_eq!(db.get_collections_in(None).len(), 2);
+    }
+
+    #[test]
+    fn namespace_max_collections() {
+        let mut db = Database::new();
+        let quota = Quota {
+            max_collections: Some(1),
+            ..Default::default()
+        };
+        let _ = db.create_namespace(&"acme".to_string(), quota);
+
+        assert_eq!(db.create_collection(&"acme/a".to_string(), 3), Ok(()));
+        assert_eq!(
+            db.create_collection(&"acme/b".to_string(), 3),
+            Err(Error::QuotaExceeded(QuotaKind::Collections))
+        );
+    }
+
+    #[test]
+    fn create_namespace_invalid_name() {
+        let mut db = Database::new();
+
+        assert_eq!(
+            db.create_namespace(&"a/b".to_string(), Quota::default()),
+            Err(Error::InvalidName)
+        );
+    }
+
+    #[test]
+    fn delete_namespace_removes_collections() {
+        let mut db = Database::new();
+        let _ = db.create_namespace(&"acme".to_string(), Quota::default());
+        let _ = db.create_collection(&"acme/docs".to_string(), 3);
+
+        assert_eq!(db.delete_namespace(&"acme".to_string()), Ok(()));
+        assert!(db.collections.is_empty());
+    }
+
+    #[test]
+    fn namespace_admin_belongs_to_one_namespace() {
+        let mut db = Database::new();
+        let principal = Principal::anonymous();
+        let _ = db.create_namespace(&"acme".to_string(), Quota::default());
+        let _ = db.create_namespace(&"other".to_string(), Quota::default());
+
+        assert_eq!(
+            db.add_namespace_admin(&"acme".to_string(), principal),
+            Ok(())
+        );
+        assert_eq!(
+            db.add_namespace_admin(&"other".to_string(), principal),
+            Err(Error::UniqueViolation)
+        );
+        assert_eq!(db.namespace_of(&principal), Some("acme".to_string()));
+    }
+
+    #[test]
+    fn collection_max_vectors() {
+        let mut db = Database::new();
+        let _ = db.create_collection(&"test".to_string(), 3);
+        let quota = Quota {
+            max_vectors: Some(2),
+            ..Default::default()
+        };
+        let _ = db.set_collection_quota(&"test".to_string(), quota);
+
+        let keys: Vec<Vec<f32>> = vec![vec![10.0, 12.0, 4.5], vec![10.0, 11.0, 10.5]];
+        let values: Vec<String> = vec!["red".to_string(), "green".to_string()];
+        let result = db.insert_into_collection(
+            &"test".to_string(),
+            keys,
+            values,
+            "test_file_name".to_string(),
+        );
+        assert_eq!(result, Ok(()));
+
+        let keys: Vec<Vec<f32>> = vec![vec![10.0, 20.5, 15.0]];
+        let values: Vec<String> = vec!["blue".to_string()];
+        let result = db.insert_into_collection(
+            &"test".to_string(),
+            keys,
+            values,
+            "test_file_name".to_string(),
+        );
+        assert_eq!(result, Err(Error::QuotaExceeded(QuotaKind::Vectors)));
+    }
+
+    #[test]
+    fn namespace_max_document_bytes() {
+        let mut db = Database::new();
+        let quota = Quota {
+            max_document_bytes: Some(8),
+            ..Default::default()
+        };
+        let _ = db.create_namespace(&"acme".to_string(), quota);
+        let _ = db.create_collection(&"acme/a".to_string(), 3);
+        let _ = db.create_collection(&"acme/b".to_string(), 3);
+
+        let result = db.insert_into_collection(
+            &"acme/a".to_string(),
+            vec![vec![10.0, 12.0, 4.5]],
+            vec!["green".to_string()],
+            "test_file_name".to_string(),
+        );
+        assert_eq!(result, Ok(()));
+
+        let result = db.insert_into_collection(
+            &"acme/b".to_string(),
+            vec![vec![10.0, 11.0, 10.5]],
+            vec!["yellow".to_string()],
+            "test_file_name".to_string(),
+        );
+        assert_eq!(result, Err(Error::QuotaExceeded(QuotaKind::DocumentBytes)));
+        assert_eq!(db.namespace_usage("acme").document_bytes, 5);
+    }
+
+    #[test]
+    fn collection_info() {
+        let mut db = Database::new();
+        let _ = db.create_collection(&"test".to_string(), 3);
+        let keys: Vec<Vec<f32>> = vec![
+            vec![10.0, 12.0, 4.5],
+            vec![10.0, 11.0, 10.5],
+            vec![10.0, 20.5, 15.0],
+        ];
+        let values: Vec<String> = vec!["red".to_string(), "green".to_string(), "blue".to_string()];
+        let _ = db.insert_into_collection(
+            &"test".to_string(),
+            keys,
+            values,
+            "test_file_name".to_string(),
+        );
+        let _ = db.build_index(&"test".to_string());
+        let _ = db.insert_into_collection(
+            &"test".to_string(),
+            vec![vec![20.0, 20.5, 15.0]],
+            vec!["black".to_string()],
+            "other_file_name".to_string(),
+        );
+
+        let info = db.collection_info(&"test".to_string()).unwrap();
+        assert_eq!(info.dimension, 3);
+        assert_eq!(info.vector_count, 4);
+        assert_eq!(info.indexed_count, 3);
+        assert_eq!(info.unindexed_count, 1);
+        assert_eq!(info.document_bytes, 17);
+        assert_eq!(info.file_count, 2);
+        assert!(info.last_built_at.is_some());
+    }
+
+    #[test]
+    fn export_collection_pages() {
+        let mut db = Database::new();
+        let _ = db.create_collection(&"test".to_string(), 3);
+        let keys: Vec<Vec<f32>> = vec![
+            vec![10.0, 12.0, 4.5],
+            vec![10.0, 11.0, 10.5],
+            vec![10.0, 20.5, 15.0],
+        ];
+        let values: Vec<String> = vec!["red".to_string(), "green".to_string(), "blue".to_string()];
+        let _ = db.insert_into_collection(
+            &"test".to_string(),
+            keys,
+            values,
+            "test_file_name".to_string(),
+        );
+
+        let page = db.export_collection(&"test".to_string(), 0, 2).unwrap();
+        assert_eq!(page.header.dimension, 3);
+        assert_eq!(page.header.record_count, 3);
+        assert_eq!(page.header.file_names, vec!["test_file_name".to_string()]);
+        assert_eq!(page.records.len(), 2);
+        assert_eq!(page.records[1].id, 1);
+        assert_eq!(page.records[1].vector, vec![10.0, 11.0, 10.5]);
+        assert_eq!(page.records[1].document, "green");
+        assert_eq!(page.next_offset, Some(2));
+
+        let page = db.export_collection(&"test".to_string(), 2, 2).unwrap();
+        assert_eq!(page.records.len(), 1);
+        assert_eq!(page.records[0].document, "blue");
+        assert_eq!(page.next_offset, None);
+
+        let result = db.export_collection(&"missing".to_string(), 0, 2);
+        assert_eq!(result, Err(Error::NotFound));
+    }
+
+    #[test]
+    fn restore_collection() {
+        let mut db = Database::new();
+        let _ = db.create_collection(&"test".to_string(), 3);
+        let _ = db.insert_into_collection(
+            &"test".to_string(),
+            vec![vec![10.0, 12.0, 4.5]],
+            vec!["red".to_string()],
+            "test_file_name".to_string(),
+        );
+        let snapshot = db.collections["test"].copy(true);
+        let _ = db.insert_into_collection(
+            &"test".to_string(),
+            vec![vec![10.0, 11.0, 10.5]],
+            vec!["green".to_string()],
+            "other_file_name".to_string(),
+        );
+
+        assert_eq!(db.restore_collection(&"test".to_string(), snapshot), Ok(()));
+        let info = db.collection_info(&"test".to_string()).unwrap();
+        assert_eq!(info.vector_count, 1);
+        assert_eq!(info.file_count, 1);
+    }
+
+    #[test]
+    fn restore_collection_over_quota() {
+        let mut db = Database::new();
+        let quota = Quota {
+            max_vectors: Some(1),
+            ..Default::default()
+        };
+        let _ = db.create_namespace(&"acme".to_string(), quota);
+        let _ = db.create_collection(&"acme/a".to_string(), 3);
+        let _ = db.create_collection(&"acme/b".to_string(), 3);
+        let _ = db.insert_into_collection(
+            &"acme/a".to_string(),
+            vec![vec![10.0, 12.0, 4.5]],
+            vec!["red".to_string()],
+            "test_file_name".to_string(),
+        );
+        let snapshot = db.collections["acme/a"].copy(true);
+
+        let result = db.restore_collection(&"acme/b".to_string(), snapshot);
+        assert_eq!(result, Err(Error::QuotaExceeded(QuotaKind::Vectors)));
+        assert_eq!(
+            db.collection_info(&"acme/b".to_string())
+                .unwrap()
+                .vector_count,
+            0
+        );
+    }
+
+    #[test]
+    fn rename_collection() {
+        let mut db = Database::new();
+        let _ = db.create_collection(&"a".to_string(), 3);
+        let _ = db.create_collection(&"b".to_string(), 3);
+
+        let result = db.rename_collection(&"a".to_string(), &"b".to_string());
+        assert_eq!(result, Err(Error::UniqueViolation));
+        let result = db.rename_collection(&"missing".to_string(), &"c".to_string());
+        assert_eq!(result, Err(Error::NotFound));
+
+        assert_eq!(
+            db.rename_collection(&"a".to_string(), &"c".to_string()),
+            Ok(())
+        );
+        let mut names = db.get_all_collections();
+        names.sort();
+        assert_eq!(names, vec!["b".to_string(), "c".to_string()]);
+
+        let _ = db.create_namespace(&"acme".to_string(), Quota::default());
+        let result = db.rename_collection(&"c".to_string(), &"acme/c".to_string());
+        assert_eq!(result, Err(Error::InvalidName));
+    }
+
+    #[test]
+    fn clone_collection() {
+        let mut db = Database::new();
+        let _ = db.create_collection(&"test".to_string(), 3);
+        let keys: Vec<Vec<f32>> = vec![vec![10.0, 12.0, 4.5], vec![10.0, 11.0, 10.5]];
+        let values: Vec<String> = vec!["red".to_string(), "green".to_string()];
+        let _ = db.insert_into_collection(
+            &"test".to_string(),
+            keys,
+            values,
+            "test_file_name".to_string(),
+        );
+        let _ = db.build_index(&"test".to_string());
+
+        let result = db.clone_collection(&"test".to_string(), &"with_index".to_string(), true);
+        assert_eq!(result, Ok(()));
+        let result = db.clone_collection(&"test".to_string(), &"plain".to_string(), false);
+        assert_eq!(result, Ok(()));
+        let result = db.clone_collection(&"test".to_string(), &"plain".to_string(), false);
+        assert_eq!(result, Err(Error::UniqueViolation));
+
+        let info = db.collection_info(&"with_index".to_string()).unwrap();
+        assert_eq!((info.vector_count, info.indexed_count), (2, 2));
+        let info = db.collection_info(&"plain".to_string()).unwrap();
+        assert_eq!((info.vector_count, info.indexed_count), (2, 0));
+        assert_eq!(info.last_built_at, None);
+
+        let _ = db.delete_collection(&"test".to_string());
+        let result = db.query_fields(&"with_index".to_string(), primary(vec![10.0, 12.5, 4.5]), 1);
+        assert_eq!(result, Ok(vec![(0.9997943, "red".to_string())]));
+    }
+
+    #[test]
+    fn aliases() {
+        let mut db = Database::new();
+        let _ = db.create_collection(&"v1".to_string(), 3);
+        let _ = db.create_collection(&"v2".to_string(), 3);
+
+        assert_eq!(
+            db.set_alias(&"v1".to_string(), &"v2".to_string()),
+            Err(Error::UniqueViolation)
+        );
+        assert_eq!(
+            db.set_alias(&"prod".to_string(), &"v3".to_string()),
+            Err(Error::NotFound)
+        );
+        assert_eq!(db.set_alias(&"prod".to_string(), &"v1".to_string()), Ok(()));
+        assert_eq!(db.resolve(&"prod".to_string()), "v1");
+        assert_eq!(db.set_alias(&"prod".to_string(), &"v2".to_string()), Ok(()));
+        assert_eq!(db.resolve(&"prod".to_string()), "v2");
+        assert_eq!(db.resolve(&"v1".to_string()), "v1");
+        assert_eq!(
+            db.create_collection(&"prod".to_string(), 3),
+            Err(Error::UniqueViolation)
+        );
+        assert_eq!(
+            db.clone_collection(&"v1".to_string(), &"prod".to_string(), false),
+            Err(Error::UniqueViolation)
+        );
+        let copy = db.collections["v1"].copy(false);
+        assert_eq!(
+            db.import_collection(&"prod".to_string(), copy),
+            Err(Error::UniqueViolation)
+        );
+
+        let _ = db.rename_collection(&"v2".to_string(), &"v3".to_string());
+        assert_eq!(db.resolve(&"prod".to_string()), "v3");
+        let _ = db.delete_collection(&"v3".to_string());
+        assert_eq!(db.delete_alias(&"prod".to_string()), Err(Error::NotFound));
+    }
+
+    #[test]
+    fn aliases_in_namespace() {
+        let mut db = Database::new();
+        let _ = db.create_namespace(&"acme".to_string(), Quota::default());
+        let _ = db.create_collection(&"acme/v1".to_string(), 3);
+        let _ = db.create_collection(&"other".to_string(), 3);
+        let _ = db.set_alias(&"acme/prod".to_string(), &"acme/v1".to_string());
+        let _ = db.set_alias(&"prod".to_string(), &"other".to_string());
+
+        assert_eq!(
+            db.get_aliases_in(Some("acme")),
+            vec![("prod".to_string(), "v1".to_string())]
+        );
+        let result = db.set_alias(&"latest".to_string(), &"acme/v1".to_string());
+        assert_eq!(result, Err(Error::InvalidName));
+        // Left over from a release that let aliases cross namespaces.
+        db.aliases
+            .insert("latest".to_string(), "acme/v1".to_string());
+        let _ = db.delete_namespace(&"acme".to_string());
+        assert_eq!(
+            db.get_aliases_in(None),
+            vec![("prod".to_string(), "other".to_string())]
+        );
+    }
+
+    #[test]
+    fn named_vector_fields() {
+        let mut db = Database::new();
+        let name = "docs".to_string();
+        let fields = vec![VectorFieldSpec {
+            name: "title".to_string(),
+            dimension: 2,
+            metric: Metric::DotProduct,
+        }];
+        let result = db.create_collection_with_fields(&name, 3, Metric::Euclidean, fields);
+        assert_eq!(result, Ok(()));
+
+        let keys = vec![vec![10.0, 12.0, 4.5], vec![10.0, 11.0, 10.5]];
+        let values = vec!["red".to_string(), "green".to_string()];
+        let result = db.insert_into_collection(&name, keys.clone(), values.clone(), "f".into());
+        assert!(matches!(result, Err(Error::InvalidField(_))));
+        let titles = vec![("title".to_string(), vec![vec![1.0, 0.0]])];
+        let result = db.insert_with_fields(&name, keys.clone(), titles, values.clone(), "f".into());
+        assert_eq!(result, Err(Error::DimensionMismatch));
+
+        let titles = vec![("title".to_string(), vec![vec![1.0, 0.0], vec![0.0, 1.0]])];
+        let result = db.insert_with_fields(&name, keys, titles, values, "f".into());
+        assert_eq!(result, Ok(()));
+        let _ = db.build_index(&name);
+
+        let query = |field: &str, vector: Vec<f32>, weight: f32| FieldQuery {
+            field: field.to_string(),
+            vector,
+            weight,
+        };
+        let result = db.query_fields(&name, vec![query("title", vec![0.0, 2.0], 1.0)], 1);
+        assert_eq!(result, Ok(vec![(2.0, "green".to_string())]));
+        let queries = vec![
+            query("default", vec![10.0, 12.5, 4.5], 1.0),
+            query("title", vec![0.0, 2.0], 0.5),
+        ];
+        let result = db.query_fields(&name, queries, 2).unwrap();
+        assert_eq!(result[0].1, "green");
+        assert_eq!(result[1].1, "red");
+        let result = db.query_fields(&name, vec![query("body", vec![0.0], 1.0)], 1);
+        assert!(matches!(result, Err(Error::InvalidField(_))));
+
+        let _ = db.clone_collection(&name, &"copy".to_string(), true);
+        let info = db.collection_info(&"copy".to_string()).unwrap();
+        assert_eq!(info.fields.len(), 1);
+        assert_eq!(info.fields[0].indexed_count, 2);
+        let page = db.export_collection(&"copy".to_string(), 0, 10).unwrap();
+        assert_eq!(page.header.fields[0].name, "title");
+        assert_eq!(page.records[1].fields, vec![vec![0.0, 1.0]]);
+    }
+
+    #[test]
+    fn sparse_and_hybrid_queries() {
+        let mut db = Database::new();
+        let name = "docs".to_string();
+        let _ = db.create_collection(&name, 2);
+        let keys = vec![vec![1.0, 0.0], vec![0.0, 1.0]];
+        let values = vec!["a".to_string(), "b".to_string()];
+        let _ = db.insert_into_collection(&name, keys, values, "f".into());
+
+        let sparse = |indices: Vec<u32>, values: Vec<f32>| SparseVector { indices, values };
+        let sparse_records = |keys, sparse, values| Records {
+            keys,
+            sparse: Some(sparse),
+            values,
+            ..Records::default()
+        };
+        let keys = vec![vec![1.0, 0.1], vec![0.1, 1.0]];
+        let values = vec!["c".to_string(), "d".to_string()];
+        let vectors = vec![sparse(vec![3, 3], vec![1.0, 1.0]), sparse(vec![], vec![])];
+        let result = db.insert_records(
+            &name,
+            sparse_records(keys.clone(), vectors, values.clone()),
+            "f".into(),
+        );
+        assert!(matches!(result, Err(Error::InvalidSparseVector(_))));
+        let vectors = vec![sparse(vec![3], vec![1.0])];
+        let result = db.insert_records(
+            &name,
+            sparse_records(keys.clone(), vectors, values.clone()),
+            "f".into(),
+        );
+        assert_eq!(result, Err(Error::DimensionMismatch));
+        let vectors = vec![
+            sparse(vec![3], vec![1.0]),
+            sparse(vec![8, 3], vec![2.0, 0.5]),
+        ];
+        let result = db.insert_records(&name, sparse_records(keys, vectors, values), "f".into());
+        assert_eq!(result, Ok(()));
+        let _ = db.build_index(&name);
+
+        let query = HybridQuery {
+            dense: vec![],
+            sparse: Some(sparse(vec![8, 3], vec![1.0, 1.0])),
+            sparse_weight: 1.0,
+        };
+        let result = db.query_hybrid(&name, query, 3);
+        assert_eq!(
+            result,
+            Ok(vec![(2.5, "d".to_string()), (1.0, "c".to_string())])
+        );
+        let query = HybridQuery {
+            dense: vec![FieldQuery {
+                field: "default".to_string(),
+                vector: vec![1.0, 0.0],
+                weight: 1.0,
+            }],
+            sparse: Some(sparse(vec![3], vec![1.0])),
+            sparse_weight: 1.0,
+        };
+        let result = db.query_hybrid(&name, query, 2).unwrap();
+        assert_eq!(result[0].1, "c");
+        assert_eq!(result[1].1, "a");
+
+        assert_eq!(db.collection_info(&name).unwrap().sparse_entries, 3);
+        let page = db.export_collection(&name, 0, 10).unwrap();
+        assert!(page.header.sparse);
+        assert_eq!(page.records[0].sparse, Some(SparseVector::default()));
+        assert_eq!(
+            page.records[3].sparse,
+            Some(sparse(vec![3, 8], vec![0.5, 2.0]))
+        );
+
+        let terms = "terms".to_string();
+        let _ = db.create_collection(&terms, 0);
+        let vectors = vec![sparse(vec![1], vec![1.0]), sparse(vec![2], vec![1.0])];
+        let values = vec!["x".to_string(), "y".to_string()];
+        let result = db.insert_records(
+            &terms,
+            sparse_records(vec![vec![]; 2], vectors, values),
+            "f".into(),
+        );
+        assert_eq!(result, Ok(()));
+        let _ = db.build_index(&terms);
+        let query = HybridQuery {
+            dense: vec![],
+            sparse: Some(sparse(vec![2], vec![1.0])),
+            sparse_weight: 1.0,
+        };
+        assert_eq!(
+            db.query_hybrid(&terms, query, 1),
+            Ok(vec![(1.0, "y".to_string())])
+        );
+    }
+
+    #[test]
+    fn multi_vector_rerank() {
+        let mut db = Database::new();
+        let name = "docs".to_string();
+        let _ = db.create_collection(&name, 2);
+        let values = vec!["plain".to_string()];
+        let _ = db.insert_into_collection(&name, vec![vec![0.6, 0.4]], values, "f".into());
+
+        let values = vec!["empty".to_string()];
+        let result = db.insert_multi_vector(&name, vec![vec![]], values.clone(), "f".into());
+        assert_eq!(result, Err(Error::DimensionMismatch));
+        let result = db.insert_multi_vector(&name, vec![vec![vec![1.0]]], values, "f".into());
+        assert_eq!(result, Err(Error::DimensionMismatch));
+
+        let tokens = vec![
+            vec![vec![1.0, 0.0], vec![0.0, 1.0]],
+            vec![vec![1.0, 0.0], vec![1.0, 0.0]],
+            vec![vec![0.0, 1.0]],
+        ];
+        let values = vec![
+            "broad".to_string(),
+            "narrow".to_string(),
+            "other".to_string(),
+        ];
+        let result = db.insert_multi_vector(&name, tokens, values, "f".into());
+        assert_eq!(result, Ok(()));
+        let _ = db.build_index(&name);
+
+        let query = vec![vec![1.0, 0.0], vec![0.0, 1.0]];
+        let result = db.query_multi_vector(&name, query, 2);
+        assert_eq!(
+            result,
+            Ok(vec![
+                (2.0, "broad".to_string()),
+                (1.0, "narrow".to_string())
+            ])
+        );
+        assert_eq!(
+            db.query_multi_vector(&name, vec![], 2),
+            Err(Error::DimensionMismatch)
+        );
+
+        assert_eq!(db.collection_info(&name).unwrap().token_count, 5);
+        let page = db.export_collection(&name, 0, 10).unwrap();
+        assert!(page.header.multi_vector);
+        assert_eq!(page.records[0].tokens, Some(vec![]));
+        assert_eq!(page.records[1].vector, vec![0.5, 0.5]);
+    }
+
+    #[test]
+    fn half_precision() {
+        let mut db = Database::new();
+        let name = "test".to_string();
+        let _ = db.create_collection(&name, 3);
+        let keys = vec![vec![10.0, 12.0, 4.5], vec![10.0, 11.0, 10.5]];
+        let values = vec!["red".to_string(), "green".to_string()];
+        let _ = db.insert_into_collection(&name, keys, values, "f".into());
+        let before = db.collection_info(&name).unwrap().memory_bytes;
+
+        assert_eq!(db.set_precision(&name, Precision::F16), Ok(()));
+        let after = db.collection_info(&name).unwrap().memory_bytes;
+        assert_eq!(after, before - 2 * 3 * 2);
+        let keys = vec![vec![0.1, 20.5, 15.0]];
+        let _ = db.insert_into_collection(&name, keys, vec!["blue".to_string()], "f".into());
+        let _ = db.build_index(&name);
+        let info = db.collection_info(&name).unwrap();
+        assert_eq!(info.precision, Precision::F16);
+
+        let result = db.query_fields(&name, primary(vec![10.0, 12.5, 4.5]), 1);
+        assert_eq!(result.unwrap()[0].1, "red");
+        let page = db.export_collection(&name, 0, 10).unwrap();
+        assert_eq!(page.header.precision, Precision::F16);
+        assert_eq!(page.records[2].vector, vec![0.099975586, 20.5, 15.0]);
+
+        let bytes = db.collections[&name].encode();
+        let copy = Collection::decode(&bytes).unwrap();
+        assert_eq!(copy.precision, Precision::F16);
+        assert_eq!(copy.export(2, 1).records[0].vector[0], 0.099975586);
+        let _ = db.set_precision(&name, Precision::Bf16);
+        let page = db.export_collection(&name, 0, 1).unwrap();
+        assert_eq!(page.records[0].vector, vec![10.0, 12.0, 4.5]);
+    }
+
+    #[test]
+    fn set_precision_checks_stored_vectors() {
+        let mut db = Database::new();
+        let name = "test".to_string();
+        let _ = db.create_collection(&name, 2);
+        let keys = vec![vec![1.0, 2.0], vec![1.0, 1e6]];
+        let values = vec!["small".to_string(), "large".to_string()];
+        let _ = db.insert_into_collection(&name, keys, values, "f".into());
+
+        assert_eq!(
+            db.set_precision(&name, Precision::F16),
+            Err(Error::InvalidVector {
+                row: 1,
+                reason: "contains values out of the F16 range".to_string(),
+            })
+        );
+        let page = db.export_collection(&name, 0, 2).unwrap();
+        assert_eq!(page.header.precision, Precision::F32);
+        assert_eq!(page.records[1].vector, vec![1.0, 1e6]);
+        assert_eq!(db.set_precision(&name, Precision::Bf16), Ok(()));
+    }
+
+    #[test]
+    fn normalize_vectors() {
+        let mut db = Database::new();
+        let name = "test".to_string();
+        let _ = db.create_collection(&name, 2);
+        let keys = vec![vec![3.0, 4.0], vec![0.0, 0.0]];
+        let values = vec!["a".to_string(), "zero".to_string()];
+        let _ = db.insert_into_collection(&name, keys, values, "f".into());
+        let zero = |row: u64| Error::InvalidVector {
+            row,
+            reason: "zero vector cannot be normalized".to_string(),
+        };
+        assert_eq!(db.set_normalize(&name, true), Err(zero(1)));
+        // Cosine collections reject zero vectors before normalizing them.
+        let zero = |row: u64| Error::InvalidVector {
+            row,
+            reason: "zero vector has no cosine similarity".to_string(),
+        };
+        assert!(!db.collection_info(&name).unwrap().normalize);
+
+        let name = "unit".to_string();
+        let _ = db.create_collection_with_fields(&name, 2, Metric::Cosine, vec![]);
+        let keys = vec![vec![3.0, 4.0], vec![2.0, 0.0]];
+        let values = vec!["a".to_string(), "b".to_string()];
+        let _ = db.insert_into_collection(&name, keys, values, "f".into());
+        let _ = db.build_index(&name);
+        assert_eq!(db.set_normalize(&name, true), Ok(()));
+        let info = db.collection_info(&name).unwrap();
+        assert!(info.normalize);
+        assert_eq!(info.indexed_count, 2);
+
+        let page = db.export_collection(&name, 0, 10).unwrap();
+        assert_eq!(page.records[0].vector, vec![0.6, 0.8]);
+        let result = db.insert_into_collection(
+            &name,
+            vec![vec![0.0, 0.0]],
+            vec!["zero".to_string()],
+            "f".into(),
+        );
+        assert_eq!(result, Err(zero(0)));
+        let result = db.insert_into_collection(
+            &name,
+            vec![vec![0.0, 5.0]],
+            vec!["c".to_string()],
+            "f".into(),
+        );
+        assert_eq!(result, Ok(()));
+        let _ = db.build_index(&name);
+
+        let query = |vector: Vec<f32>| FieldQuery {
+            field: "default".to_string(),
+            vector,
+            weight: 1.0,
+        };
+        let result = db.query_fields(&name, vec![query(vec![10.0, 0.0])], 1);
+        assert_eq!(result, Ok(vec![(1.0, "b".to_string())]));
+        let result = db.query_fields(&name, vec![query(vec![0.0, 0.5])], 1);
+        assert_eq!(result, Ok(vec![(1.0, "c".to_string())]));
+        let result = db.query_fields(&name, vec![query(vec![0.0, 0.0])], 1);
+        assert_eq!(result, Err(zero(0)));
+    }
+
+    #[test]
+    fn reject_invalid_vectors() {
+        let mut db = Database::new();
+        let name = "test".to_string();
+        let _ = db.create_collection(&name, 2);
+        let invalid = |row: u64, reason: &str| Error::InvalidVector {
+            row,
+            reason: reason.to_string(),
+        };
+        let non_finite = "contains NaN or infinite values";
+
+        let keys = vec![vec![1.0, 2.0], vec![f32::NAN, 0.0]];
+        let values = vec!["a".to_string(), "b".to_string()];
+        let result = db.insert_into_collection(&name, keys, values.clone(), "f".into());
+        assert_eq!(result, Err(invalid(1, non_finite)));
+        assert_eq!(db.collection_info(&name).unwrap().vector_count, 0);
+        let keys = vec![vec![1.0, 2.0], vec![0.0, f32::INFINITY]];
+        let result = db.insert_into_collection(&name, keys, values.clone(), "f".into());
+        assert_eq!(result, Err(invalid(1, non_finite)));
+
+        // Zero vectors are fine for Euclidean distance and score 0.
+        let keys = vec![vec![1.0, 2.0], vec![0.0, 0.0]];
+        let result = db.insert_into_collection(&name, keys, values, "f".into());
+        assert_eq!(result, Ok(()));
+        let _ = db.build_index(&name);
+        let result = db.query_fields(&name, primary(vec![0.0, 0.1]), 2).unwrap();
+        assert!(result.iter().all(|(score, _)| !score.is_nan()));
+        let query = |vector: Vec<f32>| FieldQuery {
+            field: "default".to_string(),
+            vector,
+            weight: 1.0,
+        };
+        let result = db.query_fields(&name, vec![query(vec![f32::NAN, 0.0])], 1);
+        assert_eq!(result, Err(invalid(0, non_finite)));
+
+        let cosine = "cosine".to_string();
+        let _ = db.create_collection_with_fields(&cosine, 2, Metric::Cosine, vec![]);
+        let keys = vec![vec![0.0, 0.0]];
+        let result = db.insert_into_collection(&cosine, keys, vec!["c".to_string()], "f".into());
+        assert_eq!(
+            result,
+            Err(invalid(0, "zero vector has no cosine similarity"))
+        );
+        let result = db.query_fields(&cosine, vec![query(vec![0.0, 0.0])], 1);
+        assert_eq!(
+            result,
+            Err(invalid(0, "zero vector has no cosine similarity"))
+        );
+
+        assert_eq!(db.set_precision(&name, Precision::F16), Ok(()));
+        let keys = vec![vec![1.0, 1e6]];
+        let result = db.insert_into_collection(&name, keys, vec!["d".to_string()], "f".into());
+        assert_eq!(
+            result,
+            Err(invalid(0, "contains values out of the F16 range"))
+        );
+
+        let sparse = vec![SparseVector {
+            indices: vec![1],
+            values: vec![f32::NAN],
+        }];
+        let records = Records {
+            keys: vec![vec![1.0, 0.0]],
+            sparse: Some(sparse),
+            values: vec!["e".to_string()],
+            ..Records::default()
+        };
+        let result = db.insert_records(&name, records, "f".into());
+        assert!(matches!(result, Err(Error::InvalidSparseVector(_))));
+        assert_eq!(db.collection_info(&name).unwrap().vector_count, 2);
+    }
+
+    #[test]
+    fn batch_insert_outcomes() {
+        let mut db = Database::new();
+        let name = "test".to_string();
+        let _ = db.create_collection(&name, 2);
+        let result = db.insert_into_collection(
+            &name,
+            vec![vec![1.0, 2.0]],
+            vec!["a".to_string()],
+            "f".into(),
+        );
+        assert_eq!(result, Ok(()));
+
+        let batch = || Records {
+            keys: vec![
+                vec![1.0, 2.0],
+                vec![3.0],
+                vec![f32::NAN, 1.0],
+                vec![3.0, 4.0],
+                vec![3.0, 4.0],
+                vec![3.0, 4.0],
+            ],
+            values: ["a", "b", "c", "d", "d", "e"].map(String::from).to_vec(),
+            ..Records::default()
+        };
+        let result = db.insert_batch(&name, batch(), "f".into(), InsertMode::AllOrNothing);
+        assert_eq!(result, Err(Error::DimensionMismatch));
+        assert_eq!(db.collection_info(&name).unwrap().vector_count, 1);
+
+        let report = db
+            .insert_batch(&name, batch(), "f".into(), InsertMode::BestEffort)
+            .unwrap();
+        assert_eq!(
+            report.rows,
+            vec![
+                RowOutcome::SkippedDuplicate { id: 0 },
+                RowOutcome::InvalidDimension,
+                RowOutcome::InvalidValues("contains NaN or infinite values".to_string()),
+                RowOutcome::Inserted { id: 1 },
+                RowOutcome::SkippedDuplicate { id: 1 },
+                RowOutcome::Inserted { id: 2 },
+            ]
+        );
+        assert_eq!(
+            (report.inserted, report.skipped, report.rejected),
+            (2, 2, 2)
+        );
+        let page = db.export_collection(&name, 0, 10).unwrap();
+        let documents = page.records.iter().map(|record| record.document.as_str());
+        assert!(documents.eq(["a", "d", "e"]));
+
+        // Duplicates are skipped in either mode; a mismatched list fails both.
+        let records = Records {
+            keys: vec![vec![1.0, 2.0], vec![5.0, 6.0]],
+            values: vec!["a".to_string(), "f".to_string()],
+            ..Records::default()
+        };
+        let report = db
+            .insert_batch(&name, records, "f".into(), InsertMode::AllOrNothing)
+            .unwrap();
+        assert_eq!(
+            report.rows,
+            vec![
+                RowOutcome::SkippedDuplicate { id: 0 },
+                RowOutcome::Inserted { id: 3 }
+            ]
+        );
+        let records = Records {
+            keys: vec![vec![1.0, 2.0]],
+            ..Records::default()
+        };
+        let result = db.insert_batch(&name, records, "f".into(), InsertMode::BestEffort);
+        assert_eq!(result, Err(Error::DimensionMismatch));
+    }
+
+    #[test]
+    fn duplicates_compare_every_vector() {
+        let mut db = Database::new();
+        let name = "test".to_string();
+        let fields = vec![VectorFieldSpec {
+            name: "title".to_string(),
+            dimension: 1,
+            metric: Metric::Euclidean,
+        }];
+        let _ = db.create_collection_with_fields(&name, 2, Metric::Euclidean, fields);
+        let records = |title: f32, sparse: f32| Records {
+            keys: vec![vec![1.0, 2.0]],
+            fields: vec![("title".to_string(), vec![vec![title]])],
+            sparse: Some(vec![SparseVector {
+                indices: vec![7],
+                values: vec![sparse],
+            }]),
+            values: vec!["a".to_string()],
+            ..Records::default()
+        };
+
+        // Plain inserts store every record, duplicate or not.
+        assert_eq!(
+            db.insert_records(&name, records(1.0, 1.0), "f".into()),
+            Ok(())
+        );
+        assert_eq!(
+            db.insert_records(&name, records(1.0, 1.0), "f".into()),
+            Ok(())
+        );
+        assert_eq!(db.collection_info(&name).unwrap().vector_count, 2);
+
+        let mode = InsertMode::AllOrNothing;
+        let report = db.insert_batch(&name, records(1.0, 1.0), "f".into(), mode);
+        assert_eq!(
+            report.unwrap().rows,
+            vec![RowOutcome::SkippedDuplicate { id: 0 }]
+        );
+        let report = db.insert_batch(&name, records(2.0, 1.0), "f".into(), mode);
+        assert_eq!(report.unwrap().rows, vec![RowOutcome::Inserted { id: 2 }]);
+        let report = db.insert_batch(&name, records(1.0, 0.5), "f".into(), mode);
+        assert_eq!(report.unwrap().rows, vec![RowOutcome::Inserted { id: 3 }]);
+    }
+
+    #[test]
+    fn query_needs_a_built_index() {
+        let mut db = Database::new();
+        let name = "docs".to_string();
+        let _ = db.create_collection(&name, 2);
+        assert_eq!(
+            db.query_fields(&name, primary(vec![1.0, 0.0]), 1),
+            Ok(vec![])
+        );
+
+        let keys = vec![vec![1.0, 0.0]];
+        let _ = db.insert_into_collection(&name, keys, vec!["a".into()], "f".into());
+        let result = db.query_fields(&name, primary(vec![1.0, 0.0]), 1);
+        assert_eq!(result, Err(Error::IndexNotBuilt));
+
+        let _ = db.build_index(&name);
+        let result = db.query_fields(&name, primary(vec![1.0, 0.0]), 1);
+        assert_eq!(result.unwrap().len(), 1);
+    }
 }
@@ -1,3 +1,4 @@
+use crate::database::quota::QuotaKind;
 use candid::CandidType;
 
 #[derive(Debug, thiserror::Error, PartialEq, CandidType)]
@@ -14,7 +15,53 @@ pub enum Error {
     Unauthorized,
     #[error("Memory error")]
     MemoryError,
+    #[error("Invalid collection or namespace name")]
+    InvalidName,
+    #[error("Quota exceeded: {0:?}")]
+    QuotaExceeded(QuotaKind),
+    #[error("Too many requests, try again later")]
+    RateLimited,
+    #[error("Invalid import: {0}")]
+    InvalidImport(String),
+    #[error("Checksum of the imported data doesn't match")]
+    ChecksumMismatch,
+    #[error("Invalid principal")]
+    InvalidPrincipal,
+    #[error("Invalid vector field: {0}")]
+    InvalidField(String),
+    #[error("Invalid sparse vector: {0}")]
+    InvalidSparseVector(String),
+    #[error("Invalid vector at row {row}: {reason}")]
+    InvalidVector { row: u64, reason: String },
+    #[error("Too many open sessions, commit or abort one first")]
+    TooManySessions,
+    #[error("The collection has no index yet, call build_index first")]
+    IndexNotBuilt,
 }
+impl Error {
+    /// The variant name, used to label error counters.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Error::UniqueViolation => "UniqueViolation",
+            Error::NotFound => "NotFound",
+            Error::DimensionMismatch => "DimensionMismatch",
+            Error::Unauthorized => "Unauthorized",
+            Error::MemoryError => "MemoryError",
+            Error::InvalidName => "InvalidName",
+            Error::QuotaExceeded(_) => "QuotaExceeded",
+            Error::RateLimited => "RateLimited",
+            Error::InvalidImport(_) => "InvalidImport",
+            Error::ChecksumMismatch => "ChecksumMismatch",
+            Error::InvalidPrincipal => "InvalidPrincipal",
+            Error::InvalidField(_) => "InvalidField",
+            Error::InvalidSparseVector(_) => "InvalidSparseVector",
+            Error::InvalidVector { .. } => "InvalidVector",
+            Error::TooManySessions => "TooManySessions",
+            Error::IndexNotBuilt => "IndexNotBuilt",
+        }
+    }
+}
+
 impl From<Error> for String {
     fn from(error: Error) -> Self {
         // Convert the Error to a String representation
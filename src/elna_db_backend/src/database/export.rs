@@ -0,0 +1,85 @@
+//! The documented, versioned format collections are exported in. Unlike the
+//! CBOR blob written by `pre_upgrade`, it does not depend on the in-memory
+//! layout of `Collection` and stays readable across releases.
+use crate::database::field::VectorFieldSpec;
+use crate::database::index::{IndexParams, Metric, Precision};
+use crate::database::quota::Quota;
+use crate::database::sparse::SparseVector;
+use candid::CandidType;
+use serde::{Deserialize, Serialize};
+
+/// Bumped whenever a field is removed or changes meaning; new optional fields
+/// may be added without a bump.
+pub const FORMAT_VERSION: u32 = 1;
+
+/// Upper bound on the number of records returned in a single page.
+pub const MAX_PAGE_RECORDS: u64 = 1_000;
+
+/// Approximate upper bound on the size of the records in a page, keeping the
+/// response well below the 3 MiB reply limit.
+pub const MAX_PAGE_BYTES: u64 = 1_500_000;
+
+/// Collection-wide settings, repeated in every page so each one can be
+/// interpreted on its own.
+#[derive(CandidType, Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct ExportHeader {
+    pub format_version: u32,
+    pub dimension: u64,
+    pub metric: Metric,
+    pub index_params: IndexParams,
+    pub quota: Quota,
+    /// Names of the files the vectors were inserted from, sorted.
+    pub file_names: Vec<String>,
+    /// Total number of records in the collection.
+    pub record_count: u64,
+    pub last_built_at: Option<u64>,
+    /// Named vector fields, sorted by name.
+    #[serde(default)]
+    pub fields: Vec<VectorFieldSpec>,
+    /// Whether the records carry sparse vectors.
+    #[serde(default)]
+    pub sparse: bool,
+    /// Whether the records carry bags of token vectors.
+    #[serde(default)]
+    pub multi_vector: bool,
+    /// Format the vectors are stored in. Records always carry f32 values.
+    #[serde(default)]
+    pub precision: Precision,
+    /// Whether the vectors are normalized to unit length.
+    #[serde(default)]
+    pub normalize: bool,
+}
+
+/// One stored vector. `id` is the insertion position of the vector in the
+/// collection, starting at 0.
+#[derive(CandidType, Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct ExportRecord {
+    pub id: u64,
+    pub vector: Vec<f32>,
+    /// The vectors of the named fields, in the order of the header.
+    #[serde(default)]
+    pub fields: Vec<Vec<f32>>,
+    /// The sparse vector, present if the header announces sparse vectors.
+    #[serde(default)]
+    pub sparse: Option<SparseVector>,
+    /// The bag of token vectors, present if the header announces them.
+    #[serde(default)]
+    pub tokens: Option<Vec<Vec<f32>>>,
+    pub document: String,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct ExportPage {
+    pub header: ExportHeader,
+    pub records: Vec<ExportRecord>,
+    /// Offset to pass to fetch the next page, `None` once the last record was returned.
+    pub next_offset: Option<u64>,
+}
+
+/// A slice of the encoded HNSW index of a collection, as produced by `Graph::encode_range`.
+#[derive(CandidType, Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct IndexChunk {
+    pub bytes: Vec<u8>,
+    /// Size of the whole encoded index.
+    pub total_bytes: u64,
+}
@@ -0,0 +1,94 @@
+//! Named vector fields: further embeddings stored for every record of a
+//! collection next to its primary vector, each with its own dimension, metric
+//! and index. Queries search one field or combine several.
+use super::error::Error;
+use super::graph::Graph;
+use super::index::{Metric, Vector};
+use candid::CandidType;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+
+/// Name by which queries refer to the primary vector of a collection.
+pub const PRIMARY_FIELD: &str = "default";
+
+#[derive(CandidType, Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct VectorFieldSpec {
+    pub name: String,
+    pub dimension: u64,
+    pub metric: Metric,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct VectorFieldInfo {
+    pub name: String,
+    pub dimension: u64,
+    pub metric: Metric,
+    pub indexed_count: u64,
+}
+
+/// A field to search and the weight of its score in the combined score.
+#[derive(CandidType, Deserialize, Clone, PartialEq, Debug)]
+pub struct FieldQuery {
+    pub field: String,
+    pub vector: Vec<f32>,
+    pub weight: f32,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct VectorField {
+    pub dimension: usize,
+    pub metric: Metric,
+    pub keys: Vec<Vector>,
+    /// The index over the first `graph.len()` vectors, kept in stable memory.
+    #[serde(default)]
+    pub graph: Graph,
+}
+
+impl VectorField {
+    pub fn new(spec: &VectorFieldSpec, keys: Vec<Vector>) -> Self {
+        VectorField {
+            dimension: spec.dimension as usize,
+            metric: spec.metric,
+            keys,
+            graph: Graph::default(),
+        }
+    }
+}
+
+/// Fails with `Error::InvalidName` unless the field names are non-empty,
+/// distinct and other than `PRIMARY_FIELD`.
+pub fn validate(specs: &[VectorFieldSpec]) -> Result<(), Error> {
+    let mut names = HashSet::new();
+    for spec in specs {
+        if spec.name.is_empty() || spec.name == PRIMARY_FIELD || !names.insert(&spec.name) {
+            return Err(Error::InvalidName);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{validate, VectorFieldSpec, PRIMARY_FIELD};
+    use crate::database::error::Error;
+    use crate::database::index::Metric;
+
+    fn spec(name: &str) -> VectorFieldSpec {
+        VectorFieldSpec {
+            name: name.to_string(),
+            dimension: 2,
+            metric: Metric::Cosine,
+        }
+    }
+
+    #[test]
+    fn field_names() {
+        assert_eq!(validate(&[spec("title"), spec("body")]), Ok(()));
+        assert_eq!(validate(&[spec("")]), Err(Error::InvalidName));
+        assert_eq!(validate(&[spec(PRIMARY_FIELD)]), Err(Error::InvalidName));
+        assert_eq!(
+            validate(&[spec("title"), spec("title")]),
+            Err(Error::InvalidName)
+        );
+    }
+}
@@ -0,0 +1,691 @@
+//! HNSW graphs kept in stable memory. The neighbour list of every node on every
+//! layer is an entry of `GRAPHS`, so searches read the lists they visit in place
+//! and upgrades never copy a graph to the heap. The nodes of a graph are the
+//! positions of the vectors in their collection, which keeps the vectors.
+use super::error::Error;
+use super::index::{IndexParams, Metric, Vector};
+use super::memory::{get_graph_ids_memory, get_graphs_memory, Memory};
+use ic_stable_structures::{storable::Bound, StableBTreeMap, StableCell, Storable};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashSet};
+
+/// Maximum number of neighbours of a node on the layers above the first.
+const M: usize = 32;
+
+/// Maximum number of neighbours of a node on the first layer.
+const M0: usize = 2 * M;
+
+/// Highest layer a node can be assigned to.
+const MAX_LAYER: u8 = 15;
+
+/// Length of the header of an encoded graph: length, entry point and top layer.
+const HEADER_BYTES: u64 = 9;
+
+/// Distance in encoded bytes between the entries a graph keeps the offset of,
+/// so `encode_range` starts reading close to the requested offset.
+const CHECKPOINT_BYTES: u64 = 64 * 1024;
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+struct NodeKey {
+    graph: u64,
+    layer: u8,
+    node: u32,
+}
+
+/// Keys are big-endian, so the entries of a graph are contiguous and ordered
+/// by layer, then node.
+impl Storable for NodeKey {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut bytes = Vec::with_capacity(13);
+        bytes.extend_from_slice(&self.graph.to_be_bytes());
+        bytes.push(self.layer);
+        bytes.extend_from_slice(&self.node.to_be_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        NodeKey {
+            graph: u64::from_be_bytes(bytes[..8].try_into().unwrap()),
+            layer: bytes[8],
+            node: u32::from_be_bytes(bytes[9..13].try_into().unwrap()),
+        }
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 13,
+        is_fixed_size: true,
+    };
+}
+
+struct Neighbours(Vec<u32>);
+
+impl Storable for Neighbours {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(self.0.iter().flat_map(|node| node.to_le_bytes()).collect())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Neighbours(
+            bytes
+                .chunks_exact(4)
+                .map(|chunk| u32::from_le_bytes(chunk.try_into().unwrap()))
+                .collect(),
+        )
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: (M0 * 4) as u32,
+        is_fixed_size: false,
+    };
+}
+
+thread_local! {
+    static GRAPHS: RefCell<StableBTreeMap<NodeKey, Neighbours, Memory>> = RefCell::new(init_stable_data());
+
+    /// The id the next graph is stored under. Graphs are never rewritten in
+    /// place, so a rebuilt index does not disturb the one it replaces until
+    /// that one is freed.
+    static NEXT_ID: RefCell<StableCell<u64, Memory>> = RefCell::new(init_next_id());
+}
+
+fn init_stable_data() -> StableBTreeMap<NodeKey, Neighbours, Memory> {
+    StableBTreeMap::init(get_graphs_memory())
+}
+
+fn init_next_id() -> StableCell<u64, Memory> {
+    StableCell::init(get_graph_ids_memory(), 1).expect("failed to initialize graph ids")
+}
+
+fn allocate_id() -> u64 {
+    NEXT_ID.with(|next| {
+        let mut next = next.borrow_mut();
+        let id = *next.get();
+        next.set(id + 1).expect("failed to allocate graph id");
+        id
+    })
+}
+
+fn neighbours(
+    graphs: &StableBTreeMap<NodeKey, Neighbours, Memory>,
+    graph: u64,
+    layer: u8,
+    node: u32,
+) -> Vec<u32> {
+    graphs
+        .get(&NodeKey { graph, layer, node })
+        .map_or(vec![], |neighbours| neighbours.0)
+}
+
+/// Layer of `node`, drawn from the exponential distribution of the HNSW paper
+/// with a hash of the node in place of a random number, so builds are
+/// deterministic.
+fn layer_of(node: u32) -> u8 {
+    let mut x = (node as u64).wrapping_add(0x9e37_79b9_7f4a_7c15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^= x >> 31;
+    let uniform = ((x >> 11) + 1) as f64 / (1u64 << 53) as f64;
+    let layer = -uniform.ln() / (M as f64).ln();
+    (layer as u8).min(MAX_LAYER)
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Candidate {
+    distance: f32,
+    node: u32,
+}
+
+impl PartialEq for Candidate {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Candidate {}
+
+impl Ord for Candidate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.distance
+            .total_cmp(&other.distance)
+            .then(self.node.cmp(&other.node))
+    }
+}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Returns the `ef` nodes of one layer nearest to `query` found by a best-first
+/// walk from `entry_points`, nearest first.
+fn search_layer(
+    points: &[Vector],
+    metric: Metric,
+    query: &Vector,
+    entry_points: &[u32],
+    ef: usize,
+    mut neighbours: impl FnMut(u32) -> Vec<u32>,
+) -> Vec<Candidate> {
+    let mut visited: HashSet<u32> = entry_points.iter().copied().collect();
+    let mut candidates = BinaryHeap::new();
+    let mut found = BinaryHeap::new();
+    for &node in entry_points {
+        let candidate = Candidate {
+            distance: metric.distance(query, &points[node as usize]),
+            node,
+        };
+        candidates.push(Reverse(candidate));
+        found.push(candidate);
+    }
+
+    while let Some(Reverse(nearest)) = candidates.pop() {
+        if found.peek().is_some_and(|furthest| nearest > *furthest) {
+            break;
+        }
+        for node in neighbours(nearest.node) {
+            if !visited.insert(node) {
+                continue;
+            }
+            let candidate = Candidate {
+                distance: metric.distance(query, &points[node as usize]),
+                node,
+            };
+            if found.len() < ef || found.peek().is_some_and(|furthest| candidate < *furthest) {
+                candidates.push(Reverse(candidate));
+                found.push(candidate);
+                if found.len() > ef {
+                    found.pop();
+                }
+            }
+        }
+    }
+    found.into_sorted_vec()
+}
+
+/// Picks up to `max` neighbours among `candidates`, nearest first, preferring
+/// candidates closer to the node than to any neighbour already picked so the
+/// links spread in all directions.
+fn select_neighbours(
+    points: &[Vector],
+    metric: Metric,
+    candidates: &[Candidate],
+    max: usize,
+) -> Vec<u32> {
+    let mut selected: Vec<u32> = vec![];
+    let mut pruned: Vec<u32> = vec![];
+    for candidate in candidates {
+        if selected.len() == max {
+            break;
+        }
+        let point = &points[candidate.node as usize];
+        let diverse = selected
+            .iter()
+            .all(|&node| metric.distance(point, &points[node as usize]) > candidate.distance);
+        if diverse {
+            selected.push(candidate.node);
+        } else {
+            pruned.push(candidate.node);
+        }
+    }
+    let missing = max.saturating_sub(selected.len());
+    selected.extend(pruned.into_iter().take(missing));
+    selected
+}
+
+fn take<'a>(bytes: &mut &'a [u8], len: usize) -> Result<&'a [u8], Error> {
+    if bytes.len() < len {
+        return Err(Error::InvalidImport("truncated index".to_string()));
+    }
+    let (head, tail) = bytes.split_at(len);
+    *bytes = tail;
+    Ok(head)
+}
+
+fn take_u32(bytes: &mut &[u8]) -> Result<u32, Error> {
+    Ok(u32::from_le_bytes(take(bytes, 4)?.try_into().unwrap()))
+}
+
+/// An HNSW graph over the first `len` vectors of a collection. Only this
+/// summary lives on the heap; the neighbour lists are the entries of `GRAPHS`
+/// under `id`. A graph owns its entries, which `free` removes. Its serde
+/// encoding is the summary only, so a decoded graph shares the entries of the
+/// encoded one: exactly one of them may be freed, by whoever keeps it last.
+#[derive(Serialize, Deserialize, Default, PartialEq, Debug)]
+pub struct Graph {
+    /// 0 for the empty graph, which has no entries.
+    id: u64,
+    len: u32,
+    entry_point: u32,
+    top_layer: u8,
+    /// Length of the encoding, or 0 if unknown.
+    #[serde(default)]
+    encoded_len: u64,
+    /// `(offset, layer, node)` of an entry about every `CHECKPOINT_BYTES` of
+    /// the encoding, in order.
+    #[serde(default)]
+    checkpoints: Vec<(u64, u8, u32)>,
+}
+
+/// Length of an encoded entry with `count` neighbours.
+fn entry_len(count: usize) -> u64 {
+    7 + 4 * count as u64
+}
+
+impl Graph {
+    /// Builds the graph of `points` on the heap and writes it to stable memory.
+    pub fn build(points: &[Vector], metric: Metric, params: &IndexParams) -> Self {
+        if points.is_empty() {
+            return Graph::default();
+        }
+        let ef_construction = params.ef_construction.max(1);
+
+        // links[node][layer] are the neighbours of `node` on `layer`.
+        let mut links: Vec<Vec<Vec<u32>>> = Vec::with_capacity(points.len());
+        let mut entry_point = 0;
+        let mut top_layer = layer_of(0);
+        links.push(vec![vec![]; top_layer as usize + 1]);
+
+        for node in 1..points.len() as u32 {
+            let layer = layer_of(node);
+            links.push(vec![vec![]; layer as usize + 1]);
+            let query = &points[node as usize];
+
+            let mut entry_points = vec![entry_point];
+            for l in (layer + 1..=top_layer).rev() {
+                let nearest = search_layer(points, metric, query, &entry_points, 1, |n| {
+                    links[n as usize][l as usize].clone()
+                });
+                entry_points = vec![nearest[0].node];
+            }
+            for l in (0..=layer.min(top_layer)).rev() {
+                let found =
+                    search_layer(points, metric, query, &entry_points, ef_construction, |n| {
+                        links[n as usize][l as usize].clone()
+                    });
+                let max = if l == 0 { M0 } else { M };
+                let selected = select_neighbours(points, metric, &found, max);
+                for &neighbour in &selected {
+                    let base = &points[neighbour as usize];
+                    let list = &mut links[neighbour as usize][l as usize];
+                    list.push(node);
+                    if list.len() > max {
+                        let mut candidates = list
+                            .iter()
+                            .map(|&n| Candidate {
+                                distance: metric.distance(base, &points[n as usize]),
+                                node: n,
+                            })
+                            .collect::<Vec<_>>();
+                        candidates.sort();
+                        *list = select_neighbours(points, metric, &candidates, max);
+                    }
+                }
+                links[node as usize][l as usize] = selected;
+                entry_points = found.iter().map(|candidate| candidate.node).collect();
+            }
+
+            if layer > top_layer {
+                entry_point = node;
+                top_layer = layer;
+            }
+        }
+
+        let mut entries = links
+            .into_iter()
+            .enumerate()
+            .flat_map(|(node, layers)| {
+                layers
+                    .into_iter()
+                    .enumerate()
+                    .map(move |(layer, neighbours)| (layer as u8, node as u32, neighbours))
+            })
+            .collect::<Vec<_>>();
+        entries.sort_unstable_by_key(|&(layer, node, _)| (layer, node));
+        Self::store(entries, points.len() as u32, entry_point, top_layer)
+    }
+
+    /// Number of vectors in the graph.
+    pub fn len(&self) -> usize {
+        self.len as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns up to `ef` nodes nearest to `query`, nearest first. `points`
+    /// and `metric` are those the graph was built with.
+    pub fn search(
+        &self,
+        points: &[Vector],
+        metric: Metric,
+        query: &Vector,
+        ef: usize,
+    ) -> Vec<usize> {
+        if self.is_empty() {
+            return vec![];
+        }
+        GRAPHS.with(|graphs| {
+            let graphs = graphs.borrow();
+            let mut entry_points = vec![self.entry_point];
+            for layer in (1..=self.top_layer).rev() {
+                let nearest = search_layer(points, metric, query, &entry_points, 1, |node| {
+                    neighbours(&graphs, self.id, layer, node)
+                });
+                entry_points = vec![nearest[0].node];
+            }
+            search_layer(points, metric, query, &entry_points, ef.max(1), |node| {
+                neighbours(&graphs, self.id, 0, node)
+            })
+            .into_iter()
+            .map(|candidate| candidate.node as usize)
+            .collect()
+        })
+    }
+
+    fn entries(&self) -> Vec<(NodeKey, Neighbours)> {
+        let start = NodeKey {
+            graph: self.id,
+            layer: 0,
+            node: 0,
+        };
+        let end = NodeKey {
+            graph: self.id + 1,
+            layer: 0,
+            node: 0,
+        };
+        GRAPHS.with(|graphs| graphs.borrow().range(start..end).collect())
+    }
+
+    /// Stores `entries`, ordered by layer then node, under a new id.
+    fn store(entries: Vec<(u8, u32, Vec<u32>)>, len: u32, entry_point: u32, top_layer: u8) -> Self {
+        let id = allocate_id();
+        let mut offset = HEADER_BYTES;
+        let mut checkpoints = vec![];
+        GRAPHS.with(|graphs| {
+            let mut graphs = graphs.borrow_mut();
+            for (layer, node, neighbours) in entries {
+                let next_checkpoint = checkpoints
+                    .last()
+                    .map_or(0, |&(checkpoint, _, _)| checkpoint + CHECKPOINT_BYTES);
+                if offset >= next_checkpoint {
+                    checkpoints.push((offset, layer, node));
+                }
+                offset += entry_len(neighbours.len());
+                let key = NodeKey {
+                    graph: id,
+                    layer,
+                    node,
+                };
+                graphs.insert(key, Neighbours(neighbours));
+            }
+        });
+        Graph {
+            id,
+            len,
+            entry_point,
+            top_layer,
+            encoded_len: offset,
+            checkpoints,
+        }
+    }
+
+    /// Removes the entries of the graph, leaving it empty.
+    pub fn free(&mut self) {
+        if self.is_empty() {
+            return;
+        }
+        let keys = self.entries().into_iter().map(|(key, _)| key);
+        GRAPHS.with(|graphs| {
+            let mut graphs = graphs.borrow_mut();
+            for key in keys {
+                graphs.remove(&key);
+            }
+        });
+        *self = Graph::default();
+    }
+
+    /// Copies the graph under a new id.
+    pub fn copy(&self) -> Self {
+        if self.is_empty() {
+            return Graph::default();
+        }
+        let entries = self
+            .entries()
+            .into_iter()
+            .map(|(key, neighbours)| (key.layer, key.node, neighbours.0))
+            .collect();
+        Self::store(entries, self.len, self.entry_point, self.top_layer)
+    }
+
+    /// Upper bound on the encoded length of a graph of `len` nodes: every node
+    /// on every layer with a full neighbour list.
+    pub fn max_encoded_len(len: u64) -> u64 {
+        let entry = |neighbours: usize| 7 + 4 * neighbours as u64;
+        let node = entry(M0) + MAX_LAYER as u64 * entry(M);
+        HEADER_BYTES.saturating_add(len.saturating_mul(node))
+    }
+
+    /// Length of the encoding of the graph.
+    pub fn encoded_len(&self) -> u64 {
+        match self.encoded_len {
+            0 if !self.is_empty() => {
+                let entries = self.entries();
+                HEADER_BYTES
+                    + entries
+                        .iter()
+                        .map(|(_, neighbours)| entry_len(neighbours.0.len()))
+                        .sum::<u64>()
+            }
+            encoded_len => encoded_len,
+        }
+    }
+
+    /// Returns up to `length` bytes of the encoding of the graph from
+    /// `offset`, reading only the entries from the checkpoint before `offset`
+    /// to the end of the range. A graph encodes as
+    ///
+    /// ```text
+    /// len (u32) | entry point (u32) | top layer (u8) | entries
+    /// ```
+    ///
+    /// where each entry, in order of layer then node, is
+    /// `layer (u8) | node (u32) | count (u16) | count neighbours (u32)`, all
+    /// integers little-endian. The empty graph encodes to no bytes.
+    pub fn encode_range(&self, offset: u64, length: u64) -> Vec<u8> {
+        if self.is_empty() {
+            return vec![];
+        }
+        let end = offset.saturating_add(length);
+        let mut bytes = vec![];
+        let mut header = vec![];
+        header.extend_from_slice(&self.len.to_le_bytes());
+        header.extend_from_slice(&self.entry_point.to_le_bytes());
+        header.push(self.top_layer);
+        let mut append = |position: u64, entry: &[u8]| {
+            let entry_end = position + entry.len() as u64;
+            if entry_end > offset && position < end {
+                let from = offset.saturating_sub(position) as usize;
+                let to = (end.min(entry_end) - position) as usize;
+                bytes.extend_from_slice(&entry[from..to]);
+            }
+            entry_end
+        };
+        append(0, &header);
+
+        let index = self
+            .checkpoints
+            .partition_point(|&(checkpoint, _, _)| checkpoint <= offset);
+        let (mut position, layer, node) = index
+            .checked_sub(1)
+            .map_or((HEADER_BYTES, 0, 0), |index| self.checkpoints[index]);
+        let start = NodeKey {
+            graph: self.id,
+            layer,
+            node,
+        };
+        let stop = NodeKey {
+            graph: self.id + 1,
+            layer: 0,
+            node: 0,
+        };
+        GRAPHS.with(|graphs| {
+            let mut entry = vec![];
+            for (key, neighbours) in graphs.borrow().range(start..stop) {
+                if position >= end {
+                    break;
+                }
+                entry.clear();
+                entry.push(key.layer);
+                entry.extend_from_slice(&key.node.to_le_bytes());
+                entry.extend_from_slice(&(neighbours.0.len() as u16).to_le_bytes());
+                for node in neighbours.0 {
+                    entry.extend_from_slice(&node.to_le_bytes());
+                }
+                position = append(position, &entry);
+            }
+        });
+        bytes
+    }
+
+    /// Checks an encoded graph over `points` vectors and stores it under a new id.
+    pub fn decode(mut bytes: &[u8], points: usize) -> Result<Self, Error> {
+        let invalid = |message: &str| Err(Error::InvalidImport(message.to_string()));
+        if bytes.is_empty() {
+            return Ok(Graph::default());
+        }
+        let len = take_u32(&mut bytes)?;
+        let entry_point = take_u32(&mut bytes)?;
+        let top_layer = take(&mut bytes, 1)?[0];
+        if len as usize > points {
+            return invalid("index holds more points than the collection");
+        }
+        if entry_point >= len || top_layer > MAX_LAYER {
+            return invalid("invalid entry point");
+        }
+
+        let mut entries = vec![];
+        let mut last = None;
+        while !bytes.is_empty() {
+            let layer = take(&mut bytes, 1)?[0];
+            let node = take_u32(&mut bytes)?;
+            let count = u16::from_le_bytes(take(&mut bytes, 2)?.try_into().unwrap()) as usize;
+            if Some((layer, node)) <= last {
+                return invalid("index entries out of order");
+            }
+            if layer > top_layer || node >= len {
+                return invalid("invalid node in index");
+            }
+            if count > if layer == 0 { M0 } else { M } {
+                return invalid("too many neighbours in index");
+            }
+            let neighbours = (0..count)
+                .map(|_| take_u32(&mut bytes))
+                .collect::<Result<Vec<_>, _>>()?;
+            if neighbours.iter().any(|&neighbour| neighbour >= len) {
+                return invalid("invalid neighbour in index");
+            }
+            last = Some((layer, node));
+            entries.push((layer, node, neighbours));
+        }
+
+        // Entries are strictly ordered, so this counts distinct nodes.
+        let first_layer = entries.iter().filter(|(layer, _, _)| *layer == 0).count();
+        if first_layer != len as usize {
+            return invalid("index does not cover its points");
+        }
+        let has_entry_point = entries
+            .iter()
+            .any(|&(layer, node, _)| layer == top_layer && node == entry_point);
+        if !has_entry_point {
+            return invalid("invalid entry point");
+        }
+        Ok(Self::store(entries, len, entry_point, top_layer))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Graph;
+    use crate::database::error::Error;
+    use crate::database::index::{IndexParams, Metric, Vector};
+
+    fn grid() -> Vec<Vector> {
+        (0..200)
+            .map(|i| Vector::from(vec![(i % 20) as f32, (i / 20) as f32]))
+            .collect()
+    }
+
+    fn encode(graph: &Graph) -> Vec<u8> {
+        graph.encode_range(0, u64::MAX)
+    }
+
+    #[test]
+    fn search_finds_nearest() {
+        let points = grid();
+        let graph = Graph::build(&points, Metric::Euclidean, &IndexParams::default());
+        assert_eq!(graph.len(), 200);
+
+        for (i, point) in points.iter().enumerate().step_by(7) {
+            assert_eq!(graph.search(&points, Metric::Euclidean, point, 10)[0], i);
+        }
+        let query = Vector::from(vec![4.2, 3.9]);
+        assert_eq!(graph.search(&points, Metric::Euclidean, &query, 10)[0], 84);
+    }
+
+    #[test]
+    fn encode_roundtrip() {
+        let points = grid();
+        let graph = Graph::build(&points, Metric::Euclidean, &IndexParams::default());
+        let bytes = encode(&graph);
+
+        let copy = Graph::decode(&bytes, points.len()).unwrap();
+        assert_ne!(copy.id, graph.id);
+        assert_eq!(encode(&copy), bytes);
+        assert!(matches!(
+            Graph::decode(&bytes, 10),
+            Err(Error::InvalidImport(_))
+        ));
+        assert!(matches!(
+            Graph::decode(&bytes[..bytes.len() - 1], points.len()),
+            Err(Error::InvalidImport(_))
+        ));
+    }
+
+    #[test]
+    fn encode_range_matches_the_encoding() {
+        let points = (0..1200)
+            .map(|i| Vector::from(vec![(i % 40) as f32, (i / 40) as f32]))
+            .collect::<Vec<_>>();
+        let graph = Graph::build(&points, Metric::Euclidean, &IndexParams::default());
+        let bytes = encode(&graph);
+        assert!(graph.checkpoints.len() > 1);
+        assert_eq!(graph.encoded_len(), bytes.len() as u64);
+
+        let length = 40_000;
+        for offset in [0, 5, 9, 65_540, bytes.len() as u64 - 3] {
+            let start = offset as usize;
+            let end = (start + length).min(bytes.len());
+            assert_eq!(graph.encode_range(offset, length as u64), bytes[start..end]);
+        }
+        assert!(graph.encode_range(bytes.len() as u64, 10).is_empty());
+    }
+
+    #[test]
+    fn free_removes_entries() {
+        let points = grid();
+        let mut graph = Graph::build(&points, Metric::Euclidean, &IndexParams::default());
+        let copy = graph.copy();
+        graph.free();
+        assert!(graph.is_empty());
+        assert!(encode(&graph).is_empty());
+        assert_eq!(
+            copy.search(&points, Metric::Euclidean, &points[5], 1),
+            vec![5]
+        );
+    }
+}
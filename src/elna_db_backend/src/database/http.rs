@@ -0,0 +1,333 @@
+use crate::database::api_key;
+use crate::database::error::Error;
+use crate::database::metrics;
+use crate::database::operations;
+use crate::database::quota::QuotaKind;
+use crate::database::users::{has_role, Role};
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::{query, update};
+use serde::de::DeserializeOwned;
+use serde_json::json;
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct HttpRequest {
+    pub method: String,
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct HttpResponse {
+    pub status_code: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    /// Asks the HTTP gateway to replay the request as an `http_request_update` call.
+    pub upgrade: Option<bool>,
+}
+
+impl HttpResponse {
+    fn new(status_code: u16, content_type: &str, body: Vec<u8>) -> Self {
+        Self {
+            status_code,
+            headers: vec![
+                ("Content-Type".to_string(), content_type.to_string()),
+                ("Access-Control-Allow-Origin".to_string(), "*".to_string()),
+            ],
+            body,
+            upgrade: None,
+        }
+    }
+
+    fn json(status_code: u16, body: serde_json::Value) -> Self {
+        Self::new(
+            status_code,
+            "application/json",
+            body.to_string().into_bytes(),
+        )
+    }
+
+    fn error(status_code: u16, message: &str) -> Self {
+        Self::json(status_code, json!({ "error": message }))
+    }
+
+    fn upgrade() -> Self {
+        Self {
+            upgrade: Some(true),
+            ..Self::new(200, "text/plain", vec![])
+        }
+    }
+
+    fn preflight() -> Self {
+        let mut response = Self::new(204, "text/plain", vec![]);
+        response.headers.extend([
+            (
+                "Access-Control-Allow-Methods".to_string(),
+                "GET, POST, DELETE, OPTIONS".to_string(),
+            ),
+            (
+                "Access-Control-Allow-Headers".to_string(),
+                "Authorization, Content-Type".to_string(),
+            ),
+        ]);
+        response
+    }
+}
+
+impl From<Result<serde_json::Value, Error>> for HttpResponse {
+    fn from(result: Result<serde_json::Value, Error>) -> Self {
+        match result {
+            Ok(body) => Self::json(200, body),
+            Err(error) => {
+                let status_code = match error {
+                    Error::NotFound => 404,
+                    Error::Unauthorized => 403,
+                    Error::UniqueViolation => 409,
+                    Error::RateLimited | Error::QuotaExceeded(QuotaKind::Queries) => 429,
+                    Error::MemoryError => 500,
+                    _ => 400,
+                };
+                Self::error(status_code, &error.to_string())
+            }
+        }
+    }
+}
+
+/// The REST routes served over HTTP. All but `Preflight` need an admin's API
+/// key. Collection names are relative to the namespace of the API key's
+/// principal, as in the Candid interface.
+#[derive(PartialEq, Debug)]
+enum Route<'a> {
+    Metrics,
+    Preflight,
+    ListCollections,
+    CollectionInfo(&'a str),
+    Query(&'a str),
+    CreateCollection,
+    Insert(&'a str),
+    BuildIndex(&'a str),
+    DeleteCollection(&'a str),
+}
+
+impl<'a> Route<'a> {
+    fn parse(method: &str, url: &'a str) -> Option<Self> {
+        let path = url.split('?').next().unwrap_or_default();
+        let segments = path
+            .trim_matches('/')
+            .split('/')
+            .filter(|segment| !segment.is_empty())
+            .collect::<Vec<_>>();
+        let route = match (method, segments.as_slice()) {
+            ("OPTIONS", _) => Route::Preflight,
+            ("GET", ["metrics"]) => Route::Metrics,
+            ("GET", ["collections"]) => Route::ListCollections,
+            ("POST", ["collections"]) => Route::CreateCollection,
+            ("GET", ["collections", name]) => Route::CollectionInfo(name),
+            ("DELETE", ["collections", name]) => Route::DeleteCollection(name),
+            ("POST", ["collections", name, "query"]) => Route::Query(name),
+            ("POST", ["collections", name, "insert"]) => Route::Insert(name),
+            ("POST", ["collections", name, "build"]) => Route::BuildIndex(name),
+            _ => return None,
+        };
+        Some(route)
+    }
+
+    /// Routes served by `http_request_update`: all but `Preflight`. Writes must
+    /// be, so that they persist; reads are too, since a query call's response
+    /// is not certified and could be forged by the one replica answering it.
+    fn is_update(&self) -> bool {
+        *self != Route::Preflight
+    }
+}
+
+#[derive(Deserialize)]
+struct CreateCollectionBody {
+    name: String,
+    dimension: usize,
+}
+
+#[derive(Deserialize)]
+struct InsertBody {
+    vectors: Vec<Vec<f32>>,
+    documents: Vec<String>,
+    file_name: String,
+}
+
+#[derive(Deserialize)]
+struct QueryBody {
+    vector: Vec<f32>,
+    limit: i32,
+}
+
+fn bearer_token(req: &HttpRequest) -> Option<&str> {
+    req.headers
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case("authorization"))
+        .and_then(|(_, value)| value.strip_prefix("Bearer "))
+        .map(str::trim)
+}
+
+/// Resolves the API key in the `Authorization: Bearer <key>` header to a
+/// principal that may manage collections.
+fn authenticate(req: &HttpRequest) -> Result<Principal, HttpResponse> {
+    let principal = bearer_token(req)
+        .and_then(api_key::principal_of)
+        .ok_or_else(|| HttpResponse::error(401, "Missing or invalid API key"))?;
+    if !has_role(&principal, Role::Admin) {
+        return Err(HttpResponse::error(403, &Error::Unauthorized.to_string()));
+    }
+    Ok(principal)
+}
+
+fn parse_body<T: DeserializeOwned>(req: &HttpRequest) -> Result<T, HttpResponse> {
+    serde_json::from_slice(&req.body)
+        .map_err(|error| HttpResponse::error(400, &format!("Invalid request body: {}", error)))
+}
+
+fn handle(req: &HttpRequest, route: Route) -> Result<HttpResponse, HttpResponse> {
+    if route == Route::Preflight {
+        return Ok(HttpResponse::preflight());
+    }
+
+    let caller = authenticate(req)?;
+    if route == Route::Metrics {
+        let body = metrics::encode_prometheus(&metrics::collect());
+        return Ok(HttpResponse::new(
+            200,
+            "text/plain; version=0.0.4",
+            body.into_bytes(),
+        ));
+    }
+    let result = match route {
+        Route::ListCollections => operations::get_collections(caller).map(|names| json!(names)),
+        Route::CollectionInfo(name) => {
+            operations::collection_info(caller, name).map(|info| json!(info))
+        }
+        Route::Query(name) => {
+            let body: QueryBody = parse_body(req)?;
+            operations::http_query(caller, name, body.vector, body.limit).map(|results| {
+                let results = results
+                    .into_iter()
+                    .map(|(score, document)| json!({ "score": score, "document": document }))
+                    .collect::<Vec<_>>();
+                json!(results)
+            })
+        }
+        Route::CreateCollection => {
+            let body: CreateCollectionBody = parse_body(req)?;
+            operations::create_collection(caller, &body.name, body.dimension).map(|()| json!({}))
+        }
+        Route::Insert(name) => {
+            let body: InsertBody = parse_body(req)?;
+            operations::insert(caller, name, body.vectors, body.documents, body.file_name)
+                .map(|()| json!({}))
+        }
+        Route::BuildIndex(name) => operations::build_index(caller, name).map(|()| json!({})),
+        Route::DeleteCollection(name) => {
+            operations::delete_collection(caller, name).map(|()| json!({}))
+        }
+        Route::Metrics | Route::Preflight => unreachable!(),
+    };
+    Ok(result.into())
+}
+
+/// Whether `http_request_update` may run `req`: an update route called with
+/// the API key of an admin. Checked by `canister_inspect_message`, since the
+/// gateway makes the call anonymously.
+pub fn accepts_update(req: &HttpRequest) -> bool {
+    Route::parse(&req.method, &req.url).is_some_and(|route| route.is_update())
+        && authenticate(req).is_ok()
+}
+
+/// Serves CORS preflights through the HTTP gateway and upgrades every other
+/// route to `http_request_update`, so that responses go through consensus.
+#[query]
+fn http_request(req: HttpRequest) -> HttpResponse {
+    match Route::parse(&req.method, &req.url) {
+        None => HttpResponse::error(404, "Not found"),
+        Some(route) if route.is_update() => HttpResponse::upgrade(),
+        Some(route) => handle(&req, route).unwrap_or_else(|response| response),
+    }
+}
+
+#[update]
+fn http_request_update(req: HttpRequest) -> HttpResponse {
+    match Route::parse(&req.method, &req.url) {
+        Some(route) if route.is_update() => handle(&req, route).unwrap_or_else(|response| response),
+        _ => HttpResponse::error(404, "Not found"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{accepts_update, bearer_token, handle, HttpRequest, Route};
+
+    #[test]
+    fn parse_routes() {
+        assert_eq!(Route::parse("GET", "/metrics"), Some(Route::Metrics));
+        assert_eq!(
+            Route::parse("GET", "/collections?x=1"),
+            Some(Route::ListCollections)
+        );
+        assert_eq!(
+            Route::parse("POST", "/collections/docs/query"),
+            Some(Route::Query("docs"))
+        );
+        assert_eq!(
+            Route::parse("DELETE", "/collections/docs/"),
+            Some(Route::DeleteCollection("docs"))
+        );
+        assert_eq!(Route::parse("PUT", "/collections/docs"), None);
+        assert_eq!(Route::parse("GET", "/collections/docs/query"), None);
+    }
+
+    #[test]
+    fn routes_are_upgraded() {
+        for (method, url) in [
+            ("GET", "/metrics"),
+            ("GET", "/collections"),
+            ("GET", "/collections/docs"),
+            ("POST", "/collections/docs/query"),
+            ("POST", "/collections/docs/insert"),
+        ] {
+            assert!(Route::parse(method, url).unwrap().is_update());
+        }
+        assert!(!Route::parse("OPTIONS", "/collections").unwrap().is_update());
+    }
+
+    #[test]
+    fn updates_need_an_api_key() {
+        let mut req = HttpRequest {
+            method: "POST".to_string(),
+            url: "/collections/docs/insert".to_string(),
+            headers: vec![],
+            body: vec![],
+        };
+        assert!(!accepts_update(&req));
+        req.headers = vec![("Authorization".to_string(), "Bearer elna_abc".to_string())];
+        assert!(!accepts_update(&req));
+    }
+
+    #[test]
+    fn metrics_need_an_api_key() {
+        let req = HttpRequest {
+            method: "GET".to_string(),
+            url: "/metrics".to_string(),
+            headers: vec![],
+            body: vec![],
+        };
+        let response = handle(&req, Route::Metrics).unwrap_err();
+        assert_eq!(response.status_code, 401);
+    }
+
+    #[test]
+    fn bearer_token_header() {
+        let req = HttpRequest {
+            method: "GET".to_string(),
+            url: "/collections".to_string(),
+            headers: vec![("authorization".to_string(), "Bearer elna_abc".to_string())],
+            body: vec![],
+        };
+        assert_eq!(bearer_token(&req), Some("elna_abc"));
+    }
+}
@@ -0,0 +1,414 @@
+//! Chunked import of collections in the export format. The records and the
+//! optional encoded index are uploaded over several calls into a session kept
+//! on the heap, checked against the checksum given up front, and turned into a
+//! collection in a single step by `commit_import`.
+use crate::database::audit::Operation;
+use crate::database::collection::Collection;
+use crate::database::db::DB;
+use crate::database::error::Error;
+use crate::database::export::{ExportHeader, ExportRecord, FORMAT_VERSION};
+use crate::database::graph::Graph;
+use crate::database::index::{self, Metric, MultiVector, Vector};
+use crate::database::namespace::scoped_name;
+use crate::database::operations::audited;
+use crate::database::quota::Quota;
+use crate::database::session::Sessions;
+use crate::database::sparse::SparseVector;
+use crate::database::users::is_owner;
+use elna_auth_macros::check_authorization;
+use ic_cdk::update;
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+
+/// An upload in progress. The checksum is the hex-encoded SHA-256 of, for each
+/// record in order, its `id` as a little-endian u64, its vector and then the
+/// vectors of its fields as little-endian f32s, its sparse vector if any as the
+/// number of entries as a little-endian u64, the indices as little-endian u32s
+/// and the values as little-endian f32s, its token vectors if any as their
+/// number as a little-endian u64 followed by the tokens as little-endian f32s,
+/// the byte length of its document as a little-endian u64 and the UTF-8
+/// document, followed by the encoded index, if any.
+pub struct ImportSession {
+    name: String,
+    header: ExportHeader,
+    checksum: String,
+    hasher: Sha256,
+    keys: Vec<Vector>,
+    fields: Vec<Vec<Vector>>,
+    sparse: Option<Vec<SparseVector>>,
+    tokens: Option<Vec<MultiVector>>,
+    values: Vec<String>,
+    index: Vec<u8>,
+}
+
+impl ImportSession {
+    pub fn new(name: String, header: ExportHeader, checksum: String) -> Result<Self, Error> {
+        if header.format_version != FORMAT_VERSION {
+            return Err(Error::InvalidImport(format!(
+                "unsupported format version {}",
+                header.format_version
+            )));
+        }
+        Ok(Self {
+            name,
+            checksum,
+            hasher: Sha256::new(),
+            keys: vec![],
+            fields: header.fields.iter().map(|_| vec![]).collect(),
+            sparse: header.sparse.then(Vec::new),
+            tokens: header.multi_vector.then(Vec::new),
+            values: vec![],
+            index: vec![],
+            header,
+        })
+    }
+
+    /// Appends records, which must continue the ids where the previous chunk stopped.
+    pub fn append_records(&mut self, records: Vec<ExportRecord>) -> Result<(), Error> {
+        if !self.index.is_empty() {
+            return Err(Error::InvalidImport(
+                "records must be uploaded before the index".to_string(),
+            ));
+        }
+        let dimension = self.header.dimension as usize;
+        let fields = &self.header.fields;
+        let mismatched = records.iter().any(|record| {
+            record.vector.len() != dimension
+                || record.fields.len() != fields.len()
+                || fields
+                    .iter()
+                    .zip(&record.fields)
+                    .any(|(field, vector)| vector.len() != field.dimension as usize)
+                || record
+                    .tokens
+                    .iter()
+                    .flatten()
+                    .any(|token| token.len() != dimension)
+        });
+        if mismatched {
+            return Err(Error::DimensionMismatch);
+        }
+        let next_id = self.keys.len() as u64;
+        let in_order = records
+            .iter()
+            .enumerate()
+            .all(|(i, record)| record.id == next_id + i as u64);
+        if !in_order {
+            return Err(Error::InvalidImport(format!(
+                "expected records starting at id {}",
+                next_id
+            )));
+        }
+        let precision = self.header.precision;
+        for record in &records {
+            let invalid = |reason: &str| Error::InvalidVector {
+                row: record.id,
+                reason: reason.to_string(),
+            };
+            index::check(&record.vector, self.header.metric, precision).map_err(invalid)?;
+            for (field, vector) in fields.iter().zip(&record.fields) {
+                index::check(vector, field.metric, precision).map_err(invalid)?;
+            }
+            for token in record.tokens.iter().flatten() {
+                index::check(token, Metric::DotProduct, precision).map_err(invalid)?;
+            }
+        }
+        if next_id + records.len() as u64 > self.header.record_count {
+            return Err(Error::InvalidImport(
+                "more records than announced in the header".to_string(),
+            ));
+        }
+        if records
+            .iter()
+            .any(|record| record.sparse.is_some() != self.header.sparse)
+        {
+            return Err(Error::InvalidImport(
+                "sparse vectors do not match the header".to_string(),
+            ));
+        }
+        if records
+            .iter()
+            .any(|record| record.tokens.is_some() != self.header.multi_vector)
+        {
+            return Err(Error::InvalidImport(
+                "token vectors do not match the header".to_string(),
+            ));
+        }
+        let records = records
+            .into_iter()
+            .map(|mut record| {
+                record.sparse = record.sparse.map(SparseVector::normalized).transpose()?;
+                Ok(record)
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+
+        for record in records {
+            self.hasher.update(record.id.to_le_bytes());
+            for value in record.vector.iter().chain(record.fields.iter().flatten()) {
+                self.hasher.update(value.to_le_bytes());
+            }
+            if let Some(sparse) = &record.sparse {
+                self.hasher
+                    .update((sparse.indices.len() as u64).to_le_bytes());
+                for index in &sparse.indices {
+                    self.hasher.update(index.to_le_bytes());
+                }
+                for value in &sparse.values {
+                    self.hasher.update(value.to_le_bytes());
+                }
+            }
+            if let Some(tokens) = &record.tokens {
+                self.hasher.update((tokens.len() as u64).to_le_bytes());
+                for value in tokens.iter().flatten() {
+                    self.hasher.update(value.to_le_bytes());
+                }
+            }
+            self.hasher
+                .update((record.document.len() as u64).to_le_bytes());
+            self.hasher.update(record.document.as_bytes());
+            self.keys
+                .push(Vector::with_precision(record.vector, precision));
+            for (keys, vector) in self.fields.iter_mut().zip(record.fields) {
+                keys.push(Vector::with_precision(vector, precision));
+            }
+            if let (Some(sparse), Some(vector)) = (&mut self.sparse, record.sparse) {
+                sparse.push(vector);
+            }
+            if let (Some(bags), Some(tokens)) = (&mut self.tokens, record.tokens) {
+                bags.push(MultiVector::with_precision(tokens, precision));
+            }
+            self.values.push(record.document);
+        }
+        Ok(())
+    }
+
+    /// Appends a chunk of the encoded index, failing if the index would grow
+    /// larger than any graph of `record_count` nodes encodes to.
+    pub fn append_index(&mut self, bytes: Vec<u8>) -> Result<(), Error> {
+        let max_len = Graph::max_encoded_len(self.header.record_count);
+        if self.index.len() as u64 + bytes.len() as u64 > max_len {
+            return Err(Error::InvalidImport(format!(
+                "index larger than {} bytes",
+                max_len
+            )));
+        }
+        self.hasher.update(&bytes);
+        self.index.extend(bytes);
+        Ok(())
+    }
+
+    /// Verifies the upload is complete and intact and builds the collection.
+    pub fn finish(self) -> Result<(String, Collection), Error> {
+        if self.keys.len() as u64 != self.header.record_count {
+            return Err(Error::InvalidImport(format!(
+                "received {} of {} records",
+                self.keys.len(),
+                self.header.record_count
+            )));
+        }
+        let digest = self.hasher.finalize();
+        let checksum = digest
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect::<String>();
+        if !checksum.eq_ignore_ascii_case(&self.checksum) {
+            return Err(Error::ChecksumMismatch);
+        }
+
+        let index = (!self.index.is_empty()).then_some(self.index.as_slice());
+        let collection = Collection::from_export(
+            &self.header,
+            self.keys,
+            self.fields,
+            self.sparse,
+            self.tokens,
+            self.values,
+            index,
+        )?;
+        Ok((self.name, collection))
+    }
+}
+
+thread_local! {
+    pub static IMPORTS: RefCell<Sessions<ImportSession>> = RefCell::new(Sessions::default());
+}
+
+/// Opens an import of a collection named `name` described by `header`, to be
+/// verified against `checksum`. Returns the session id to upload to. The quota
+/// in the header is only kept when the owner imports, as only the owner sets
+/// collection quotas.
+#[update]
+#[check_authorization]
+fn begin_import(name: String, mut header: ExportHeader, checksum: String) -> Result<u64, Error> {
+    let caller = ic_cdk::caller();
+    let name = scoped_name(&caller, &name)?;
+    if DB.with(|db| db.borrow().name_taken(&name)) {
+        return Err(Error::UniqueViolation);
+    }
+    if !is_owner(&caller) {
+        header.quota = Quota::default();
+    }
+    let session = ImportSession::new(name, header, checksum)?;
+    IMPORTS.with(|imports| imports.borrow_mut().open(caller, session))
+}
+
+#[update]
+#[check_authorization]
+fn import_records(session: u64, records: Vec<ExportRecord>) -> Result<(), Error> {
+    let caller = ic_cdk::caller();
+    IMPORTS.with(|imports| {
+        imports
+            .borrow_mut()
+            .get_mut(session, &caller)?
+            .append_records(records)
+    })
+}
+
+/// Appends a chunk of the encoded index, as returned by `export_index`.
+/// Chunks must follow all the records.
+#[update]
+#[check_authorization]
+fn import_index_chunk(session: u64, bytes: Vec<u8>) -> Result<(), Error> {
+    let caller = ic_cdk::caller();
+    IMPORTS.with(|imports| {
+        imports
+            .borrow_mut()
+            .get_mut(session, &caller)?
+            .append_index(bytes)
+    })
+}
+
+/// Verifies the upload and creates the collection. The session is closed
+/// whether or not the import succeeds.
+#[update]
+#[check_authorization]
+fn commit_import(session: u64) -> Result<(), Error> {
+    let caller = ic_cdk::caller();
+    audited(
+        caller,
+        Operation::ImportCollection,
+        "commit_import",
+        String::new(),
+        |target| {
+            let session = IMPORTS.with(|imports| imports.borrow_mut().close(session, &caller))?;
+            *target = session.name.clone();
+            let (name, collection) = session.finish()?;
+            DB.with(|db| db.borrow_mut().import_collection(&name, collection))
+        },
+    )
+}
+
+#[update]
+#[check_authorization]
+fn abort_import(session: u64) -> Result<(), Error> {
+    let caller = ic_cdk::caller();
+    IMPORTS
+        .with(|imports| imports.borrow_mut().close(session, &caller))
+        .map(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ImportSession;
+    use crate::database::error::Error;
+    use crate::database::export::{ExportHeader, ExportRecord, FORMAT_VERSION};
+    use crate::database::graph::Graph;
+    use crate::database::index::{IndexParams, Metric, Precision};
+    use crate::database::quota::Quota;
+    use sha2::{Digest, Sha256};
+
+    fn header(record_count: u64) -> ExportHeader {
+        ExportHeader {
+            format_version: FORMAT_VERSION,
+            dimension: 2,
+            metric: Metric::Euclidean,
+            index_params: IndexParams::default(),
+            quota: Quota::default(),
+            file_names: vec!["file".to_string()],
+            record_count,
+            last_built_at: None,
+            fields: vec![],
+            sparse: false,
+            multi_vector: false,
+            precision: Precision::F32,
+            normalize: false,
+        }
+    }
+
+    fn record(id: u64, vector: Vec<f32>, document: &str) -> ExportRecord {
+        ExportRecord {
+            id,
+            vector,
+            fields: vec![],
+            sparse: None,
+            tokens: None,
+            document: document.to_string(),
+        }
+    }
+
+    fn checksum(records: &[ExportRecord]) -> String {
+        let mut hasher = Sha256::new();
+        for record in records {
+            hasher.update(record.id.to_le_bytes());
+            for value in &record.vector {
+                hasher.update(value.to_le_bytes());
+            }
+            hasher.update((record.document.len() as u64).to_le_bytes());
+            hasher.update(record.document.as_bytes());
+        }
+        hasher
+            .finalize()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+
+    #[test]
+    fn import_in_chunks() {
+        let records = vec![
+            record(0, vec![1.0, 2.0], "a"),
+            record(1, vec![3.0, 4.0], "b"),
+        ];
+        let mut session =
+            ImportSession::new("test".to_string(), header(2), checksum(&records)).unwrap();
+        assert_eq!(session.append_records(records[..1].to_vec()), Ok(()));
+        assert_eq!(session.append_records(records[1..].to_vec()), Ok(()));
+
+        let (name, collection) = session.finish().unwrap();
+        assert_eq!(name, "test");
+        let info = collection.info();
+        assert_eq!(info.vector_count, 2);
+        assert_eq!(info.indexed_count, 0);
+        assert_eq!(info.file_count, 1);
+    }
+
+    #[test]
+    fn reject_corrupted_import() {
+        let records = vec![record(0, vec![1.0, 2.0], "a")];
+        let mut session =
+            ImportSession::new("test".to_string(), header(1), checksum(&records)).unwrap();
+        let _ = session.append_records(vec![record(0, vec![1.0, 2.5], "a")]);
+        assert_eq!(session.finish().err(), Some(Error::ChecksumMismatch));
+    }
+
+    #[test]
+    fn reject_out_of_order_records() {
+        let mut session = ImportSession::new("test".to_string(), header(2), String::new()).unwrap();
+        let result = session.append_records(vec![record(1, vec![1.0, 2.0], "a")]);
+        assert!(matches!(result, Err(Error::InvalidImport(_))));
+        let result = session.append_records(vec![record(0, vec![1.0], "a")]);
+        assert_eq!(result, Err(Error::DimensionMismatch));
+        let result = session.append_records(vec![record(0, vec![f32::NAN, 2.0], "a")]);
+        assert!(matches!(result, Err(Error::InvalidVector { row: 0, .. })));
+    }
+
+    #[test]
+    fn reject_oversized_index() {
+        let mut session = ImportSession::new("test".to_string(), header(1), String::new()).unwrap();
+        let max_len = Graph::max_encoded_len(1) as usize;
+        assert_eq!(session.append_index(vec![0; max_len - 1]), Ok(()));
+        assert_eq!(session.append_index(vec![0]), Ok(()));
+        let result = session.append_index(vec![0]);
+        assert!(matches!(result, Err(Error::InvalidImport(_))));
+    }
+}
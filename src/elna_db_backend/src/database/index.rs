@@ -1,26 +1,198 @@
-use instant_distance::{Builder, HnswMap, Point};
-use nalgebra::{ComplexField, DVector};
+use candid::CandidType;
+use half::{bf16, f16};
+use nalgebra::DVector;
 use serde::{Deserialize, Serialize};
 
-pub fn generate_index(points: Vec<Vector>, values: Vec<String>) -> HnswMap<Vector, String> {
-    Builder::default().build(points, values)
+/// How neighbours are ranked and scored. `Euclidean` ranks by squared
+/// Euclidean distance and, like `Cosine`, scores by cosine similarity;
+/// `DotProduct` ranks and scores by inner product.
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Debug)]
+pub enum Metric {
+    #[default]
+    Euclidean,
+    Cosine,
+    DotProduct,
 }
 
-#[derive(Clone, Serialize, Deserialize)]
+impl Metric {
+    /// Distance by which the HNSW graph ranks neighbours, smaller being nearer.
+    pub fn distance(&self, a: &Vector, b: &Vector) -> f32 {
+        match self {
+            Metric::Euclidean => a.distance(b),
+            Metric::Cosine => 1.0 - a.cos_sim(b),
+            Metric::DotProduct => -a.dot(b),
+        }
+    }
+
+    /// The metric to rank and score by. Unit vectors rank alike by all three
+    /// metrics, with cosine similarity equal to the inner product, so
+    /// `normalized` collections use the inner product and skip the norms.
+    pub fn for_vectors(self, normalized: bool) -> Metric {
+        if normalized {
+            Metric::DotProduct
+        } else {
+            self
+        }
+    }
+
+    /// Score returned by queries, larger being nearer.
+    pub fn score(&self, a: &Vector, b: &Vector) -> f32 {
+        match self {
+            Metric::Euclidean | Metric::Cosine => a.cos_sim(b),
+            Metric::DotProduct => a.dot(b),
+        }
+    }
+}
+
+/// Fails with the reason `values` cannot be stored in `precision` or searched
+/// by `metric`: NaN and infinite values break the ordering of the index, and
+/// the zero vector has no cosine similarity.
+pub fn check(values: &[f32], metric: Metric, precision: Precision) -> Result<(), &'static str> {
+    if values.iter().any(|value| !value.is_finite()) {
+        return Err("contains NaN or infinite values");
+    }
+    if precision == Precision::F16 && values.iter().any(|value| value.abs() > f16::MAX.to_f32()) {
+        return Err("contains values out of the F16 range");
+    }
+    if metric == Metric::Cosine && values.iter().all(|&value| value == 0.0) {
+        return Err("zero vector has no cosine similarity");
+    }
+    Ok(())
+}
+
+/// Scales `values` to unit length, `None` for the zero vector.
+pub fn normalized(mut values: Vec<f32>) -> Option<Vec<f32>> {
+    let norm = values.iter().map(|value| value * value).sum::<f32>().sqrt();
+    if norm == 0.0 {
+        return None;
+    }
+    for value in values.iter_mut() {
+        *value /= norm;
+    }
+    Some(values)
+}
+
+/// HNSW build and search parameters.
+#[derive(CandidType, Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct IndexParams {
+    pub ef_construction: usize,
+    pub ef_search: usize,
+}
+
+impl Default for IndexParams {
+    fn default() -> Self {
+        Self {
+            ef_construction: 100,
+            ef_search: 100,
+        }
+    }
+}
+
+/// Number format vectors are stored in. Vectors are converted when inserted
+/// and returned, and distances are always computed in f32.
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Debug)]
+pub enum Precision {
+    #[default]
+    F32,
+    F16,
+    Bf16,
+}
+
+impl Precision {
+    /// Size of a stored component.
+    pub fn component_bytes(&self) -> usize {
+        match self {
+            Precision::F32 => 4,
+            Precision::F16 | Precision::Bf16 => 2,
+        }
+    }
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(try_from = "StoredVector<DVector<f32>>")]
 pub struct Vector {
-    data: DVector<f32>,
+    data: Data,
+}
+
+#[derive(Clone)]
+enum Data {
+    F32(DVector<f32>),
+    /// Bit patterns of the half-precision components.
+    F16(Vec<u16>),
+    Bf16(Vec<u16>),
+}
+
+/// Serialized form of a vector: a map with a single key naming the format,
+/// f32 vectors keeping the `data` key they were always serialized under.
+#[derive(Serialize, Deserialize)]
+#[serde(bound(deserialize = "T: Deserialize<'de>"))]
+struct StoredVector<T> {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    data: Option<T>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    f16: Option<Vec<u16>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    bf16: Option<Vec<u16>>,
+}
+
+impl Serialize for Vector {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut stored = StoredVector {
+            data: None,
+            f16: None,
+            bf16: None,
+        };
+        match &self.data {
+            Data::F32(values) => stored.data = Some(values),
+            Data::F16(bits) => stored.f16 = Some(bits.clone()),
+            Data::Bf16(bits) => stored.bf16 = Some(bits.clone()),
+        }
+        stored.serialize(serializer)
+    }
+}
+
+impl TryFrom<StoredVector<DVector<f32>>> for Vector {
+    type Error = String;
+
+    fn try_from(stored: StoredVector<DVector<f32>>) -> Result<Self, String> {
+        let data = match stored {
+            StoredVector {
+                data: Some(values), ..
+            } => Data::F32(values),
+            StoredVector {
+                f16: Some(bits), ..
+            } => Data::F16(bits),
+            StoredVector {
+                bf16: Some(bits), ..
+            } => Data::Bf16(bits),
+            _ => return Err("vector without components".to_string()),
+        };
+        Ok(Vector { data })
+    }
+}
+
+/// Iterates over the components of a vector as f32s.
+enum Values<'a> {
+    F32(std::slice::Iter<'a, f32>),
+    F16(std::slice::Iter<'a, u16>),
+    Bf16(std::slice::Iter<'a, u16>),
 }
 
-impl Point for Vector {
-    fn distance(&self, other: &Self) -> f32 {
-        let diff = self.data.clone() - other.data.clone();
-        diff.dot(&diff).norm1()
+impl Iterator for Values<'_> {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        match self {
+            Values::F32(values) => values.next().copied(),
+            Values::F16(bits) => bits.next().map(|&bits| f16::from_bits(bits).to_f32()),
+            Values::Bf16(bits) => bits.next().map(|&bits| bf16::from_bits(bits).to_f32()),
+        }
     }
 }
 
 impl PartialEq for Vector {
     fn eq(&self, other: &Self) -> bool {
-        self.data == other.data
+        self.values().eq(other.values())
     }
     fn ne(&self, other: &Self) -> bool {
         !self.eq(other)
@@ -30,12 +202,153 @@ impl PartialEq for Vector {
 impl From<Vec<f32>> for Vector {
     fn from(value: Vec<f32>) -> Self {
         let svec = DVector::from_vec(value);
-        Vector { data: svec }
+        Vector {
+            data: Data::F32(svec),
+        }
     }
 }
 
 impl Vector {
+    /// Stores `values` in `precision`, rounding to the nearest representable value.
+    pub fn with_precision(values: Vec<f32>, precision: Precision) -> Self {
+        let data = match precision {
+            Precision::F32 => Data::F32(DVector::from_vec(values)),
+            Precision::F16 => Data::F16(
+                values
+                    .into_iter()
+                    .map(|value| f16::from_f32(value).to_bits())
+                    .collect(),
+            ),
+            Precision::Bf16 => Data::Bf16(
+                values
+                    .into_iter()
+                    .map(|value| bf16::from_f32(value).to_bits())
+                    .collect(),
+            ),
+        };
+        Vector { data }
+    }
+
+    /// Copy of the vector stored in `precision`.
+    pub fn converted(&self, precision: Precision) -> Self {
+        Self::with_precision(self.to_vec(), precision)
+    }
+
+    fn values(&self) -> Values<'_> {
+        match &self.data {
+            Data::F32(values) => Values::F32(values.as_slice().iter()),
+            Data::F16(bits) => Values::F16(bits.iter()),
+            Data::Bf16(bits) => Values::Bf16(bits.iter()),
+        }
+    }
+
+    pub fn to_vec(&self) -> Vec<f32> {
+        self.values().collect()
+    }
+
+    /// Squared Euclidean distance.
+    pub fn distance(&self, other: &Vector) -> f32 {
+        self.values()
+            .zip(other.values())
+            .map(|(a, b)| (a - b) * (a - b))
+            .sum()
+    }
+
+    pub fn dot(&self, other: &Vector) -> f32 {
+        match (&self.data, &other.data) {
+            (Data::F32(a), Data::F32(b)) => a.dot(b),
+            _ => self.values().zip(other.values()).map(|(a, b)| a * b).sum(),
+        }
+    }
+
+    pub fn norm(&self) -> f32 {
+        match &self.data {
+            Data::F32(values) => values.norm(),
+            _ => self.values().map(|a| a * a).sum::<f32>().sqrt(),
+        }
+    }
+
+    /// Cosine similarity, 0 if either vector is zero.
     pub fn cos_sim(&self, other: &Vector) -> f32 {
-        self.data.dot(&other.data) / (self.data.norm() * other.data.norm())
+        let norms = self.norm() * other.norm();
+        if norms == 0.0 {
+            return 0.0;
+        }
+        self.dot(other) / norms
+    }
+}
+
+/// A bag of token-level vectors, as produced by late-interaction models such
+/// as ColBERT.
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct MultiVector {
+    tokens: Vec<Vector>,
+}
+
+impl From<Vec<Vec<f32>>> for MultiVector {
+    fn from(tokens: Vec<Vec<f32>>) -> Self {
+        Self::with_precision(tokens, Precision::F32)
+    }
+}
+
+impl MultiVector {
+    pub fn with_precision(tokens: Vec<Vec<f32>>, precision: Precision) -> Self {
+        MultiVector {
+            tokens: tokens
+                .into_iter()
+                .map(|token| Vector::with_precision(token, precision))
+                .collect(),
+        }
+    }
+
+    pub fn converted(&self, precision: Precision) -> Self {
+        MultiVector {
+            tokens: self
+                .tokens
+                .iter()
+                .map(|token| token.converted(precision))
+                .collect(),
+        }
+    }
+
+    pub fn token_count(&self) -> usize {
+        self.tokens.len()
+    }
+
+    pub fn to_vecs(&self) -> Vec<Vec<f32>> {
+        self.tokens.iter().map(Vector::to_vec).collect()
+    }
+
+    /// Mean of the token vectors, `None` for an empty bag.
+    pub fn pooled(&self) -> Option<Vector> {
+        let (first, rest) = self.tokens.split_first()?;
+        let mut sum = first.to_vec();
+        for token in rest {
+            for (total, value) in sum.iter_mut().zip(token.values()) {
+                *total += value;
+            }
+        }
+        let count = self.tokens.len() as f32;
+        Some(Vector::from(
+            sum.into_iter()
+                .map(|total| total / count)
+                .collect::<Vec<_>>(),
+        ))
+    }
+
+    /// Late-interaction score: for each token of `query`, the largest inner
+    /// product with a token of `self`, summed over the query tokens.
+    pub fn max_sim(&self, query: &MultiVector) -> f32 {
+        query
+            .tokens
+            .iter()
+            .map(|q| {
+                self.tokens
+                    .iter()
+                    .map(|token| token.dot(q))
+                    .fold(f32::NEG_INFINITY, f32::max)
+            })
+            .filter(|score| score.is_finite())
+            .sum()
     }
 }
@@ -0,0 +1,46 @@
+use crate::database::http::{self, HttpRequest};
+use crate::database::users::{has_role, Role};
+use ic_cdk::api::call::{accept_message, arg_data, method_name};
+use ic_cdk::inspect_message;
+
+// The role an ingress caller needs for each method, generated by `build.rs`
+// from the authorization macro on the method itself. Methods without one are
+// rejected.
+include!(concat!(env!("OUT_DIR"), "/roles.rs"));
+
+/// Rejects ingress update calls from principals lacking the required role
+/// before the method runs, so unauthorized callers cannot burn cycles on
+/// executing it. Inter-canister calls and query calls do not go through this
+/// hook and are still checked by the macros.
+///
+/// `http_request_update` is called anonymously by the HTTP gateway, so it is
+/// only accepted for requests carrying the API key of an admin.
+#[inspect_message]
+fn inspect_message() {
+    let caller = ic_cdk::caller();
+    let method = method_name();
+    let allowed = if method == "http_request_update" {
+        let (req,) = arg_data::<(HttpRequest,)>();
+        http::accepts_update(&req)
+    } else {
+        required_role(&method).is_some_and(|role| has_role(&caller, role))
+    };
+    if allowed {
+        accept_message();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::required_role;
+    use crate::database::users::Role;
+
+    #[test]
+    fn roles_follow_the_authorization_macros() {
+        assert_eq!(required_role("create_collection"), Some(Role::Admin));
+        assert_eq!(required_role("set_alias"), Some(Role::Admin));
+        assert_eq!(required_role("add_admin"), Some(Role::Owner));
+        assert_eq!(required_role("get_audit_log"), Some(Role::Auditor));
+        assert_eq!(required_role("http_request_update"), None);
+    }
+}
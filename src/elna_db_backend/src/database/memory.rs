@@ -9,6 +9,30 @@ const UPGRADES: MemoryId = MemoryId::new(0);
 // every additional stable structure.
 const STABLE_BTREE: MemoryId = MemoryId::new(1);
 
+// The audit log is a StableLog, which needs one memory for its index and one for its data.
+const AUDIT_LOG_INDEX: MemoryId = MemoryId::new(2);
+const AUDIT_LOG_DATA: MemoryId = MemoryId::new(3);
+
+// A memory for the set of principals allowed to read the audit log.
+const AUDITORS: MemoryId = MemoryId::new(4);
+
+// A memory for the rate limits configured by the owner.
+const RATE_LIMITS: MemoryId = MemoryId::new(5);
+
+// A memory for the hashes of the API keys accepted by the HTTP interface.
+const API_KEYS: MemoryId = MemoryId::new(6);
+
+// A memory for the collection snapshots.
+const SNAPSHOTS: MemoryId = MemoryId::new(7);
+
+// A memory for the owner principal, so it survives upgrades without being passed again.
+const OWNER: MemoryId = MemoryId::new(8);
+
+// A memory for the neighbour lists of the HNSW graphs, and one for the counter
+// allocating their ids.
+const GRAPHS: MemoryId = MemoryId::new(9);
+const GRAPH_IDS: MemoryId = MemoryId::new(10);
+
 pub type Memory = VirtualMemory<DefaultMemoryImpl>;
 
 thread_local! {
@@ -25,3 +49,39 @@ pub fn get_upgrades_memory() -> Memory {
 pub fn get_stable_btree_memory() -> Memory {
     MEMORY_MANAGER.with(|m| m.borrow().get(STABLE_BTREE))
 }
+
+pub fn get_audit_log_index_memory() -> Memory {
+    MEMORY_MANAGER.with(|m| m.borrow().get(AUDIT_LOG_INDEX))
+}
+
+pub fn get_audit_log_data_memory() -> Memory {
+    MEMORY_MANAGER.with(|m| m.borrow().get(AUDIT_LOG_DATA))
+}
+
+pub fn get_auditors_memory() -> Memory {
+    MEMORY_MANAGER.with(|m| m.borrow().get(AUDITORS))
+}
+
+pub fn get_rate_limits_memory() -> Memory {
+    MEMORY_MANAGER.with(|m| m.borrow().get(RATE_LIMITS))
+}
+
+pub fn get_api_keys_memory() -> Memory {
+    MEMORY_MANAGER.with(|m| m.borrow().get(API_KEYS))
+}
+
+pub fn get_snapshots_memory() -> Memory {
+    MEMORY_MANAGER.with(|m| m.borrow().get(SNAPSHOTS))
+}
+
+pub fn get_owner_memory() -> Memory {
+    MEMORY_MANAGER.with(|m| m.borrow().get(OWNER))
+}
+
+pub fn get_graphs_memory() -> Memory {
+    MEMORY_MANAGER.with(|m| m.borrow().get(GRAPHS))
+}
+
+pub fn get_graph_ids_memory() -> Memory {
+    MEMORY_MANAGER.with(|m| m.borrow().get(GRAPH_IDS))
+}
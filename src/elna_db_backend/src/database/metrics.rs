@@ -0,0 +1,307 @@
+use crate::database::error::Error;
+use candid::{CandidType, Deserialize};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+const WASM_PAGE_SIZE: u64 = 65536;
+
+/// Endpoints whose calls are counted, including calls rejected before they
+/// reach the endpoint, e.g. by the authorization macros.
+const OBSERVED_ENDPOINTS: &[&str] = &[
+    "create_collection",
+    "create_collection_with_fields",
+    "create_index",
+    "insert",
+    "insert_with_fields",
+    "insert_batch",
+    "insert_sparse",
+    "insert_multi_vector",
+    "commit_upload",
+    "commit_import",
+    "set_precision",
+    "set_normalize",
+    "build_index",
+    "delete_collection",
+    "rename_collection",
+    "clone_collection",
+    "set_alias",
+    "delete_alias",
+    "create_snapshot",
+    "restore_snapshot",
+    "clone_snapshot",
+    "delete_snapshot",
+    "query",
+    "query_fields",
+    "query_hybrid",
+    "query_multi_vector",
+];
+
+#[derive(Default)]
+struct EndpointCounters {
+    calls: u64,
+    errors: u64,
+    instructions: u64,
+}
+
+/// Call, error and instruction counters. They live on the heap and start over
+/// after an upgrade. Query calls cannot persist state, so queries are only
+/// counted when served by an update call, as HTTP queries are.
+#[derive(Default)]
+pub struct Metrics {
+    endpoints: BTreeMap<&'static str, EndpointCounters>,
+    errors: BTreeMap<&'static str, u64>,
+}
+
+thread_local! {
+    static METRICS: RefCell<Metrics> = RefCell::new(Metrics::default());
+}
+
+impl Metrics {
+    pub fn observe<T>(
+        &mut self,
+        endpoint: &'static str,
+        instructions: u64,
+        result: &Result<T, Error>,
+    ) {
+        let counters = self.endpoints.entry(endpoint).or_default();
+        counters.calls += 1;
+        counters.instructions += instructions;
+        if let Err(error) = result {
+            counters.errors += 1;
+            *self.errors.entry(error.name()).or_default() += 1;
+        }
+    }
+}
+
+/// Records a finished call of `endpoint`, charging it the instructions executed
+/// so far in the current message.
+pub fn observe<T>(endpoint: &'static str, result: &Result<T, Error>) {
+    let instructions = instruction_counter();
+    METRICS.with(|metrics| metrics.borrow_mut().observe(endpoint, instructions, result))
+}
+
+/// Whether calls of `endpoint` are counted.
+pub fn is_observed(endpoint: &str) -> bool {
+    OBSERVED_ENDPOINTS.contains(&endpoint)
+}
+
+#[derive(CandidType, Deserialize, Clone, PartialEq, Debug)]
+pub struct EndpointMetrics {
+    pub endpoint: String,
+    pub calls: u64,
+    pub errors: u64,
+    pub instructions: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, PartialEq, Debug)]
+pub struct CanisterMetrics {
+    pub heap_memory_bytes: u64,
+    pub stable_memory_bytes: u64,
+    pub cycles: u128,
+    pub endpoints: Vec<EndpointMetrics>,
+    pub errors: Vec<(String, u64)>,
+}
+
+/// Instructions executed so far in the current message, or 0 outside of a canister.
+pub(crate) fn instruction_counter() -> u64 {
+    #[cfg(target_arch = "wasm32")]
+    {
+        ic_cdk::api::performance_counter(0)
+    }
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        0
+    }
+}
+
+pub fn heap_memory_bytes() -> u64 {
+    #[cfg(target_arch = "wasm32")]
+    {
+        core::arch::wasm32::memory_size(0) as u64 * WASM_PAGE_SIZE
+    }
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        0
+    }
+}
+
+pub fn collect() -> CanisterMetrics {
+    let (endpoints, errors) = METRICS.with(|metrics| {
+        let metrics = metrics.borrow();
+        let endpoints = metrics
+            .endpoints
+            .iter()
+            .map(|(endpoint, counters)| EndpointMetrics {
+                endpoint: endpoint.to_string(),
+                calls: counters.calls,
+                errors: counters.errors,
+                instructions: counters.instructions,
+            })
+            .collect();
+        let errors = metrics
+            .errors
+            .iter()
+            .map(|(name, count)| (name.to_string(), *count))
+            .collect();
+        (endpoints, errors)
+    });
+    CanisterMetrics {
+        heap_memory_bytes: heap_memory_bytes(),
+        stable_memory_bytes: ic_cdk::api::stable::stable64_size() * WASM_PAGE_SIZE,
+        cycles: ic_cdk::api::canister_balance128(),
+        endpoints,
+        errors,
+    }
+}
+
+type EndpointCounter = fn(&EndpointMetrics) -> u64;
+
+/// Renders the metrics in the Prometheus text exposition format.
+pub fn encode_prometheus(metrics: &CanisterMetrics) -> String {
+    let mut out = String::new();
+    let gauges = [
+        (
+            "elna_heap_memory_bytes",
+            "Size of the heap memory in bytes.",
+            metrics.heap_memory_bytes as u128,
+        ),
+        (
+            "elna_stable_memory_bytes",
+            "Size of the stable memory in bytes.",
+            metrics.stable_memory_bytes as u128,
+        ),
+        (
+            "elna_cycles_balance",
+            "Cycles balance of the canister.",
+            metrics.cycles,
+        ),
+    ];
+    for (name, help, value) in gauges {
+        let _ = writeln!(
+            out,
+            "# HELP {} {}\n# TYPE {} gauge\n{} {}",
+            name, help, name, name, value
+        );
+    }
+
+    let counters: [(&str, &str, EndpointCounter); 3] = [
+        (
+            "elna_calls_total",
+            "Number of update calls per endpoint.",
+            |m| m.calls,
+        ),
+        (
+            "elna_call_errors_total",
+            "Number of update calls per endpoint that returned an error.",
+            |m| m.errors,
+        ),
+        (
+            "elna_instructions_total",
+            "Instructions executed by update calls per endpoint.",
+            |m| m.instructions,
+        ),
+    ];
+    for (name, help, value) in counters {
+        let _ = writeln!(out, "# HELP {} {}\n# TYPE {} counter", name, help, name);
+        for endpoint in &metrics.endpoints {
+            let _ = writeln!(
+                out,
+                "{}{{endpoint=\"{}\"}} {}",
+                name,
+                endpoint.endpoint,
+                value(endpoint)
+            );
+        }
+    }
+
+    let name = "elna_errors_total";
+    let _ = writeln!(
+        out,
+        "# HELP {} Number of errors returned per error variant.\n# TYPE {} counter",
+        name, name
+    );
+    for (error, count) in &metrics.errors {
+        let _ = writeln!(out, "{}{{error=\"{}\"}} {}", name, error, count);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{encode_prometheus, CanisterMetrics, EndpointMetrics, Metrics, METRICS};
+    use crate::database::error::Error;
+    use crate::database::{operations, rate_limit, users};
+    use candid::Principal;
+
+    #[test]
+    fn observe_counts_calls_and_errors() {
+        let mut metrics = Metrics::default();
+        metrics.observe("insert", 10, &Ok::<(), Error>(()));
+        metrics.observe("insert", 5, &Err::<(), Error>(Error::NotFound));
+
+        let counters = &metrics.endpoints["insert"];
+        assert_eq!(
+            (counters.calls, counters.errors, counters.instructions),
+            (2, 1, 15)
+        );
+        assert_eq!(metrics.errors["NotFound"], 1);
+    }
+
+    #[test]
+    fn counts_calls_failing_before_reaching_the_endpoint() {
+        let caller = Principal::from_slice(&[1]);
+        users::reject("insert", &caller, "docs");
+        users::reject("add_admin", &caller, "aaaaa-aa");
+        let limit = rate_limit::RateLimit {
+            capacity: 0,
+            refill_per_minute: 0,
+        };
+        rate_limit::RATE_LIMITS.with(|limits| {
+            limits
+                .borrow_mut()
+                .insert(rate_limit::Endpoint::HttpQuery.id(), limit)
+        });
+        let result = operations::http_query(caller, "docs", vec![1.0], 1);
+        assert_eq!(result, Err(Error::RateLimited));
+
+        METRICS.with(|metrics| {
+            let metrics = metrics.borrow();
+            assert_eq!(
+                metrics.endpoints.keys().copied().collect::<Vec<_>>(),
+                ["insert", "query"]
+            );
+            assert!(metrics
+                .endpoints
+                .values()
+                .all(|counters| (counters.calls, counters.errors) == (1, 1)));
+            assert_eq!(metrics.errors["Unauthorized"], 1);
+            assert_eq!(metrics.errors["RateLimited"], 1);
+        });
+    }
+
+    #[test]
+    fn prometheus_format() {
+        let metrics = CanisterMetrics {
+            heap_memory_bytes: 65536,
+            stable_memory_bytes: 0,
+            cycles: 42,
+            endpoints: vec![EndpointMetrics {
+                endpoint: "insert".to_string(),
+                calls: 2,
+                errors: 1,
+                instructions: 15,
+            }],
+            errors: vec![("NotFound".to_string(), 1)],
+        };
+        let text = encode_prometheus(&metrics);
+
+        assert!(
+            text.contains("# TYPE elna_heap_memory_bytes gauge\nelna_heap_memory_bytes 65536\n")
+        );
+        assert!(text.contains("elna_cycles_balance 42\n"));
+        assert!(text.contains("elna_calls_total{endpoint=\"insert\"} 2\n"));
+        assert!(text.contains("elna_instructions_total{endpoint=\"insert\"} 15\n"));
+        assert!(text.contains("elna_errors_total{error=\"NotFound\"} 1\n"));
+    }
+}
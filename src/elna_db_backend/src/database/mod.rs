@@ -1,6 +1,26 @@
+pub mod api_key;
+pub mod audit;
+pub mod batch;
+pub mod clock;
 pub mod collection;
 pub mod db;
 pub mod error;
+pub mod export;
+pub mod field;
+pub mod graph;
+pub mod http;
+pub mod import;
 pub mod index;
+pub mod inspect;
 pub mod memory;
+pub mod metrics;
+pub mod namespace;
+pub mod operations;
+pub mod quota;
+pub mod rate_limit;
+pub mod session;
+pub mod snapshot;
+pub mod sparse;
+pub mod state;
+pub mod upload;
 pub mod users;
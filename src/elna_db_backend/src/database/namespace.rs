@@ -0,0 +1,218 @@
+use crate::database::audit::{self, Operation};
+use crate::database::clock;
+use crate::database::db::DB;
+use crate::database::error::Error;
+use crate::database::metrics;
+use crate::database::quota::{Meter, Quota, QuotaKind, Usage};
+use crate::database::users::is_canister_admin;
+use candid::{CandidType, Principal};
+use elna_auth_macros::check_is_owner;
+use ic_cdk::{query, update};
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Separates the namespace from the collection name in `Database.collections` keys.
+pub const SEPARATOR: char = '/';
+
+/// A tenant owning a set of collections. Collections of a namespace are stored
+/// under `<namespace>/<name>` and are only visible to the namespace's admins,
+/// the canister owner and the canister-wide admins.
+#[derive(Serialize, Deserialize, Default)]
+pub struct Namespace {
+    pub admins: BTreeSet<Principal>,
+    #[serde(default)]
+    pub quota: Quota,
+}
+
+#[derive(CandidType, Deserialize, Clone, PartialEq, Debug)]
+pub struct NamespaceInfo {
+    pub name: String,
+    pub admins: Vec<Principal>,
+    pub quota: Quota,
+    pub usage: Usage,
+    pub collections: Vec<String>,
+}
+
+/// Resolves the namespace `principal` works in: `None` for the owner and the
+/// canister-wide admins, who see every collection, or the namespace the
+/// principal administers.
+pub fn namespace_for(principal: &Principal) -> Result<Option<String>, Error> {
+    if is_canister_admin(principal) {
+        return Ok(None);
+    }
+    DB.with(|db| db.borrow().namespace_of(principal))
+        .map(Some)
+        .ok_or(Error::Unauthorized)
+}
+
+/// Qualifies a collection name supplied by `principal` with its namespace.
+pub fn scoped_name(principal: &Principal, name: &str) -> Result<String, Error> {
+    let namespace = namespace_for(principal)?;
+    qualify(namespace.as_deref(), name)
+}
+
+pub fn qualify(namespace: Option<&str>, name: &str) -> Result<String, Error> {
+    match namespace {
+        None => Ok(name.to_string()),
+        Some(_) if name.is_empty() || name.contains(SEPARATOR) => Err(Error::InvalidName),
+        Some(namespace) => Ok(format!("{}{}{}", namespace, SEPARATOR, name)),
+    }
+}
+
+thread_local! {
+    static METERS: RefCell<BTreeMap<String, Meter>> = RefCell::new(BTreeMap::default());
+}
+
+/// The namespace `principal` works in and its quota. `None` for canister-wide
+/// admins, who are not metered, and for principals without a namespace.
+fn metered_namespace(principal: &Principal) -> Option<(String, Quota)> {
+    let namespace = namespace_for(principal).ok().flatten()?;
+    DB.with(|db| {
+        let db = db.borrow();
+        let quota = db.namespaces.get(&namespace)?.quota.clone();
+        Some((namespace, quota))
+    })
+}
+
+/// Counts a search by `principal` against the query rate quota of its
+/// namespace. Query calls cannot persist the count, so a namespace with a
+/// query rate quota is refused them (`update_call` unset) and has to search
+/// through the HTTP gateway, which serves searches as update calls.
+pub fn charge_query(principal: &Principal, update_call: bool) -> Result<(), Error> {
+    let Some((namespace, quota)) = metered_namespace(principal) else {
+        return Ok(());
+    };
+    if quota.max_queries_per_minute.is_none() {
+        return Ok(());
+    }
+    if !update_call {
+        return Err(Error::QuotaExceeded(QuotaKind::Queries));
+    }
+    METERS.with(|meters| {
+        let mut meters = meters.borrow_mut();
+        let meter = meters.entry(namespace).or_default();
+        meter.charge_query(&quota, clock::now())
+    })
+}
+
+/// Runs the update call `f` for `principal` within the daily instruction
+/// quota of its namespace, charging it the instructions executed by the
+/// end of `f`.
+pub fn metered<T>(principal: &Principal, f: impl FnOnce() -> Result<T, Error>) -> Result<T, Error> {
+    let Some((namespace, quota)) = metered_namespace(principal) else {
+        return f();
+    };
+    METERS.with(|meters| {
+        let mut meters = meters.borrow_mut();
+        let meter = meters.entry(namespace.clone()).or_default();
+        meter.check_instructions(&quota, clock::now())
+    })?;
+    let result = f();
+    let instructions = metrics::instruction_counter();
+    METERS.with(|meters| {
+        let mut meters = meters.borrow_mut();
+        let meter = meters.entry(namespace).or_default();
+        meter.charge_instructions(instructions, clock::now())
+    });
+    result
+}
+
+/// The searches and instructions `namespace` used in the current windows.
+pub fn metered_usage(namespace: &str) -> (u64, u64) {
+    METERS.with(|meters| {
+        meters
+            .borrow()
+            .get(namespace)
+            .map_or((0, 0), |meter| meter.usage(clock::now()))
+    })
+}
+
+#[query]
+#[check_is_owner]
+fn get_namespaces() -> Result<Vec<NamespaceInfo>, Error> {
+    DB.with(|db| Ok(db.borrow().get_namespaces()))
+}
+
+#[update]
+#[check_is_owner]
+fn create_namespace(name: String, quota: Quota) -> Result<(), Error> {
+    let result = DB.with(|db| db.borrow_mut().create_namespace(&name, quota));
+    audit::record(Operation::CreateNamespace, &name, &result);
+    result
+}
+
+#[update]
+#[check_is_owner]
+fn set_namespace_quota(name: String, quota: Quota) -> Result<(), Error> {
+    let result = DB.with(|db| db.borrow_mut().set_namespace_quota(&name, quota));
+    audit::record(Operation::SetQuota, &name, &result);
+    result
+}
+
+#[update]
+#[check_is_owner]
+fn delete_namespace(name: String) -> Result<(), Error> {
+    let result = DB.with(|db| db.borrow_mut().delete_namespace(&name));
+    if result.is_ok() {
+        METERS.with(|meters| meters.borrow_mut().remove(&name));
+    }
+    audit::record(Operation::DeleteNamespace, &name, &result);
+    result
+}
+
+#[update]
+#[check_is_owner]
+fn add_namespace_admin(name: String, principal_id: Principal) -> Result<(), Error> {
+    let result = DB.with(|db| db.borrow_mut().add_namespace_admin(&name, principal_id));
+    let target = format!("{}{}{}", name, SEPARATOR, principal_id);
+    audit::record(Operation::AddNamespaceAdmin, &target, &result);
+    result
+}
+
+#[update]
+#[check_is_owner]
+fn remove_namespace_admin(name: String, principal_id: Principal) -> Result<(), Error> {
+    let result = DB.with(|db| db.borrow_mut().remove_namespace_admin(&name, principal_id));
+    let target = format!("{}{}{}", name, SEPARATOR, principal_id);
+    audit::record(Operation::RemoveNamespaceAdmin, &target, &result);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{charge_query, metered};
+    use crate::database::db::DB;
+    use crate::database::error::Error;
+    use crate::database::quota::{Quota, QuotaKind};
+    use candid::Principal;
+
+    #[test]
+    fn namespace_quotas_meter_searches_and_instructions() {
+        let admin = Principal::from_slice(&[1]);
+        DB.with(|db| {
+            let mut db = db.borrow_mut();
+            let quota = Quota {
+                max_queries_per_minute: Some(1),
+                max_instructions_per_day: Some(0),
+                ..Default::default()
+            };
+            db.create_namespace(&"acme".to_string(), quota).unwrap();
+            db.add_namespace_admin(&"acme".to_string(), admin).unwrap();
+        });
+
+        let queries_exceeded = Err(Error::QuotaExceeded(QuotaKind::Queries));
+        assert_eq!(charge_query(&admin, false), queries_exceeded);
+        assert_eq!(charge_query(&admin, true), Ok(()));
+        assert_eq!(charge_query(&admin, true), queries_exceeded);
+        assert_eq!(
+            metered(&admin, || Ok(())),
+            Err(Error::QuotaExceeded(QuotaKind::Instructions))
+        );
+
+        // Principals outside of a namespace are not metered.
+        let other = Principal::from_slice(&[2]);
+        assert_eq!(charge_query(&other, false), Ok(()));
+        assert_eq!(metered(&other, || Ok(())), Ok(()));
+    }
+}
@@ -0,0 +1,587 @@
+//! Collection operations shared by the Candid endpoints and the HTTP gateway.
+//! Authorization is checked by the callers; these functions scope collection
+//! names to the namespace of `caller`, resolve aliases where an existing
+//! collection is read or written, apply rate limits and record audit entries
+//! and metrics on its behalf, whichever way the call fails.
+use crate::database::audit::{self, Operation};
+use crate::database::batch::{InsertMode, InsertReport};
+use crate::database::collection::CollectionInfo;
+use crate::database::db::{Records, DB};
+use crate::database::error::Error;
+use crate::database::export::{ExportPage, IndexChunk};
+use crate::database::field::{FieldQuery, VectorFieldSpec, PRIMARY_FIELD};
+use crate::database::index::{Metric, Precision};
+use crate::database::metrics;
+use crate::database::namespace::{self, namespace_for, scoped_name};
+use crate::database::rate_limit::{self, Endpoint};
+use crate::database::snapshot::{self, SnapshotInfo};
+use crate::database::sparse::{HybridQuery, SparseVector};
+use candid::Principal;
+
+/// Scopes `name` to the namespace of `caller` and resolves it if it is an alias.
+pub fn resolved_name(caller: &Principal, name: &str) -> Result<String, Error> {
+    let name = scoped_name(caller, name)?;
+    Ok(DB.with(|db| db.borrow().resolve(&name)))
+}
+
+/// Runs a mutating call of `endpoint` within the instruction quota of the
+/// caller's namespace and records its outcome in the audit log and the
+/// metrics. `target` names what the call acts on; `f` may replace it
+/// once it has scoped the name, and it is recorded as it stands when `f` returns.
+pub(crate) fn audited<T>(
+    caller: Principal,
+    operation: Operation,
+    endpoint: &'static str,
+    mut target: String,
+    f: impl FnOnce(&mut String) -> Result<T, Error>,
+) -> Result<T, Error> {
+    let result = namespace::metered(&caller, || f(&mut target));
+    audit::record_as(caller, operation, &target, &result);
+    metrics::observe(endpoint, &result);
+    result
+}
+
+/// Runs a search served by `endpoint` and counts it in the metrics.
+fn observed<T>(endpoint: &'static str, f: impl FnOnce() -> Result<T, Error>) -> Result<T, Error> {
+    let result = f();
+    metrics::observe(endpoint, &result);
+    result
+}
+
+pub fn create_collection(caller: Principal, name: &str, dimension: usize) -> Result<(), Error> {
+    audited(
+        caller,
+        Operation::CreateCollection,
+        "create_collection",
+        name.to_string(),
+        |target| {
+            *target = scoped_name(&caller, name)?;
+            DB.with(|db| {
+                let mut db = db.borrow_mut();
+                db.create_collection(target, dimension)
+            })
+        },
+    )
+}
+
+pub fn create_collection_with_fields(
+    caller: Principal,
+    name: &str,
+    dimension: usize,
+    metric: Metric,
+    fields: Vec<VectorFieldSpec>,
+) -> Result<(), Error> {
+    audited(
+        caller,
+        Operation::CreateCollection,
+        "create_collection_with_fields",
+        name.to_string(),
+        |target| {
+            *target = scoped_name(&caller, name)?;
+            DB.with(|db| {
+                let mut db = db.borrow_mut();
+                db.create_collection_with_fields(target, dimension, metric, fields)
+            })
+        },
+    )
+}
+
+pub fn create_index(
+    caller: Principal,
+    name: &str,
+    dimension: usize,
+    docs: Vec<String>,
+    embeddings: Vec<Vec<f32>>,
+    file_name: String,
+) -> Result<(), Error> {
+    audited(
+        caller,
+        Operation::CreateIndex,
+        "create_index",
+        name.to_string(),
+        |target| {
+            rate_limit::acquire(caller, Endpoint::CreateIndex)?;
+            *target = scoped_name(&caller, name)?;
+            DB.with(|db| {
+                let mut db = db.borrow_mut();
+                db.create_collection(target, dimension)?;
+                db.insert_into_collection(target, embeddings, docs, file_name)?;
+                db.build_index(target)
+            })
+        },
+    )
+}
+
+pub fn insert(
+    caller: Principal,
+    name: &str,
+    keys: Vec<Vec<f32>>,
+    values: Vec<String>,
+    file_name: String,
+) -> Result<(), Error> {
+    audited(
+        caller,
+        Operation::Insert,
+        "insert",
+        name.to_string(),
+        |target| {
+            rate_limit::acquire(caller, Endpoint::Insert)?;
+            *target = resolved_name(&caller, name)?;
+            DB.with(|db| {
+                let mut db = db.borrow_mut();
+                db.insert_into_collection(target, keys, values, file_name)
+            })
+        },
+    )
+}
+
+pub fn insert_with_fields(
+    caller: Principal,
+    name: &str,
+    keys: Vec<Vec<f32>>,
+    fields: Vec<(String, Vec<Vec<f32>>)>,
+    values: Vec<String>,
+    file_name: String,
+) -> Result<(), Error> {
+    audited(
+        caller,
+        Operation::Insert,
+        "insert_with_fields",
+        name.to_string(),
+        |target| {
+            rate_limit::acquire(caller, Endpoint::Insert)?;
+            *target = resolved_name(&caller, name)?;
+            DB.with(|db| {
+                let mut db = db.borrow_mut();
+                db.insert_with_fields(target, keys, fields, values, file_name)
+            })
+        },
+    )
+}
+
+pub fn insert_batch(
+    caller: Principal,
+    name: &str,
+    records: Records,
+    file_name: String,
+    mode: InsertMode,
+) -> Result<InsertReport, Error> {
+    audited(
+        caller,
+        Operation::Insert,
+        "insert_batch",
+        name.to_string(),
+        |target| {
+            rate_limit::acquire(caller, Endpoint::Insert)?;
+            *target = resolved_name(&caller, name)?;
+            DB.with(|db| {
+                let mut db = db.borrow_mut();
+                db.insert_batch(target, records, file_name, mode)
+            })
+        },
+    )
+}
+
+pub fn insert_sparse(
+    caller: Principal,
+    name: &str,
+    keys: Vec<Vec<f32>>,
+    fields: Vec<(String, Vec<Vec<f32>>)>,
+    sparse: Vec<SparseVector>,
+    values: Vec<String>,
+    file_name: String,
+) -> Result<(), Error> {
+    audited(
+        caller,
+        Operation::Insert,
+        "insert_sparse",
+        name.to_string(),
+        |target| {
+            rate_limit::acquire(caller, Endpoint::Insert)?;
+            *target = resolved_name(&caller, name)?;
+            DB.with(|db| {
+                let mut db = db.borrow_mut();
+                let records = Records {
+                    keys,
+                    fields,
+                    sparse: Some(sparse),
+                    values,
+                    ..Records::default()
+                };
+                db.insert_records(target, records, file_name)
+            })
+        },
+    )
+}
+
+pub fn insert_multi_vector(
+    caller: Principal,
+    name: &str,
+    tokens: Vec<Vec<Vec<f32>>>,
+    values: Vec<String>,
+    file_name: String,
+) -> Result<(), Error> {
+    audited(
+        caller,
+        Operation::Insert,
+        "insert_multi_vector",
+        name.to_string(),
+        |target| {
+            rate_limit::acquire(caller, Endpoint::Insert)?;
+            *target = resolved_name(&caller, name)?;
+            DB.with(|db| {
+                let mut db = db.borrow_mut();
+                db.insert_multi_vector(target, tokens, values, file_name)
+            })
+        },
+    )
+}
+
+pub fn set_precision(caller: Principal, name: &str, precision: Precision) -> Result<(), Error> {
+    audited(
+        caller,
+        Operation::SetPrecision,
+        "set_precision",
+        name.to_string(),
+        |target| {
+            *target = resolved_name(&caller, name)?;
+            DB.with(|db| {
+                let mut db = db.borrow_mut();
+                db.set_precision(target, precision)
+            })
+        },
+    )
+}
+
+pub fn set_normalize(caller: Principal, name: &str, normalize: bool) -> Result<(), Error> {
+    audited(
+        caller,
+        Operation::SetNormalize,
+        "set_normalize",
+        name.to_string(),
+        |target| {
+            *target = resolved_name(&caller, name)?;
+            DB.with(|db| {
+                let mut db = db.borrow_mut();
+                db.set_normalize(target, normalize)
+            })
+        },
+    )
+}
+
+pub fn build_index(caller: Principal, name: &str) -> Result<(), Error> {
+    audited(
+        caller,
+        Operation::BuildIndex,
+        "build_index",
+        name.to_string(),
+        |target| {
+            *target = resolved_name(&caller, name)?;
+            DB.with(|db| {
+                let mut db = db.borrow_mut();
+                db.build_index(target)
+            })
+        },
+    )
+}
+
+pub fn delete_collection(caller: Principal, name: &str) -> Result<(), Error> {
+    audited(
+        caller,
+        Operation::DeleteCollection,
+        "delete_collection",
+        name.to_string(),
+        |target| {
+            *target = scoped_name(&caller, name)?;
+            DB.with(|db| {
+                let mut db = db.borrow_mut();
+                db.delete_collection(target)
+            })
+        },
+    )
+}
+
+pub fn rename_collection(caller: Principal, name: &str, new_name: &str) -> Result<(), Error> {
+    audited(
+        caller,
+        Operation::RenameCollection,
+        "rename_collection",
+        format!("{} -> {}", name, new_name),
+        |target| {
+            let name = scoped_name(&caller, name)?;
+            let new_name = scoped_name(&caller, new_name)?;
+            *target = format!("{} -> {}", name, new_name);
+            DB.with(|db| {
+                let mut db = db.borrow_mut();
+                db.rename_collection(&name, &new_name)
+            })
+        },
+    )
+}
+
+pub fn clone_collection(
+    caller: Principal,
+    name: &str,
+    new_name: &str,
+    with_index: bool,
+) -> Result<(), Error> {
+    audited(
+        caller,
+        Operation::CloneCollection,
+        "clone_collection",
+        format!("{} -> {}", name, new_name),
+        |target| {
+            let name = scoped_name(&caller, name)?;
+            let new_name = scoped_name(&caller, new_name)?;
+            *target = format!("{} -> {}", name, new_name);
+            DB.with(|db| {
+                let mut db = db.borrow_mut();
+                db.clone_collection(&name, &new_name, with_index)
+            })
+        },
+    )
+}
+
+/// Returns up to `limit` `(score, document)` pairs nearest to `q`.
+pub fn query(
+    caller: Principal,
+    name: &str,
+    q: Vec<f32>,
+    limit: i32,
+) -> Result<Vec<(f32, String)>, Error> {
+    observed("query", || {
+        let name = resolved_name(&caller, name)?;
+        let query = FieldQuery {
+            field: PRIMARY_FIELD.to_string(),
+            vector: q,
+            weight: 1.0,
+        };
+        DB.with(|db| {
+            let db = db.borrow();
+            db.query_fields(&name, vec![query], limit)
+        })
+    })
+}
+
+/// Like `query`, for the HTTP gateway, which serves searches as update calls
+/// so that they take a token from the caller's `HttpQuery` bucket and count
+/// against the query rate and instruction quotas of its namespace.
+pub fn http_query(
+    caller: Principal,
+    name: &str,
+    q: Vec<f32>,
+    limit: i32,
+) -> Result<Vec<(f32, String)>, Error> {
+    namespace::metered(&caller, || {
+        if let Err(error) = rate_limit::acquire(caller, Endpoint::HttpQuery)
+            .and_then(|()| namespace::charge_query(&caller, true))
+        {
+            return observed("query", || Err(error));
+        }
+        query(caller, name, q, limit)
+    })
+}
+
+/// Returns up to `limit` `(score, document)` pairs for a query over one or
+/// more vector fields.
+pub fn query_fields(
+    caller: Principal,
+    name: &str,
+    queries: Vec<FieldQuery>,
+    limit: i32,
+) -> Result<Vec<(f32, String)>, Error> {
+    observed("query_fields", || {
+        let name = resolved_name(&caller, name)?;
+        DB.with(|db| {
+            let db = db.borrow();
+            db.query_fields(&name, queries, limit)
+        })
+    })
+}
+
+/// Returns up to `limit` `(score, document)` pairs for a query over dense
+/// vector fields and a sparse vector.
+pub fn query_hybrid(
+    caller: Principal,
+    name: &str,
+    query: HybridQuery,
+    limit: i32,
+) -> Result<Vec<(f32, String)>, Error> {
+    observed("query_hybrid", || {
+        let name = resolved_name(&caller, name)?;
+        DB.with(|db| {
+            let db = db.borrow();
+            db.query_hybrid(&name, query, limit)
+        })
+    })
+}
+
+/// Returns up to `limit` `(score, document)` pairs for a bag of query token
+/// vectors, scored by MaxSim.
+pub fn query_multi_vector(
+    caller: Principal,
+    name: &str,
+    tokens: Vec<Vec<f32>>,
+    limit: i32,
+) -> Result<Vec<(f32, String)>, Error> {
+    observed("query_multi_vector", || {
+        let name = resolved_name(&caller, name)?;
+        DB.with(|db| {
+            let db = db.borrow();
+            db.query_multi_vector(&name, tokens, limit)
+        })
+    })
+}
+
+pub fn get_collections(caller: Principal) -> Result<Vec<String>, Error> {
+    let namespace = namespace_for(&caller)?;
+    DB.with(|db| {
+        let db = db.borrow();
+        Ok(db.get_collections_in(namespace.as_deref()))
+    })
+}
+
+pub fn collection_info(caller: Principal, name: &str) -> Result<CollectionInfo, Error> {
+    let name = resolved_name(&caller, name)?;
+    DB.with(|db| {
+        let db = db.borrow();
+        db.collection_info(&name)
+    })
+}
+
+pub fn export_collection(
+    caller: Principal,
+    name: &str,
+    offset: u64,
+    limit: u64,
+) -> Result<ExportPage, Error> {
+    let name = resolved_name(&caller, name)?;
+    DB.with(|db| {
+        let db = db.borrow();
+        db.export_collection(&name, offset, limit)
+    })
+}
+
+pub fn export_index(
+    caller: Principal,
+    name: &str,
+    offset: u64,
+    length: u64,
+) -> Result<IndexChunk, Error> {
+    let name = resolved_name(&caller, name)?;
+    DB.with(|db| {
+        let db = db.borrow();
+        db.export_index(&name, offset, length)
+    })
+}
+
+pub fn set_alias(caller: Principal, alias: &str, collection: &str) -> Result<(), Error> {
+    audited(
+        caller,
+        Operation::SetAlias,
+        "set_alias",
+        format!("{} -> {}", alias, collection),
+        |target| {
+            let alias = scoped_name(&caller, alias)?;
+            let collection = scoped_name(&caller, collection)?;
+            *target = format!("{} -> {}", alias, collection);
+            DB.with(|db| {
+                let mut db = db.borrow_mut();
+                db.set_alias(&alias, &collection)
+            })
+        },
+    )
+}
+
+pub fn delete_alias(caller: Principal, alias: &str) -> Result<(), Error> {
+    audited(
+        caller,
+        Operation::DeleteAlias,
+        "delete_alias",
+        alias.to_string(),
+        |target| {
+            *target = scoped_name(&caller, alias)?;
+            DB.with(|db| {
+                let mut db = db.borrow_mut();
+                db.delete_alias(target)
+            })
+        },
+    )
+}
+
+pub fn get_aliases(caller: Principal) -> Result<Vec<(String, String)>, Error> {
+    let namespace = namespace_for(&caller)?;
+    DB.with(|db| {
+        let db = db.borrow();
+        Ok(db.get_aliases_in(namespace.as_deref()))
+    })
+}
+
+pub fn create_snapshot(caller: Principal, name: &str, label: &str) -> Result<(), Error> {
+    audited(
+        caller,
+        Operation::CreateSnapshot,
+        "create_snapshot",
+        format!("{}@{}", name, label),
+        |target| {
+            rate_limit::acquire(caller, Endpoint::Snapshot)?;
+            let name = scoped_name(&caller, name)?;
+            *target = format!("{}@{}", name, label);
+            snapshot::create(&name, label)
+        },
+    )
+}
+
+pub fn list_snapshots(caller: Principal, name: &str) -> Result<Vec<SnapshotInfo>, Error> {
+    let name = scoped_name(&caller, name)?;
+    Ok(snapshot::list(&name))
+}
+
+pub fn restore_snapshot(caller: Principal, name: &str, label: &str) -> Result<(), Error> {
+    audited(
+        caller,
+        Operation::RestoreSnapshot,
+        "restore_snapshot",
+        format!("{}@{}", name, label),
+        |target| {
+            rate_limit::acquire(caller, Endpoint::Snapshot)?;
+            let name = scoped_name(&caller, name)?;
+            *target = format!("{}@{}", name, label);
+            snapshot::restore(&name, label)
+        },
+    )
+}
+
+pub fn clone_snapshot(
+    caller: Principal,
+    name: &str,
+    label: &str,
+    new_name: &str,
+) -> Result<(), Error> {
+    audited(
+        caller,
+        Operation::CloneSnapshot,
+        "clone_snapshot",
+        new_name.to_string(),
+        |target| {
+            rate_limit::acquire(caller, Endpoint::Snapshot)?;
+            let name = scoped_name(&caller, name)?;
+            *target = scoped_name(&caller, new_name)?;
+            snapshot::clone_into(&name, label, target)
+        },
+    )
+}
+
+pub fn delete_snapshot(caller: Principal, name: &str, label: &str) -> Result<(), Error> {
+    audited(
+        caller,
+        Operation::DeleteSnapshot,
+        "delete_snapshot",
+        format!("{}@{}", name, label),
+        |target| {
+            let name = scoped_name(&caller, name)?;
+            *target = format!("{}@{}", name, label);
+            snapshot::delete(&name, label)
+        },
+    )
+}
@@ -0,0 +1,183 @@
+use crate::database::error::Error;
+use candid::CandidType;
+use serde::{Deserialize, Serialize};
+
+const MINUTE_NANOS: u64 = 60_000_000_000;
+const DAY_NANOS: u64 = 24 * 60 * MINUTE_NANOS;
+
+/// Limits on what a collection or a namespace may hold and use. `None` means
+/// unlimited. `max_collections`, `max_snapshot_bytes`,
+/// `max_queries_per_minute` and `max_instructions_per_day` only apply to
+/// namespaces, whose admins share them.
+#[derive(CandidType, Serialize, Deserialize, Clone, Default, PartialEq, Debug)]
+pub struct Quota {
+    pub max_collections: Option<u64>,
+    pub max_vectors: Option<u64>,
+    pub max_document_bytes: Option<u64>,
+    /// Total size of the snapshots of the namespace's collections.
+    #[serde(default)]
+    pub max_snapshot_bytes: Option<u64>,
+    /// Searches per calendar minute. Query calls cannot count themselves, so a
+    /// namespace with this quota has to search through the HTTP gateway.
+    #[serde(default)]
+    pub max_queries_per_minute: Option<u64>,
+    /// Instructions executed per UTC day by the namespace's audited calls and
+    /// HTTP searches, which bounds the cycles they burn.
+    #[serde(default)]
+    pub max_instructions_per_day: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub enum QuotaKind {
+    Collections,
+    Vectors,
+    DocumentBytes,
+    SnapshotBytes,
+    Queries,
+    Instructions,
+}
+
+#[derive(CandidType, Deserialize, Clone, Default, PartialEq, Debug)]
+pub struct Usage {
+    pub collections: u64,
+    pub vectors: u64,
+    pub document_bytes: u64,
+    pub snapshot_bytes: u64,
+    /// Searches in the current minute.
+    pub queries: u64,
+    /// Instructions executed in the current UTC day.
+    pub instructions: u64,
+}
+
+impl Quota {
+    pub fn check_collections(&self, collections: u64) -> Result<(), Error> {
+        check(self.max_collections, collections, QuotaKind::Collections)
+    }
+
+    pub fn check_data(&self, vectors: u64, document_bytes: u64) -> Result<(), Error> {
+        check(self.max_vectors, vectors, QuotaKind::Vectors)?;
+        check(
+            self.max_document_bytes,
+            document_bytes,
+            QuotaKind::DocumentBytes,
+        )
+    }
+
+    pub fn check_snapshot_bytes(&self, snapshot_bytes: u64) -> Result<(), Error> {
+        check(
+            self.max_snapshot_bytes,
+            snapshot_bytes,
+            QuotaKind::SnapshotBytes,
+        )
+    }
+}
+
+/// Searches and instructions a namespace used in the current minute and day.
+/// Meters live on the heap, like the rate limiter's buckets, and start over
+/// after an upgrade.
+#[derive(Default, Clone, PartialEq, Debug)]
+pub struct Meter {
+    minute: u64,
+    queries: u64,
+    day: u64,
+    instructions: u64,
+}
+
+impl Meter {
+    /// Starts new windows once the minute or the day is over. `now` is in nanoseconds.
+    fn roll(&mut self, now: u64) {
+        if now / MINUTE_NANOS != self.minute {
+            self.minute = now / MINUTE_NANOS;
+            self.queries = 0;
+        }
+        if now / DAY_NANOS != self.day {
+            self.day = now / DAY_NANOS;
+            self.instructions = 0;
+        }
+    }
+
+    /// Counts a search, failing if the minute's searches are used up.
+    pub fn charge_query(&mut self, quota: &Quota, now: u64) -> Result<(), Error> {
+        self.roll(now);
+        check(
+            quota.max_queries_per_minute,
+            self.queries + 1,
+            QuotaKind::Queries,
+        )?;
+        self.queries += 1;
+        Ok(())
+    }
+
+    /// Fails if the day's instructions are used up. A call that starts within
+    /// the budget runs to its end, so the budget can be overshot by one call.
+    pub fn check_instructions(&mut self, quota: &Quota, now: u64) -> Result<(), Error> {
+        self.roll(now);
+        match quota.max_instructions_per_day {
+            Some(limit) if self.instructions >= limit => {
+                Err(Error::QuotaExceeded(QuotaKind::Instructions))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub fn charge_instructions(&mut self, instructions: u64, now: u64) {
+        self.roll(now);
+        self.instructions += instructions;
+    }
+
+    /// The searches and instructions used in the windows current at `now`.
+    pub fn usage(&self, now: u64) -> (u64, u64) {
+        let mut meter = self.clone();
+        meter.roll(now);
+        (meter.queries, meter.instructions)
+    }
+}
+
+fn check(limit: Option<u64>, value: u64, kind: QuotaKind) -> Result<(), Error> {
+    match limit {
+        Some(limit) if value > limit => Err(Error::QuotaExceeded(kind)),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Meter, Quota, QuotaKind, DAY_NANOS, MINUTE_NANOS};
+    use crate::database::error::Error;
+
+    #[test]
+    fn queries_are_counted_per_minute() {
+        let quota = Quota {
+            max_queries_per_minute: Some(2),
+            ..Default::default()
+        };
+        let mut meter = Meter::default();
+        let now = 10 * MINUTE_NANOS;
+        assert_eq!(meter.charge_query(&quota, now), Ok(()));
+        assert_eq!(meter.charge_query(&quota, now + 1), Ok(()));
+        assert_eq!(
+            meter.charge_query(&quota, now + 2),
+            Err(Error::QuotaExceeded(QuotaKind::Queries))
+        );
+        assert_eq!(meter.usage(now), (2, 0));
+        assert_eq!(meter.charge_query(&quota, now + MINUTE_NANOS), Ok(()));
+    }
+
+    #[test]
+    fn instructions_are_counted_per_day() {
+        let quota = Quota {
+            max_instructions_per_day: Some(100),
+            ..Default::default()
+        };
+        let mut meter = Meter::default();
+        let now = 3 * DAY_NANOS;
+        assert_eq!(meter.check_instructions(&quota, now), Ok(()));
+        meter.charge_instructions(150, now);
+        assert_eq!(
+            meter.check_instructions(&quota, now + MINUTE_NANOS),
+            Err(Error::QuotaExceeded(QuotaKind::Instructions))
+        );
+        assert_eq!(meter.usage(now), (0, 150));
+        assert_eq!(meter.check_instructions(&quota, now + DAY_NANOS), Ok(()));
+    }
+}
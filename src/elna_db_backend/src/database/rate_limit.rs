@@ -0,0 +1,277 @@
+use crate::database::audit::{self, Operation};
+use crate::database::clock;
+use crate::database::error::Error;
+use crate::database::memory::{get_rate_limits_memory, Memory};
+use candid::{CandidType, Decode, Deserialize, Encode, Principal};
+use elna_auth_macros::check_is_owner;
+use ic_cdk::{query, update};
+use ic_stable_structures::{storable::Bound, StableBTreeMap, Storable};
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+/// Buckets are pruned once this many callers are tracked; full buckets carry no state.
+const MAX_TRACKED_BUCKETS: usize = 10_000;
+
+/// Endpoints that can be rate limited.
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Endpoint {
+    Insert,
+    CreateIndex,
+    /// Searches through the HTTP gateway, which serves them as update calls.
+    /// Candid query calls cannot persist the tokens they would take, so they
+    /// are never rate limited.
+    HttpQuery,
+    /// Creating, restoring and cloning snapshots, which copy whole collections.
+    Snapshot,
+}
+
+impl Endpoint {
+    const ALL: [Endpoint; 4] = [
+        Endpoint::Insert,
+        Endpoint::CreateIndex,
+        Endpoint::HttpQuery,
+        Endpoint::Snapshot,
+    ];
+
+    pub(crate) fn id(self) -> u8 {
+        match self {
+            Endpoint::Insert => 0,
+            Endpoint::CreateIndex => 1,
+            Endpoint::HttpQuery => 2,
+            Endpoint::Snapshot => 3,
+        }
+    }
+}
+
+/// A token bucket holding up to `capacity` calls, refilled continuously at
+/// `refill_per_minute` calls per minute.
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub struct RateLimit {
+    pub capacity: u32,
+    pub refill_per_minute: u32,
+}
+
+impl Storable for RateLimit {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(&bytes, Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(CandidType, Deserialize, Clone, Default, PartialEq, Debug)]
+pub struct RateLimitStats {
+    pub allowed: u64,
+    pub rejected: u64,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+struct Bucket {
+    tokens: f64,
+    updated_at: u64,
+}
+
+impl Bucket {
+    fn refill(&mut self, limit: &RateLimit, now: u64) {
+        let elapsed_minutes = now.saturating_sub(self.updated_at) as f64 / 60e9;
+        let tokens = self.tokens + elapsed_minutes * limit.refill_per_minute as f64;
+        self.tokens = tokens.min(limit.capacity as f64);
+        self.updated_at = now;
+    }
+
+    fn is_full(&self, limit: &RateLimit) -> bool {
+        self.tokens >= limit.capacity as f64
+    }
+}
+
+/// Per-caller token buckets. Buckets and counters live on the heap only and
+/// start over after an upgrade.
+#[derive(Default)]
+pub struct RateLimiter {
+    buckets: HashMap<(Principal, Endpoint), Bucket>,
+    stats: HashMap<Endpoint, RateLimitStats>,
+}
+
+impl RateLimiter {
+    /// Takes a token from the caller's bucket for `endpoint`, failing with
+    /// `Error::RateLimited` if the bucket is empty. `now` is in nanoseconds.
+    pub fn acquire(
+        &mut self,
+        caller: Principal,
+        endpoint: Endpoint,
+        limit: Option<RateLimit>,
+        now: u64,
+    ) -> Result<(), Error> {
+        let stats = self.stats.entry(endpoint).or_default();
+        let limit = match limit {
+            Some(limit) => limit,
+            None => {
+                stats.allowed += 1;
+                return Ok(());
+            }
+        };
+
+        let bucket = self.buckets.entry((caller, endpoint)).or_insert(Bucket {
+            tokens: limit.capacity as f64,
+            updated_at: now,
+        });
+        bucket.refill(&limit, now);
+        let result = if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            stats.allowed += 1;
+            Ok(())
+        } else {
+            stats.rejected += 1;
+            Err(Error::RateLimited)
+        };
+
+        if self.buckets.len() > MAX_TRACKED_BUCKETS {
+            self.buckets.retain(|&(_, bucket_endpoint), bucket| {
+                if bucket_endpoint != endpoint {
+                    return true;
+                }
+                bucket.refill(&limit, now);
+                !bucket.is_full(&limit)
+            });
+        }
+        result
+    }
+
+    pub fn stats(&self, endpoint: Endpoint) -> RateLimitStats {
+        self.stats.get(&endpoint).cloned().unwrap_or_default()
+    }
+}
+
+thread_local! {
+    pub static RATE_LIMITS: RefCell<StableBTreeMap<u8, RateLimit, Memory>> = RefCell::new(init_stable_data());
+    pub static RATE_LIMITER: RefCell<RateLimiter> = RefCell::new(RateLimiter::default());
+}
+
+fn init_stable_data() -> StableBTreeMap<u8, RateLimit, Memory> {
+    StableBTreeMap::init(get_rate_limits_memory())
+}
+
+/// Charges `caller` one call to `endpoint` against the configured limit.
+pub fn acquire(caller: Principal, endpoint: Endpoint) -> Result<(), Error> {
+    let limit = RATE_LIMITS.with(|limits| limits.borrow().get(&endpoint.id()));
+    RATE_LIMITER.with(|limiter| {
+        limiter
+            .borrow_mut()
+            .acquire(caller, endpoint, limit, clock::now())
+    })
+}
+
+#[update]
+#[check_is_owner]
+fn set_rate_limit(endpoint: Endpoint, limit: Option<RateLimit>) -> Result<(), Error> {
+    RATE_LIMITS.with(|limits| {
+        let mut limits = limits.borrow_mut();
+        match limit {
+            Some(limit) => limits.insert(endpoint.id(), limit),
+            None => limits.remove(&endpoint.id()),
+        };
+    });
+    let result = Ok(());
+    audit::record(Operation::SetRateLimit, &format!("{:?}", endpoint), &result);
+    result
+}
+
+#[derive(CandidType, Deserialize, Clone, PartialEq, Debug)]
+pub struct EndpointRateLimit {
+    pub endpoint: Endpoint,
+    pub limit: Option<RateLimit>,
+    pub stats: RateLimitStats,
+}
+
+#[query]
+#[check_is_owner]
+fn get_rate_limits() -> Result<Vec<EndpointRateLimit>, Error> {
+    let limits = RATE_LIMITS.with(|limits| {
+        let limits = limits.borrow();
+        Endpoint::ALL
+            .iter()
+            .map(|endpoint| (*endpoint, limits.get(&endpoint.id())))
+            .collect::<Vec<_>>()
+    });
+    RATE_LIMITER.with(|limiter| {
+        let limiter = limiter.borrow();
+        Ok(limits
+            .into_iter()
+            .map(|(endpoint, limit)| EndpointRateLimit {
+                endpoint,
+                limit,
+                stats: limiter.stats(endpoint),
+            })
+            .collect())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Endpoint, RateLimit, RateLimiter};
+    use crate::database::error::Error;
+    use candid::Principal;
+
+    const SECOND: u64 = 1_000_000_000;
+
+    #[test]
+    fn unlimited_endpoint() {
+        let mut limiter = RateLimiter::default();
+        for _ in 0..100 {
+            let result = limiter.acquire(Principal::anonymous(), Endpoint::Insert, None, 0);
+            assert_eq!(result, Ok(()));
+        }
+        assert_eq!(limiter.stats(Endpoint::Insert).allowed, 100);
+    }
+
+    #[test]
+    fn bucket_empties_and_refills() {
+        let mut limiter = RateLimiter::default();
+        let limit = Some(RateLimit {
+            capacity: 2,
+            refill_per_minute: 60,
+        });
+        let caller = Principal::anonymous();
+
+        assert_eq!(limiter.acquire(caller, Endpoint::Insert, limit, 0), Ok(()));
+        assert_eq!(limiter.acquire(caller, Endpoint::Insert, limit, 0), Ok(()));
+        assert_eq!(
+            limiter.acquire(caller, Endpoint::Insert, limit, 0),
+            Err(Error::RateLimited)
+        );
+        assert_eq!(
+            limiter.acquire(caller, Endpoint::Insert, limit, 2 * SECOND),
+            Ok(())
+        );
+
+        let stats = limiter.stats(Endpoint::Insert);
+        assert_eq!((stats.allowed, stats.rejected), (3, 1));
+    }
+
+    #[test]
+    fn buckets_are_per_caller_and_endpoint() {
+        let mut limiter = RateLimiter::default();
+        let limit = Some(RateLimit {
+            capacity: 1,
+            refill_per_minute: 1,
+        });
+        let caller = Principal::anonymous();
+        let other = Principal::management_canister();
+
+        assert_eq!(limiter.acquire(caller, Endpoint::Insert, limit, 0), Ok(()));
+        assert_eq!(limiter.acquire(other, Endpoint::Insert, limit, 0), Ok(()));
+        assert_eq!(
+            limiter.acquire(caller, Endpoint::CreateIndex, limit, 0),
+            Ok(())
+        );
+        assert_eq!(
+            limiter.acquire(caller, Endpoint::Insert, limit, 0),
+            Err(Error::RateLimited)
+        );
+    }
+}
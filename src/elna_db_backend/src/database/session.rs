@@ -0,0 +1,80 @@
+use crate::database::error::Error;
+use candid::Principal;
+use std::collections::HashMap;
+
+/// Upper bound on the number of sessions of one kind open at the same time.
+const MAX_OPEN_SESSIONS: usize = 16;
+
+/// Multi-call uploads in progress, each usable only by the principal that
+/// opened it. Sessions live on the heap and are lost on upgrade.
+pub struct Sessions<T> {
+    next_id: u64,
+    sessions: HashMap<u64, (Principal, T)>,
+}
+
+impl<T> Default for Sessions<T> {
+    fn default() -> Self {
+        Self {
+            next_id: 0,
+            sessions: HashMap::new(),
+        }
+    }
+}
+
+impl<T> Sessions<T> {
+    pub fn open(&mut self, owner: Principal, session: T) -> Result<u64, Error> {
+        if self.sessions.len() >= MAX_OPEN_SESSIONS {
+            return Err(Error::TooManySessions);
+        }
+        let id = self.next_id;
+        self.next_id += 1;
+        self.sessions.insert(id, (owner, session));
+        Ok(id)
+    }
+
+    /// Returns the session `id` if it was opened by `caller`.
+    pub fn get_mut(&mut self, id: u64, caller: &Principal) -> Result<&mut T, Error> {
+        match self.sessions.get_mut(&id) {
+            Some((owner, session)) if owner == caller => Ok(session),
+            _ => Err(Error::NotFound),
+        }
+    }
+
+    /// Removes and returns the session `id` if it was opened by `caller`.
+    pub fn close(&mut self, id: u64, caller: &Principal) -> Result<T, Error> {
+        self.get_mut(id, caller)?;
+        Ok(self.sessions.remove(&id).unwrap().1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Sessions, MAX_OPEN_SESSIONS};
+    use crate::database::error::Error;
+    use candid::Principal;
+
+    #[test]
+    fn sessions_belong_to_their_owner() {
+        let mut sessions = Sessions::default();
+        let owner = Principal::anonymous();
+        let other = Principal::management_canister();
+        let id = sessions.open(owner, "data").unwrap();
+
+        assert_eq!(sessions.get_mut(id, &other), Err(Error::NotFound));
+        assert_eq!(sessions.close(id, &other), Err(Error::NotFound));
+        assert_eq!(sessions.close(id, &owner), Ok("data"));
+        assert_eq!(sessions.close(id, &owner), Err(Error::NotFound));
+    }
+
+    #[test]
+    fn open_sessions_are_bounded() {
+        let mut sessions = Sessions::default();
+        for _ in 0..MAX_OPEN_SESSIONS {
+            assert!(sessions.open(Principal::anonymous(), ()).is_ok());
+        }
+        assert_eq!(
+            sessions.open(Principal::anonymous(), ()),
+            Err(Error::TooManySessions)
+        );
+    }
+}
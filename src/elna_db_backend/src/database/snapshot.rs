@@ -0,0 +1,304 @@
+//! Labelled copies of collections kept in stable memory. A snapshot holds the
+//! whole collection, index included, and survives upgrades and the deletion of
+//! the collection it was taken from.
+use crate::database::clock;
+use crate::database::collection::Collection;
+use crate::database::db::DB;
+use crate::database::error::Error;
+use crate::database::memory::{get_snapshots_memory, Memory};
+use crate::database::namespace;
+use candid::CandidType;
+use ic_stable_structures::{storable::Bound, StableBTreeMap, Storable};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+/// Separates the collection name from the label in snapshot keys. Labels may
+/// not contain it, so a key always splits at its last occurrence.
+const SEPARATOR: char = '\0';
+
+#[derive(Serialize, Deserialize)]
+pub struct Snapshot {
+    /// Nanoseconds since the UNIX epoch.
+    pub created_at: u64,
+    /// The collection, encoded by `Collection::encode`. The snapshot owns the
+    /// indexes the encoding names, which are freed with it.
+    pub collection: Vec<u8>,
+    /// Length in bytes of the encodings of those indexes.
+    #[serde(default)]
+    pub index_bytes: u64,
+}
+
+impl Snapshot {
+    fn size_bytes(&self) -> u64 {
+        self.collection.len() as u64 + self.index_bytes
+    }
+
+    /// Frees the indexes of the snapshot. A snapshot that no longer decodes
+    /// names no indexes that could be found.
+    fn free(self) {
+        if let Ok(mut collection) = Collection::decode(&self.collection) {
+            collection.clear_index();
+        }
+    }
+}
+
+impl Storable for Snapshot {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut bytes = vec![];
+        ciborium::ser::into_writer(self, &mut bytes).unwrap();
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        ciborium::de::from_reader(bytes.as_ref()).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(CandidType, Deserialize, Clone, PartialEq, Debug)]
+pub struct SnapshotInfo {
+    pub label: String,
+    pub created_at: u64,
+    pub size_bytes: u64,
+}
+
+thread_local! {
+    pub static SNAPSHOTS: RefCell<StableBTreeMap<String, Snapshot, Memory>> = RefCell::new(init_stable_data());
+}
+
+fn init_stable_data() -> StableBTreeMap<String, Snapshot, Memory> {
+    StableBTreeMap::init(get_snapshots_memory())
+}
+
+fn key(collection: &str, label: &str) -> Result<String, Error> {
+    if label.is_empty() || label.contains(SEPARATOR) {
+        return Err(Error::InvalidName);
+    }
+    Ok(format!("{}{}{}", collection, SEPARATOR, label))
+}
+
+/// Decodes the snapshot `label` of `collection` into a collection with
+/// indexes of its own.
+fn load(collection: &str, label: &str) -> Result<Collection, Error> {
+    let key = key(collection, label)?;
+    let snapshot = SNAPSHOTS
+        .with(|snapshots| snapshots.borrow().get(&key))
+        .ok_or(Error::NotFound)?;
+    Ok(Collection::decode(&snapshot.collection)?.copy(true))
+}
+
+/// Size in bytes of the snapshots of the collections of `namespace`.
+pub fn namespace_bytes(namespace: &str) -> u64 {
+    let prefix = format!("{}{}", namespace, namespace::SEPARATOR);
+    SNAPSHOTS.with(|snapshots| {
+        snapshots
+            .borrow()
+            .range(prefix.clone()..)
+            .take_while(|(key, _)| key.starts_with(&prefix))
+            .map(|(_, snapshot)| snapshot.size_bytes())
+            .sum()
+    })
+}
+
+/// Copies the collection `name` into a new snapshot called `label`, within
+/// the snapshot quota of its namespace.
+pub fn create(name: &str, label: &str) -> Result<(), Error> {
+    let key = key(name, label)?;
+    if SNAPSHOTS.with(|snapshots| snapshots.borrow().contains_key(&key)) {
+        return Err(Error::UniqueViolation);
+    }
+    let (collection, index_bytes) = DB.with(|db| {
+        let db = db.borrow();
+        let collection = db.collections.get(name).ok_or(Error::NotFound)?;
+        let index_bytes = collection.index_bytes();
+        db.check_snapshot_quota(name, collection.encode().len() as u64 + index_bytes)?;
+        Ok((collection.copy(true).encode(), index_bytes))
+    })?;
+    let snapshot = Snapshot {
+        created_at: clock::now(),
+        collection,
+        index_bytes,
+    };
+    SNAPSHOTS.with(|snapshots| snapshots.borrow_mut().insert(key, snapshot));
+    Ok(())
+}
+
+/// Lists the snapshots of the collection `name`, which may have been deleted since.
+pub fn list(name: &str) -> Vec<SnapshotInfo> {
+    let prefix = format!("{}{}", name, SEPARATOR);
+    SNAPSHOTS.with(|snapshots| {
+        snapshots
+            .borrow()
+            .range(prefix.clone()..)
+            .take_while(|(key, _)| key.starts_with(&prefix))
+            .filter_map(|(key, snapshot)| {
+                let (collection, label) = key.rsplit_once(SEPARATOR)?;
+                (collection == name).then(|| SnapshotInfo {
+                    label: label.to_string(),
+                    created_at: snapshot.created_at,
+                    size_bytes: snapshot.size_bytes(),
+                })
+            })
+            .collect()
+    })
+}
+
+/// Rolls the collection `name` back to the snapshot `label`, recreating it if
+/// it was deleted.
+pub fn restore(name: &str, label: &str) -> Result<(), Error> {
+    let collection = load(name, label)?;
+    DB.with(|db| {
+        db.borrow_mut()
+            .restore_collection(&name.to_string(), collection)
+    })
+}
+
+/// Creates the collection `target` from the snapshot `label` of the collection `name`.
+pub fn clone_into(name: &str, label: &str, target: &str) -> Result<(), Error> {
+    let collection = load(name, label)?;
+    DB.with(|db| {
+        db.borrow_mut()
+            .import_collection(&target.to_string(), collection)
+    })
+}
+
+pub fn delete(name: &str, label: &str) -> Result<(), Error> {
+    let key = key(name, label)?;
+    SNAPSHOTS
+        .with(|snapshots| snapshots.borrow_mut().remove(&key))
+        .map(Snapshot::free)
+        .ok_or(Error::NotFound)
+}
+
+/// Deletes the snapshots of the collections of `namespace`.
+pub fn delete_namespace(namespace: &str) {
+    let prefix = format!("{}{}", namespace, namespace::SEPARATOR);
+    SNAPSHOTS.with(|snapshots| {
+        let mut snapshots = snapshots.borrow_mut();
+        let keys: Vec<String> = snapshots
+            .range(prefix.clone()..)
+            .map(|(key, _)| key)
+            .take_while(|key| key.starts_with(&prefix))
+            .collect();
+        for key in keys {
+            if let Some(snapshot) = snapshots.remove(&key) {
+                snapshot.free();
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{create, delete, key, list, restore, Snapshot, SNAPSHOTS};
+    use crate::database::collection::Collection;
+    use crate::database::db::DB;
+    use crate::database::error::Error;
+    use crate::database::field::FieldQuery;
+    use crate::database::quota::{Quota, QuotaKind};
+
+    #[test]
+    fn labels_cannot_contain_the_separator() {
+        assert_eq!(key("docs", "v1"), Ok("docs\0v1".to_string()));
+        assert_eq!(key("docs", ""), Err(Error::InvalidName));
+        assert_eq!(key("docs", "a\0b"), Err(Error::InvalidName));
+    }
+
+    #[test]
+    fn snapshots_count_against_the_namespace_quota() {
+        let name = "acme/docs".to_string();
+        DB.with(|db| {
+            let mut db = db.borrow_mut();
+            let quota = Quota {
+                max_snapshot_bytes: Some(1),
+                ..Default::default()
+            };
+            db.create_namespace(&"acme".to_string(), quota).unwrap();
+            db.create_collection(&name, 3).unwrap();
+        });
+        assert_eq!(
+            create(&name, "v1"),
+            Err(Error::QuotaExceeded(QuotaKind::SnapshotBytes))
+        );
+
+        DB.with(|db| {
+            let quota = Quota {
+                max_snapshot_bytes: Some(1 << 20),
+                ..Default::default()
+            };
+            db.borrow_mut()
+                .set_namespace_quota(&"acme".to_string(), quota)
+                .unwrap();
+        });
+        assert_eq!(create(&name, "v1"), Ok(()));
+        let usage = DB.with(|db| db.borrow().namespace_usage("acme"));
+        assert!(usage.snapshot_bytes > 1);
+    }
+
+    #[test]
+    fn deleting_a_namespace_deletes_its_snapshots() {
+        DB.with(|db| {
+            let mut db = db.borrow_mut();
+            db.create_namespace(&"acme".to_string(), Quota::default())
+                .unwrap();
+            db.create_collection(&"acme/docs".to_string(), 3).unwrap();
+            db.create_collection(&"docs".to_string(), 3).unwrap();
+        });
+        create("acme/docs", "v1").unwrap();
+        create("docs", "v1").unwrap();
+
+        DB.with(|db| db.borrow_mut().delete_namespace(&"acme".to_string()))
+            .unwrap();
+        assert!(list("acme/docs").is_empty());
+        assert_eq!(list("docs").len(), 1);
+    }
+
+    #[test]
+    fn snapshots_own_their_indexes() {
+        let name = "docs".to_string();
+        DB.with(|db| {
+            let mut db = db.borrow_mut();
+            db.create_collection(&name, 2).unwrap();
+            let keys = vec![vec![1.0, 0.0], vec![0.0, 1.0]];
+            let values = vec!["x".to_string(), "y".to_string()];
+            db.insert_into_collection(&name, keys, values, "f".into())
+                .unwrap();
+            db.build_index(&name).unwrap();
+        });
+        create(&name, "v1").unwrap();
+        assert!(list(&name)[0].size_bytes > 0);
+        restore(&name, "v1").unwrap();
+        restore(&name, "v1").unwrap();
+        delete(&name, "v1").unwrap();
+
+        let query = vec![FieldQuery {
+            field: "default".to_string(),
+            vector: vec![0.0, 1.0],
+            weight: 1.0,
+        }];
+        let result = DB.with(|db| db.borrow().query_fields(&name, query, 1));
+        assert_eq!(result.unwrap()[0].1, "y");
+    }
+
+    #[test]
+    fn malformed_snapshots_fail_to_load() {
+        assert!(matches!(
+            Collection::decode(&[0xff, 0x00]),
+            Err(Error::MemoryError)
+        ));
+        let snapshot = Snapshot {
+            created_at: 0,
+            collection: vec![0xff, 0x00],
+            index_bytes: 0,
+        };
+        SNAPSHOTS.with(|snapshots| {
+            snapshots
+                .borrow_mut()
+                .insert(key("docs", "bad").unwrap(), snapshot)
+        });
+        assert_eq!(restore("docs", "bad"), Err(Error::MemoryError));
+        assert_eq!(delete("docs", "bad"), Ok(()));
+    }
+}
@@ -0,0 +1,200 @@
+//! Sparse vectors, such as those of learned sparse retrieval models like
+//! SPLADE, searched by dot product through an inverted index from each
+//! dimension to the records with a non-zero value in it.
+use super::error::Error;
+use super::field::FieldQuery;
+use candid::CandidType;
+use serde::{Deserialize, Serialize, Serializer};
+use std::collections::{BTreeMap, HashMap};
+
+/// The non-zero entries of a sparse vector, as parallel lists of dimensions
+/// and values.
+#[derive(CandidType, Serialize, Deserialize, Clone, Default, PartialEq, Debug)]
+pub struct SparseVector {
+    pub indices: Vec<u32>,
+    pub values: Vec<f32>,
+}
+
+impl SparseVector {
+    /// Sorts the entries by dimension, failing if the lists differ in length,
+    /// a value is NaN or infinite or a dimension repeats.
+    pub fn normalized(self) -> Result<Self, Error> {
+        if self.indices.len() != self.values.len() {
+            return Err(Error::InvalidSparseVector(
+                "indices and values differ in length".to_string(),
+            ));
+        }
+        if self.values.iter().any(|value| !value.is_finite()) {
+            return Err(Error::InvalidSparseVector(
+                "contains NaN or infinite values".to_string(),
+            ));
+        }
+        let mut entries = self
+            .indices
+            .into_iter()
+            .zip(self.values)
+            .collect::<Vec<_>>();
+        entries.sort_by_key(|&(index, _)| index);
+        if entries.windows(2).any(|pair| pair[0].0 == pair[1].0) {
+            return Err(Error::InvalidSparseVector("repeated index".to_string()));
+        }
+        let (indices, values) = entries.into_iter().unzip();
+        Ok(SparseVector { indices, values })
+    }
+
+    fn entries(&self) -> impl Iterator<Item = (u32, f32)> + '_ {
+        self.indices
+            .iter()
+            .copied()
+            .zip(self.values.iter().copied())
+    }
+
+    /// Dot product of two normalized vectors.
+    pub fn dot(&self, other: &SparseVector) -> f32 {
+        let (mut i, mut j, mut sum) = (0, 0, 0.0);
+        while i < self.indices.len() && j < other.indices.len() {
+            match self.indices[i].cmp(&other.indices[j]) {
+                std::cmp::Ordering::Less => i += 1,
+                std::cmp::Ordering::Greater => j += 1,
+                std::cmp::Ordering::Equal => {
+                    sum += self.values[i] * other.values[j];
+                    i += 1;
+                    j += 1;
+                }
+            }
+        }
+        sum
+    }
+}
+
+/// A query combining dense fields, searched as by `query_fields`, with a
+/// sparse vector whose dot product is weighted by `sparse_weight`.
+#[derive(CandidType, Deserialize, Clone, PartialEq, Debug)]
+pub struct HybridQuery {
+    pub dense: Vec<FieldQuery>,
+    pub sparse: Option<SparseVector>,
+    pub sparse_weight: f32,
+}
+
+/// The sparse vectors of a collection, one per record. Only the vectors are
+/// serialized; the inverted index is rebuilt from them when deserializing.
+#[derive(Deserialize, Clone, Default)]
+#[serde(from = "Vec<SparseVector>")]
+pub struct SparseIndex {
+    rows: Vec<SparseVector>,
+    /// For each dimension, the rows with a non-zero value in it and that value.
+    postings: BTreeMap<u32, Vec<(u32, f32)>>,
+}
+
+impl Serialize for SparseIndex {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.rows.serialize(serializer)
+    }
+}
+
+impl From<Vec<SparseVector>> for SparseIndex {
+    fn from(rows: Vec<SparseVector>) -> Self {
+        let mut index = SparseIndex::default();
+        for row in rows {
+            index.push(row);
+        }
+        index
+    }
+}
+
+impl SparseIndex {
+    /// An index of `count` empty vectors, for records inserted before the
+    /// first sparse vector.
+    pub fn with_empty_rows(count: usize) -> Self {
+        SparseIndex {
+            rows: vec![SparseVector::default(); count],
+            postings: BTreeMap::new(),
+        }
+    }
+
+    /// Appends a normalized vector as the next row.
+    pub fn push(&mut self, vector: SparseVector) {
+        let row = self.rows.len() as u32;
+        for (index, value) in vector.entries().filter(|&(_, value)| value != 0.0) {
+            self.postings.entry(index).or_default().push((row, value));
+        }
+        self.rows.push(vector);
+    }
+
+    pub fn row(&self, row: usize) -> &SparseVector {
+        &self.rows[row]
+    }
+
+    /// Number of stored non-zero entries.
+    pub fn entry_count(&self) -> u64 {
+        self.rows.iter().map(|row| row.indices.len() as u64).sum()
+    }
+
+    pub fn score(&self, row: usize, query: &SparseVector) -> f32 {
+        self.rows[row].dot(query)
+    }
+
+    /// Returns up to `limit` rows with the highest dot product with `query`,
+    /// best first, leaving out rows sharing no dimension with it.
+    pub fn search(&self, query: &SparseVector, limit: usize) -> Vec<usize> {
+        let mut scores: HashMap<u32, f32> = HashMap::new();
+        for (index, value) in query.entries() {
+            for &(row, stored) in self.postings.get(&index).into_iter().flatten() {
+                *scores.entry(row).or_default() += value * stored;
+            }
+        }
+        let mut ranked = scores.into_iter().collect::<Vec<_>>();
+        ranked.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+        ranked
+            .into_iter()
+            .take(limit)
+            .map(|(row, _)| row as usize)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SparseIndex, SparseVector};
+    use crate::database::error::Error;
+
+    fn sparse(indices: Vec<u32>, values: Vec<f32>) -> SparseVector {
+        SparseVector { indices, values }.normalized().unwrap()
+    }
+
+    #[test]
+    fn normalize() {
+        assert_eq!(
+            sparse(vec![7, 2], vec![1.0, 2.0]),
+            SparseVector {
+                indices: vec![2, 7],
+                values: vec![2.0, 1.0]
+            }
+        );
+        let repeated = SparseVector {
+            indices: vec![2, 2],
+            values: vec![1.0, 1.0],
+        };
+        assert!(matches!(
+            repeated.normalized(),
+            Err(Error::InvalidSparseVector(_))
+        ));
+    }
+
+    #[test]
+    fn search_by_dot_product() {
+        let mut index = SparseIndex::with_empty_rows(1);
+        index.push(sparse(vec![1, 5], vec![1.0, 1.0]));
+        index.push(sparse(vec![5, 9], vec![3.0, 1.0]));
+        index.push(sparse(vec![2], vec![4.0]));
+
+        let query = sparse(vec![5, 1], vec![1.0, 0.5]);
+        assert_eq!(index.search(&query, 10), vec![2, 1]);
+        assert_eq!(index.score(1, &query), 1.5);
+
+        let mut bytes = vec![];
+        ciborium::ser::into_writer(&index, &mut bytes).unwrap();
+        let index: SparseIndex = ciborium::de::from_reader(bytes.as_slice()).unwrap();
+        assert_eq!(index.search(&query, 1), vec![2]);
+    }
+}
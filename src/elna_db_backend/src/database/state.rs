@@ -0,0 +1,354 @@
+//! Layout of the heap state saved across upgrades. The state is written as
+//!
+//! ```text
+//! MAGIC (4 bytes) | version (u32 LE) | length (u64 LE) | SHA-256 (32 bytes) | CBOR-encoded `Database`
+//! ```
+//!
+//! where the SHA-256 is that of the CBOR payload and is verified on load.
+//! Versions 2 and earlier held the HNSW index of each collection on the heap;
+//! it now lives in stable memory, and older states load unindexed until
+//! `build_index` is called on each collection.
+//! Version 1 had no checksum. Canisters released before the header existed
+//! wrote `length (u32 LE) | CBOR` instead; that layout is read as version 0.
+//!
+//! To change the schema incompatibly, bump `CURRENT_VERSION`, keep the
+//! previous structs in a `vN` module and add a migration from it to
+//! `decode_payload`, along with a test decoding a fixture of the old version.
+//! Adding a field with `#[serde(default)]` does not need a new version.
+use crate::database::db::{Database, DB};
+use crate::database::error::Error;
+use crate::database::metrics;
+use candid::{CandidType, Deserialize};
+use elna_auth_macros::check_is_owner;
+use ic_cdk::update;
+use ic_stable_structures::writer::Writer;
+use ic_stable_structures::Memory;
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+
+pub const MAGIC: &[u8; 4] = b"ELNA";
+
+pub const CURRENT_VERSION: u32 = 3;
+
+/// Size of the longest header, that of versions 2 and later.
+const HEADER_LEN: usize = 48;
+
+/// Instructions available to `pre_upgrade` and `post_upgrade` together.
+const UPGRADE_INSTRUCTION_LIMIT: u64 = 300_000_000_000;
+
+/// Size of the wasm32 heap, which has to hold the state and its encoding at once.
+const HEAP_LIMIT_BYTES: u64 = 4 * 1024 * 1024 * 1024;
+
+thread_local! {
+    /// Checksum of the state verified by the last `post_upgrade`, if any.
+    static LAST_UPGRADE_CHECKSUM: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+#[derive(Debug, thiserror::Error, PartialEq)]
+pub enum StateError {
+    #[error("state version {0} is newer than this release supports")]
+    UnsupportedVersion(u32),
+    #[error("failed to decode state version {version}: {message}")]
+    Decode { version: u32, message: String },
+    #[error("state checksum mismatch: expected {expected}, found {found}")]
+    ChecksumMismatch { expected: String, found: String },
+}
+
+/// Where the payload is and which version it has, as read from the start of
+/// the upgrades memory.
+#[derive(Debug, PartialEq)]
+struct Header {
+    version: u32,
+    offset: u64,
+    len: u64,
+    checksum: Option<[u8; 32]>,
+}
+
+impl Header {
+    fn parse(prefix: &[u8; HEADER_LEN]) -> Self {
+        if prefix[..4] != MAGIC[..] {
+            return Header {
+                version: 0,
+                offset: 4,
+                len: u32::from_le_bytes(prefix[..4].try_into().unwrap()) as u64,
+                checksum: None,
+            };
+        }
+        let version = u32::from_le_bytes(prefix[4..8].try_into().unwrap());
+        let len = u64::from_le_bytes(prefix[8..16].try_into().unwrap());
+        if version == 1 {
+            return Header {
+                version,
+                offset: 16,
+                len,
+                checksum: None,
+            };
+        }
+        Header {
+            version,
+            offset: HEADER_LEN as u64,
+            len,
+            checksum: Some(prefix[16..48].try_into().unwrap()),
+        }
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Encodes `db` with the header of the current version.
+pub fn encode(db: &Database) -> Vec<u8> {
+    let mut payload = vec![];
+    ciborium::ser::into_writer(db, &mut payload).expect("failed to encode state");
+
+    let mut bytes = Vec::with_capacity(HEADER_LEN + payload.len());
+    bytes.extend_from_slice(MAGIC);
+    bytes.extend_from_slice(&CURRENT_VERSION.to_le_bytes());
+    bytes.extend_from_slice(&(payload.len() as u64).to_le_bytes());
+    bytes.extend_from_slice(&Sha256::digest(&payload));
+    bytes.extend_from_slice(&payload);
+    bytes
+}
+
+/// Hex SHA-256 of the payload of an encoded state, as stored in its header.
+pub fn checksum(bytes: &[u8]) -> Option<String> {
+    let mut prefix = [0; HEADER_LEN];
+    let available = bytes.len().min(HEADER_LEN);
+    prefix[..available].copy_from_slice(&bytes[..available]);
+    Header::parse(&prefix)
+        .checksum
+        .map(|checksum| to_hex(&checksum))
+}
+
+/// Decodes a state written by `encode` by this or an earlier release,
+/// migrating it to the current schema.
+pub fn decode(bytes: &[u8]) -> Result<Database, StateError> {
+    let mut prefix = [0; HEADER_LEN];
+    let available = bytes.len().min(HEADER_LEN);
+    prefix[..available].copy_from_slice(&bytes[..available]);
+    let header = Header::parse(&prefix);
+
+    let start = (header.offset as usize).min(bytes.len());
+    let end = start.saturating_add(header.len as usize).min(bytes.len());
+    let payload = &bytes[start..end];
+    if let Some(expected) = header.checksum {
+        let found = Sha256::digest(payload);
+        if found[..] != expected[..] {
+            return Err(StateError::ChecksumMismatch {
+                expected: to_hex(&expected),
+                found: to_hex(&found),
+            });
+        }
+    }
+    decode_payload(header.version, payload)
+}
+
+fn decode_payload(version: u32, payload: &[u8]) -> Result<Database, StateError> {
+    let decode_error = |e: ciborium::de::Error<std::io::Error>| StateError::Decode {
+        version,
+        message: e.to_string(),
+    };
+    match version {
+        // Versions 0 to 2 differ from version 3 by fields that were added with
+        // serde defaults and by the index each collection kept on the heap.
+        // That index is skipped when decoding: building it again would not fit
+        // in `post_upgrade` for large collections, so they load unindexed and
+        // `build_index` has to be called on each of them after the upgrade.
+        0..=2 => {
+            let mut db: Database = ciborium::de::from_reader(payload).map_err(decode_error)?;
+            for collection in db.collections.values_mut() {
+                collection.clear_index();
+            }
+            Ok(db)
+        }
+        3 => ciborium::de::from_reader(payload).map_err(decode_error),
+        _ => Err(StateError::UnsupportedVersion(version)),
+    }
+}
+
+pub fn save<M: Memory>(memory: &mut M, db: &Database) {
+    let bytes = encode(db);
+    let mut writer = Writer::new(memory, 0);
+    writer.write(&bytes).expect("failed to write state");
+}
+
+pub fn load<M: Memory>(memory: &M) -> Result<Database, StateError> {
+    let mut prefix = [0; HEADER_LEN];
+    memory.read(0, &mut prefix);
+    let header = Header::parse(&prefix);
+
+    let mut bytes = vec![0; (header.offset + header.len) as usize];
+    memory.read(0, &mut bytes);
+    let db = decode(&bytes)?;
+    LAST_UPGRADE_CHECKSUM.with(|last| *last.borrow_mut() = checksum(&bytes));
+    Ok(db)
+}
+
+#[derive(CandidType, Deserialize, Clone, PartialEq, Debug)]
+pub struct UpgradeReport {
+    /// Size of the encoded state, header included.
+    pub state_bytes: u64,
+    pub encode_instructions: u64,
+    pub decode_instructions: u64,
+    /// Whether encoding and decoding fit the upgrade instruction limit and
+    /// the heap can hold the state next to its encoding.
+    pub fits: bool,
+    /// Hex SHA-256 of the state as it would be written now.
+    pub checksum: String,
+    /// Checksum of the state verified by the last upgrade, if any.
+    pub last_upgrade_checksum: Option<String>,
+    /// Why the encoded state could not be decoded again, if it could not.
+    pub error: Option<String>,
+}
+
+/// Encodes the state and decodes it again as an upgrade would, without
+/// changing anything. Runs as an update call for its higher instruction
+/// limit; a state too large for that limit makes the call trap, which is a
+/// warning sign in itself.
+#[update]
+#[check_is_owner]
+fn upgrade_dry_run() -> Result<UpgradeReport, Error> {
+    let counter = || ic_cdk::api::performance_counter(0);
+
+    let start = counter();
+    let bytes = DB.with(|db| encode(&db.borrow()));
+    let encode_instructions = counter() - start;
+
+    let start = counter();
+    let error = decode(&bytes).err().map(|error| error.to_string());
+    let decode_instructions = counter() - start;
+
+    let state_bytes = bytes.len() as u64;
+    let fits = error.is_none()
+        && encode_instructions + decode_instructions < UPGRADE_INSTRUCTION_LIMIT
+        && metrics::heap_memory_bytes() + state_bytes < HEAP_LIMIT_BYTES;
+    Ok(UpgradeReport {
+        state_bytes,
+        encode_instructions,
+        decode_instructions,
+        fits,
+        checksum: checksum(&bytes).unwrap_or_default(),
+        last_upgrade_checksum: LAST_UPGRADE_CHECKSUM.with(|last| last.borrow().clone()),
+        error,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{checksum, decode, encode, Header, StateError, CURRENT_VERSION, MAGIC};
+    use crate::database::db::Database;
+    use crate::database::error::Error;
+    use crate::database::field::FieldQuery;
+
+    /// States written by the pre-upgrade hooks of earlier releases, each
+    /// holding the collection `test` with the vectors `red` and `green`, built.
+    /// Versions 1 and 2 also alias it as `prod`.
+    const V0_STATE: &[u8] = include_bytes!("fixtures/state_v0.bin");
+    const V1_STATE: &[u8] = include_bytes!("fixtures/state_v1.bin");
+    const V2_STATE: &[u8] = include_bytes!("fixtures/state_v2.bin");
+
+    /// Checks that `db` holds the collection of the fixtures, unindexed, and
+    /// that it can be searched once the index is built again.
+    fn assert_fixture_collection(db: &mut Database) {
+        let name = "test".to_string();
+        let info = db.collection_info(&name).unwrap();
+        assert_eq!(info.dimension, 2);
+        assert_eq!(info.vector_count, 2);
+        assert_eq!(info.indexed_count, 0);
+        assert_eq!(info.last_built_at, None);
+        assert_eq!(info.file_count, 1);
+
+        let query = || {
+            vec![FieldQuery {
+                field: "default".to_string(),
+                vector: vec![3.0, 4.5],
+                weight: 1.0,
+            }]
+        };
+        assert_eq!(
+            db.query_fields(&name, query(), 1),
+            Err(Error::IndexNotBuilt)
+        );
+        db.build_index(&name).unwrap();
+        let result = db.query_fields(&name, query(), 1);
+        assert_eq!(result, Ok(vec![(0.9984603, "green".to_string())]));
+    }
+
+    #[test]
+    fn decode_v0_state() {
+        let mut db = decode(V0_STATE).unwrap();
+        assert!(db.namespaces.is_empty());
+        assert!(db.aliases.is_empty());
+        assert_fixture_collection(&mut db);
+    }
+
+    #[test]
+    fn roundtrip_current_state() {
+        let mut db = Database::new();
+        let _ = db.create_collection(&"test".to_string(), 3);
+        let _ = db.set_alias(&"prod".to_string(), &"test".to_string());
+
+        let bytes = encode(&db);
+        assert_eq!(&bytes[..4], MAGIC);
+        let db = decode(&bytes).unwrap();
+        assert_eq!(db.get_all_collections(), vec!["test".to_string()]);
+        assert_eq!(db.resolve(&"prod".to_string()), "test");
+    }
+
+    #[test]
+    fn decode_v1_state() {
+        let mut db = decode(V1_STATE).unwrap();
+        assert_eq!(db.resolve(&"prod".to_string()), "test");
+        assert_fixture_collection(&mut db);
+    }
+
+    #[test]
+    fn decode_v2_state() {
+        assert!(checksum(V2_STATE).is_some());
+        let mut db = decode(V2_STATE).unwrap();
+        assert_eq!(db.resolve(&"prod".to_string()), "test");
+        assert_fixture_collection(&mut db);
+    }
+
+    #[test]
+    fn reject_corrupted_state() {
+        let mut db = Database::new();
+        let _ = db.create_collection(&"test".to_string(), 3);
+        let mut bytes = encode(&db);
+        assert_eq!(checksum(&bytes).map(|c| c.len()), Some(64));
+
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+        assert!(matches!(
+            decode(&bytes),
+            Err(StateError::ChecksumMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn reject_newer_state() {
+        let mut bytes = encode(&Database::new());
+        bytes[4..8].copy_from_slice(&(CURRENT_VERSION + 1).to_le_bytes());
+        assert_eq!(
+            decode(&bytes).err(),
+            Some(StateError::UnsupportedVersion(CURRENT_VERSION + 1))
+        );
+    }
+
+    #[test]
+    fn parse_legacy_header() {
+        let mut prefix = [0; 48];
+        prefix[..4].copy_from_slice(&42u32.to_le_bytes());
+        let header = Header::parse(&prefix);
+        assert_eq!(
+            header,
+            Header {
+                version: 0,
+                offset: 4,
+                len: 42,
+                checksum: None,
+            }
+        );
+    }
+}
@@ -0,0 +1,157 @@
+//! Staged inserts for batches larger than a single message. Chunks are checked
+//! as they arrive and kept on the heap until `commit_upload` inserts all of
+//! them at once, so a failed or abandoned upload leaves the collection as it was.
+use crate::database::audit::Operation;
+use crate::database::db::DB;
+use crate::database::error::Error;
+use crate::database::operations::{audited, resolved_name};
+use crate::database::rate_limit::{self, Endpoint};
+use crate::database::session::Sessions;
+use elna_auth_macros::check_authorization;
+use ic_cdk::update;
+use std::cell::RefCell;
+
+pub struct UploadSession {
+    name: String,
+    file_name: String,
+    dimension: usize,
+    keys: Vec<Vec<f32>>,
+    values: Vec<String>,
+    document_bytes: u64,
+}
+
+impl UploadSession {
+    pub fn new(name: String, file_name: String, dimension: usize) -> Self {
+        Self {
+            name,
+            file_name,
+            dimension,
+            keys: vec![],
+            values: vec![],
+            document_bytes: 0,
+        }
+    }
+
+    /// Stages a chunk after checking it has one document per vector and that
+    /// every vector has the collection's dimension.
+    pub fn append(&mut self, keys: Vec<Vec<f32>>, values: Vec<String>) -> Result<(), Error> {
+        if keys.len() != values.len() || keys.iter().any(|key| key.len() != self.dimension) {
+            return Err(Error::DimensionMismatch);
+        }
+        self.document_bytes += values.iter().map(|v| v.len() as u64).sum::<u64>();
+        self.keys.extend(keys);
+        self.values.extend(values);
+        Ok(())
+    }
+}
+
+thread_local! {
+    pub static UPLOADS: RefCell<Sessions<UploadSession>> = RefCell::new(Sessions::default());
+}
+
+/// Opens a staged insert into the collection `name`. Returns the session id
+/// to pass to `upload_chunk`, `commit_upload` and `abort_upload`.
+#[update]
+#[check_authorization]
+fn begin_upload(name: String, file_name: String) -> Result<u64, Error> {
+    let caller = ic_cdk::caller();
+    let name = resolved_name(&caller, &name)?;
+    let dimension = DB.with(|db| {
+        let db = db.borrow();
+        db.collections
+            .get(&name)
+            .map(|collection| collection.dimension)
+            .ok_or(Error::NotFound)
+    })?;
+    let session = UploadSession::new(name, file_name, dimension);
+    UPLOADS.with(|uploads| uploads.borrow_mut().open(caller, session))
+}
+
+/// Stages vectors and their documents. Fails without staging anything if the
+/// chunk is malformed or would take the collection over its quota.
+#[update]
+#[check_authorization]
+fn upload_chunk(session: u64, keys: Vec<Vec<f32>>, values: Vec<String>) -> Result<(), Error> {
+    let caller = ic_cdk::caller();
+    UPLOADS.with(|uploads| {
+        let mut uploads = uploads.borrow_mut();
+        let session = uploads.get_mut(session, &caller)?;
+        let document_bytes = values.iter().map(|v| v.len() as u64).sum::<u64>();
+        DB.with(|db| {
+            db.borrow().check_insert_quota(
+                &session.name,
+                (session.keys.len() + keys.len()) as u64,
+                session.document_bytes + document_bytes,
+            )
+        })?;
+        session.append(keys, values)
+    })
+}
+
+/// Inserts every staged chunk into the collection at once and closes the session.
+/// A rate-limited commit leaves the session open, so it can be retried.
+#[update]
+#[check_authorization]
+fn commit_upload(session: u64) -> Result<(), Error> {
+    let caller = ic_cdk::caller();
+    audited(
+        caller,
+        Operation::Insert,
+        "commit_upload",
+        String::new(),
+        |target| {
+            *target = UPLOADS.with(|uploads| {
+                let mut uploads = uploads.borrow_mut();
+                uploads
+                    .get_mut(session, &caller)
+                    .map(|session| session.name.clone())
+            })?;
+            rate_limit::acquire(caller, Endpoint::Insert)?;
+            let session = UPLOADS.with(|uploads| uploads.borrow_mut().close(session, &caller))?;
+            DB.with(|db| {
+                let mut db = db.borrow_mut();
+                db.insert_into_collection(
+                    &session.name,
+                    session.keys,
+                    session.values,
+                    session.file_name,
+                )
+            })
+        },
+    )
+}
+
+/// Discards the staged chunks and closes the session.
+#[update]
+#[check_authorization]
+fn abort_upload(session: u64) -> Result<(), Error> {
+    let caller = ic_cdk::caller();
+    UPLOADS
+        .with(|uploads| uploads.borrow_mut().close(session, &caller))
+        .map(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::UploadSession;
+    use crate::database::error::Error;
+
+    #[test]
+    fn append_checks_each_chunk() {
+        let mut session = UploadSession::new("test".to_string(), "file".to_string(), 2);
+        assert_eq!(
+            session.append(vec![vec![1.0, 2.0]], vec!["red".to_string()]),
+            Ok(())
+        );
+        assert_eq!(
+            session.append(vec![vec![1.0, 2.0, 3.0]], vec!["green".to_string()]),
+            Err(Error::DimensionMismatch)
+        );
+        assert_eq!(
+            session.append(vec![vec![1.0, 2.0]], vec![]),
+            Err(Error::DimensionMismatch)
+        );
+        assert_eq!(session.keys.len(), 1);
+        assert_eq!(session.document_bytes, 3);
+    }
+}
@@ -1,10 +1,15 @@
-use crate::database::memory::{get_stable_btree_memory, Memory};
+use crate::database::memory::{
+    get_auditors_memory, get_owner_memory, get_stable_btree_memory, Memory,
+};
 
+use crate::database::audit::{self, Operation};
+use crate::database::db::DB;
 use crate::database::error::Error;
-use candid::{CandidType, Principal};
+use crate::database::metrics;
+use candid::{CandidType, Deserialize, Principal};
 use elna_auth_macros::check_is_owner;
 use ic_cdk::{init, query, update};
-use ic_stable_structures::{storable::Bound, StableBTreeMap, Storable};
+use ic_stable_structures::{storable::Bound, StableBTreeMap, StableCell, Storable};
 
 use std::cell::RefCell;
 
@@ -27,13 +32,138 @@ impl Storable for StorablePrincipal {
 }
 
 thread_local! {
-    pub static OWNER: RefCell<String> = RefCell::new(String::new());
+    /// The owner, or the anonymous principal until one is set.
+    pub static OWNER: RefCell<StableCell<StorablePrincipal, Memory>> = RefCell::new(init_owner());
     pub static ADMINS: RefCell<StableBTreeMap<StorablePrincipal, bool, Memory>> = RefCell::new(init_stable_data());
+    pub static AUDITORS: RefCell<StableBTreeMap<StorablePrincipal, bool, Memory>> = RefCell::new(init_auditors());
+}
+
+/// Roles checked by the authorization macros and by `canister_inspect_message`.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Role {
+    /// The super user of the canister.
+    Owner,
+    /// The owner, the canister-wide admins and the namespace admins.
+    Admin,
+    /// The owner and the auditors.
+    Auditor,
+}
+
+pub fn owner() -> Option<Principal> {
+    let owner = OWNER.with(|owner| owner.borrow().get().0);
+    (owner != Principal::anonymous()).then_some(owner)
+}
+
+fn set_owner(owner: Principal) {
+    OWNER
+        .with(|cell| cell.borrow_mut().set(StorablePrincipal(owner)))
+        .expect("failed to store owner");
+}
+
+pub fn is_owner(principal: &Principal) -> bool {
+    owner() == Some(*principal)
+}
+
+/// Whether `principal` is the owner or a canister-wide admin, i.e. sees every namespace.
+pub fn is_canister_admin(principal: &Principal) -> bool {
+    is_owner(principal)
+        || ADMINS.with(|admins| admins.borrow().contains_key(&StorablePrincipal(*principal)))
+}
+
+pub fn has_role(principal: &Principal, role: Role) -> bool {
+    match role {
+        Role::Owner => is_owner(principal),
+        Role::Admin => {
+            is_canister_admin(principal)
+                || DB.with(|db| db.borrow().namespace_of(principal).is_some())
+        }
+        Role::Auditor => {
+            is_owner(principal)
+                || AUDITORS.with(|auditors| {
+                    auditors
+                        .borrow()
+                        .contains_key(&StorablePrincipal(*principal))
+                })
+        }
+    }
+}
+
+/// Fails a call to `method` that the authorization macros rejected, recording
+/// it in the audit log if the method mutates state and in the metrics if its
+/// calls are counted. Ingress messages are already rejected by
+/// `canister_inspect_message`, so these are calls from other canisters.
+pub fn reject(method: &'static str, caller: &Principal, target: &str) -> Error {
+    let result: Result<(), Error> = Err(Error::Unauthorized);
+    if let Some(operation) = Operation::for_method(method) {
+        audit::record_as(*caller, operation, target, &result);
+    }
+    if metrics::is_observed(method) {
+        metrics::observe(method, &result);
+    }
+    Error::Unauthorized
+}
+
+/// Optional argument of an upgrade. The owner is kept in stable memory, so
+/// upgrades normally pass no argument.
+#[derive(CandidType, Deserialize, Clone, Default, Debug)]
+pub struct UpgradeArgs {
+    /// Only needed when upgrading from a release that did not store the
+    /// owner; otherwise it must match the stored owner if given.
+    pub owner: Option<Principal>,
+}
+
+/// Decides the owner after an upgrade from the `stored` one and the one
+/// `requested` in the upgrade argument. Ownership is never changed by an
+/// upgrade; use `transfer_ownership` instead.
+pub fn upgraded_owner(
+    stored: Option<Principal>,
+    requested: Option<Principal>,
+) -> Result<Principal, String> {
+    match (stored, requested) {
+        (_, Some(requested)) if requested == Principal::anonymous() => {
+            Err("the anonymous principal cannot own the canister".to_string())
+        }
+        (Some(stored), None) => Ok(stored),
+        (Some(stored), Some(requested)) if stored == requested => Ok(stored),
+        (Some(stored), Some(requested)) => Err(format!(
+            "upgrade argument owner {} differs from the current owner {}; use transfer_ownership",
+            requested, stored
+        )),
+        (None, Some(requested)) => Ok(requested),
+        (None, None) => Err("no owner is stored; pass UpgradeArgs with the owner".to_string()),
+    }
+}
+
+/// Applies the upgrade argument, trapping, and so rolling the upgrade back, if
+/// it is invalid.
+pub fn apply_upgrade_args(args: Option<UpgradeArgs>) {
+    let requested = args.unwrap_or_default().owner;
+    match upgraded_owner(owner(), requested) {
+        Ok(owner) => set_owner(owner),
+        Err(message) => ic_cdk::trap(&message),
+    }
 }
 
 #[init]
 fn init(owner: Principal) {
-    OWNER.with(|o| *o.borrow_mut() = owner.to_string());
+    if owner == Principal::anonymous() {
+        ic_cdk::trap("the anonymous principal cannot own the canister");
+    }
+    set_owner(owner);
+}
+
+/// Hands the canister over to `new_owner`. The caller loses the owner role.
+#[update]
+#[check_is_owner]
+fn transfer_ownership(new_owner: Principal) -> Result<(), Error> {
+    let result = if new_owner == Principal::anonymous() {
+        Err(Error::InvalidPrincipal)
+    } else {
+        set_owner(new_owner);
+        Ok(())
+    };
+    audit::record(Operation::TransferOwnership, &new_owner.to_text(), &result);
+    result
 }
 
 #[query]
@@ -49,7 +179,7 @@ fn get_admins() -> Result<Vec<Principal>, Error> {
 #[check_is_owner]
 fn add_admin(principal_id: Principal) -> Result<(), Error> {
     let target_principal = StorablePrincipal(principal_id);
-    ADMINS.with(|admins| {
+    let result = ADMINS.with(|admins| {
         let mut admins = admins.borrow_mut();
         let exists = admins.contains_key(&target_principal);
         if exists {
@@ -57,14 +187,16 @@ fn add_admin(principal_id: Principal) -> Result<(), Error> {
         }
         admins.insert(target_principal, true);
         return Ok(());
-    })
+    });
+    audit::record(Operation::AddAdmin, &principal_id.to_text(), &result);
+    result
 }
 
 #[update]
 #[check_is_owner]
 fn remove_admin(principal_id: Principal) -> Result<(), Error> {
     let target_principal = StorablePrincipal(principal_id);
-    ADMINS.with(|admins| {
+    let result = ADMINS.with(|admins| {
         let mut admins = admins.borrow_mut();
         let exists = admins.contains_key(&target_principal);
         if !exists {
@@ -73,9 +205,90 @@ fn remove_admin(principal_id: Principal) -> Result<(), Error> {
 
         admins.remove(&target_principal);
         return Ok(());
+    });
+    audit::record(Operation::RemoveAdmin, &principal_id.to_text(), &result);
+    result
+}
+
+#[query]
+#[check_is_owner]
+fn get_auditors() -> Result<Vec<Principal>, Error> {
+    AUDITORS.with(|auditors| {
+        let auditors = auditors
+            .borrow()
+            .iter()
+            .map(|(k, _)| k.0)
+            .collect::<Vec<_>>();
+        Ok(auditors)
     })
 }
 
+#[update]
+#[check_is_owner]
+fn add_auditor(principal_id: Principal) -> Result<(), Error> {
+    let target_principal = StorablePrincipal(principal_id);
+    let result = AUDITORS.with(|auditors| {
+        let mut auditors = auditors.borrow_mut();
+        if auditors.contains_key(&target_principal) {
+            return Err(Error::UniqueViolation);
+        }
+        auditors.insert(target_principal, true);
+        Ok(())
+    });
+    audit::record(Operation::AddAuditor, &principal_id.to_text(), &result);
+    result
+}
+
+#[update]
+#[check_is_owner]
+fn remove_auditor(principal_id: Principal) -> Result<(), Error> {
+    let target_principal = StorablePrincipal(principal_id);
+    let result = AUDITORS.with(|auditors| {
+        let mut auditors = auditors.borrow_mut();
+        if auditors.remove(&target_principal).is_none() {
+            return Err(Error::NotFound);
+        }
+        Ok(())
+    });
+    audit::record(Operation::RemoveAuditor, &principal_id.to_text(), &result);
+    result
+}
+
 fn init_stable_data() -> StableBTreeMap<StorablePrincipal, bool, Memory> {
     StableBTreeMap::init(get_stable_btree_memory())
 }
+
+fn init_auditors() -> StableBTreeMap<StorablePrincipal, bool, Memory> {
+    StableBTreeMap::init(get_auditors_memory())
+}
+
+fn init_owner() -> StableCell<StorablePrincipal, Memory> {
+    StableCell::init(
+        get_owner_memory(),
+        StorablePrincipal(Principal::anonymous()),
+    )
+    .expect("failed to initialize owner")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::upgraded_owner;
+    use candid::Principal;
+
+    #[test]
+    fn upgrade_keeps_the_stored_owner() {
+        let owner = Principal::management_canister();
+        assert_eq!(upgraded_owner(Some(owner), None), Ok(owner));
+        assert_eq!(upgraded_owner(Some(owner), Some(owner)), Ok(owner));
+    }
+
+    #[test]
+    fn upgrade_rejects_invalid_owners() {
+        let owner = Principal::management_canister();
+        let other = Principal::from_slice(&[1]);
+        assert!(upgraded_owner(Some(owner), Some(other)).is_err());
+        assert!(upgraded_owner(Some(owner), Some(Principal::anonymous())).is_err());
+        assert!(upgraded_owner(None, None).is_err());
+        assert_eq!(upgraded_owner(None, Some(other)), Ok(other));
+    }
+}
@@ -2,23 +2,47 @@ extern crate elna_auth_macros;
 
 mod database;
 use candid::Principal;
-use database::db::DB;
+use database::audit::{self, AuditFilter, AuditPage, Operation};
+use database::batch::{InsertMode, InsertReport};
+use database::collection::CollectionInfo;
+use database::db::{Records, DB};
 use database::error::Error;
+use database::export::{ExportHeader, ExportPage, ExportRecord, IndexChunk};
+use database::field::{FieldQuery, VectorFieldSpec};
+use database::http::{HttpRequest, HttpResponse};
+use database::index::{Metric, Precision};
 use database::memory::get_upgrades_memory;
-use database::users::{ADMINS, OWNER};
-use elna_auth_macros::check_authorization;
+use database::metrics::{self, CanisterMetrics};
+use database::namespace::{self, NamespaceInfo};
+use database::operations;
+use database::quota::Quota;
+use database::rate_limit::{Endpoint, EndpointRateLimit, RateLimit};
+use database::snapshot::SnapshotInfo;
+use database::sparse::{HybridQuery, SparseVector};
+use database::state;
+use database::state::UpgradeReport;
+use database::users::{self, UpgradeArgs};
+use elna_auth_macros::{check_authorization, check_is_owner};
 use ic_cdk::{post_upgrade, pre_upgrade, query, update};
 use ic_cdk_macros::export_candid;
-use ic_stable_structures::writer::Writer;
-use ic_stable_structures::Memory as _;
 
 #[update]
 #[check_authorization]
 fn create_collection(name: String, dimension: usize) -> Result<(), Error> {
-    DB.with(|db| {
-        let mut db = db.borrow_mut();
-        db.create_collection(&name, dimension)
-    })
+    operations::create_collection(ic_cdk::caller(), &name, dimension)
+}
+
+/// Creates a collection whose records carry, besides their primary vector of
+/// `dimension` compared by `metric`, one vector for each of the named `fields`.
+#[update]
+#[check_authorization]
+fn create_collection_with_fields(
+    name: String,
+    dimension: usize,
+    metric: Metric,
+    fields: Vec<VectorFieldSpec>,
+) -> Result<(), Error> {
+    operations::create_collection_with_fields(ic_cdk::caller(), &name, dimension, metric, fields)
 }
 
 #[update]
@@ -30,12 +54,14 @@ fn create_index(
     embeddings: Vec<Vec<f32>>,
     file_name: String,
 ) -> Result<(), Error> {
-    DB.with(|db| {
-        let mut db = db.borrow_mut();
-        db.create_collection(&name, dimension);
-        db.insert_into_collection(&name, embeddings, docs, file_name);
-        db.build_index(&name)
-    })
+    operations::create_index(
+        ic_cdk::caller(),
+        &name,
+        dimension,
+        docs,
+        embeddings,
+        file_name,
+    )
 }
 
 #[update]
@@ -46,103 +72,298 @@ fn insert(
     values: Vec<String>,
     file_name: String,
 ) -> Result<(), Error> {
-    DB.with(|db| {
-        let mut db = db.borrow_mut();
-        db.insert_into_collection(&name, keys, values, file_name)
-    })
+    operations::insert(ic_cdk::caller(), &name, keys, values, file_name)
+}
+
+/// Inserts records into a collection with named vector fields. `fields` holds
+/// a `(field, vectors)` pair for every field, with one vector per record.
+#[update]
+#[check_authorization]
+fn insert_with_fields(
+    name: String,
+    keys: Vec<Vec<f32>>,
+    fields: Vec<(String, Vec<Vec<f32>>)>,
+    values: Vec<String>,
+    file_name: String,
+) -> Result<(), Error> {
+    operations::insert_with_fields(ic_cdk::caller(), &name, keys, fields, values, file_name)
+}
+
+/// Inserts records like `insert_with_fields`, reporting the outcome of each.
+/// In `BestEffort` mode invalid records are reported and left out instead of
+/// failing the batch.
+#[update]
+#[check_authorization]
+fn insert_batch(
+    name: String,
+    keys: Vec<Vec<f32>>,
+    fields: Vec<(String, Vec<Vec<f32>>)>,
+    values: Vec<String>,
+    file_name: String,
+    mode: InsertMode,
+) -> Result<InsertReport, Error> {
+    let records = Records {
+        keys,
+        fields,
+        values,
+        ..Records::default()
+    };
+    operations::insert_batch(ic_cdk::caller(), &name, records, file_name, mode)
+}
+
+/// Inserts records with a sparse vector each, alongside their primary vectors
+/// and the vectors of any named fields.
+#[update]
+#[check_authorization]
+fn insert_sparse(
+    name: String,
+    keys: Vec<Vec<f32>>,
+    fields: Vec<(String, Vec<Vec<f32>>)>,
+    sparse: Vec<SparseVector>,
+    values: Vec<String>,
+    file_name: String,
+) -> Result<(), Error> {
+    let caller = ic_cdk::caller();
+    operations::insert_sparse(caller, &name, keys, fields, sparse, values, file_name)
+}
+
+/// Inserts records given as bags of token vectors, such as ColBERT embeddings.
+/// Each record is indexed by the mean of its tokens.
+#[update]
+#[check_authorization]
+fn insert_multi_vector(
+    name: String,
+    tokens: Vec<Vec<Vec<f32>>>,
+    values: Vec<String>,
+    file_name: String,
+) -> Result<(), Error> {
+    operations::insert_multi_vector(ic_cdk::caller(), &name, tokens, values, file_name)
+}
+
+/// Converts the stored vectors of a collection to `precision`, e.g. `F16` to
+/// halve their memory. Inserted vectors are converted on the way in and
+/// distances are computed in f32.
+#[update]
+#[check_authorization]
+fn set_precision(name: String, precision: Precision) -> Result<(), Error> {
+    operations::set_precision(ic_cdk::caller(), &name, precision)
+}
+
+/// Turns normalization of a collection's vectors to unit length on or off.
+/// Turning it on normalizes the stored vectors and rebuilds the index; it
+/// fails with `InvalidVector` if a stored vector is zero.
+#[update]
+#[check_authorization]
+fn set_normalize(name: String, normalize: bool) -> Result<(), Error> {
+    operations::set_normalize(ic_cdk::caller(), &name, normalize)
 }
 
 #[update]
 #[check_authorization]
 fn build_index(name: String) -> Result<(), Error> {
-    DB.with(|db| {
-        let mut db = db.borrow_mut();
-        db.build_index(&name)
-    })
+    operations::build_index(ic_cdk::caller(), &name)
 }
 
 #[update]
 #[check_authorization]
 fn delete_collection(name: String) -> Result<(), Error> {
-    DB.with(|db| {
+    operations::delete_collection(ic_cdk::caller(), &name)
+}
+
+/// Renames the collection `name` to `new_name`, failing with
+/// `UniqueViolation` if `new_name` is taken and with `InvalidName` if it lies
+/// in another namespace.
+#[update]
+#[check_authorization]
+fn rename_collection(name: String, new_name: String) -> Result<(), Error> {
+    operations::rename_collection(ic_cdk::caller(), &name, &new_name)
+}
+
+/// Copies the collection `name` to the new collection `new_name`. The index is
+/// copied too if `with_index` is set; otherwise the copy has to be built.
+#[update]
+#[check_authorization]
+fn clone_collection(name: String, new_name: String, with_index: bool) -> Result<(), Error> {
+    operations::clone_collection(ic_cdk::caller(), &name, &new_name, with_index)
+}
+
+/// Points `alias` at `collection`, creating the alias or switching it over in
+/// one step. Reads and writes through the alias reach the current target.
+/// Both must be in the same namespace.
+#[update]
+#[check_authorization]
+fn set_alias(alias: String, collection: String) -> Result<(), Error> {
+    operations::set_alias(ic_cdk::caller(), &alias, &collection)
+}
+
+#[update]
+#[check_authorization]
+fn delete_alias(alias: String) -> Result<(), Error> {
+    operations::delete_alias(ic_cdk::caller(), &alias)
+}
+
+/// Returns the `(alias, collection)` pairs visible to the caller.
+#[query]
+#[check_authorization]
+fn get_aliases() -> Result<Vec<(String, String)>, Error> {
+    operations::get_aliases(ic_cdk::caller())
+}
+
+/// Copies the collection `name` into a new snapshot called `label`.
+#[update]
+#[check_authorization]
+fn create_snapshot(name: String, label: String) -> Result<(), Error> {
+    operations::create_snapshot(ic_cdk::caller(), &name, &label)
+}
+
+/// Lists the snapshots of the collection `name`, which may have been deleted since.
+#[query]
+#[check_authorization]
+fn list_snapshots(name: String) -> Result<Vec<SnapshotInfo>, Error> {
+    operations::list_snapshots(ic_cdk::caller(), &name)
+}
+
+/// Rolls the collection `name` back to the snapshot `label`, recreating it if
+/// it was deleted.
+#[update]
+#[check_authorization]
+fn restore_snapshot(name: String, label: String) -> Result<(), Error> {
+    operations::restore_snapshot(ic_cdk::caller(), &name, &label)
+}
+
+/// Creates the collection `target` from the snapshot `label` of the collection `name`.
+#[update]
+#[check_authorization]
+fn clone_snapshot(name: String, label: String, target: String) -> Result<(), Error> {
+    operations::clone_snapshot(ic_cdk::caller(), &name, &label, &target)
+}
+
+#[update]
+#[check_authorization]
+fn delete_snapshot(name: String, label: String) -> Result<(), Error> {
+    operations::delete_snapshot(ic_cdk::caller(), &name, &label)
+}
+
+#[update]
+#[check_is_owner]
+fn set_collection_quota(name: String, quota: Quota) -> Result<(), Error> {
+    let result = DB.with(|db| {
         let mut db = db.borrow_mut();
-        db.delete_collection(&name)
-    })
+        db.set_collection_quota(&name, quota)
+    });
+    audit::record(Operation::SetQuota, &name, &result);
+    result
 }
 
 #[query]
 #[check_authorization]
 fn query(name: String, q: Vec<f32>, limit: i32) -> Result<Vec<String>, Error> {
-    DB.with(|db| {
-        let mut db = db.borrow_mut();
-        let result = db.query(&name, q, limit);
-        match result {
-            Ok(data) => {
-                // Extract the Vec<(f32, String)> from the Ok variant
-                let (_, strings): (Vec<_>, Vec<_>) = data.into_iter().unzip();
-                // println!("Floats: {:?}", floats);
-                Ok(strings)
-            }
-            Err(error) => {
-                println!("Error: {}", error);
-                Err(Error::NotFound)
-            }
-        }
-    })
+    let caller = ic_cdk::caller();
+    namespace::charge_query(&caller, false)?;
+    let results = operations::query(caller, &name, q, limit)?;
+    Ok(results.into_iter().map(|(_, doc)| doc).collect())
+}
+
+/// Searches the vector fields named in `queries`, the primary vector being the
+/// field `"default"`. Several fields are ranked by the weighted sum of their
+/// scores.
+#[query]
+#[check_authorization]
+fn query_fields(name: String, queries: Vec<FieldQuery>, limit: i32) -> Result<Vec<String>, Error> {
+    let caller = ic_cdk::caller();
+    namespace::charge_query(&caller, false)?;
+    let results = operations::query_fields(caller, &name, queries, limit)?;
+    Ok(results.into_iter().map(|(_, doc)| doc).collect())
+}
+
+/// Searches by dense vector fields and a sparse vector, ranking by the
+/// weighted sum of the field scores and the sparse dot product.
+#[query]
+#[check_authorization]
+fn query_hybrid(name: String, query: HybridQuery, limit: i32) -> Result<Vec<String>, Error> {
+    let caller = ic_cdk::caller();
+    namespace::charge_query(&caller, false)?;
+    let results = operations::query_hybrid(caller, &name, query, limit)?;
+    Ok(results.into_iter().map(|(_, doc)| doc).collect())
+}
+
+/// Searches by a bag of query token vectors: candidates from the HNSW index,
+/// found by the mean of the tokens, are reranked by late-interaction MaxSim.
+#[query]
+#[check_authorization]
+fn query_multi_vector(
+    name: String,
+    tokens: Vec<Vec<f32>>,
+    limit: i32,
+) -> Result<Vec<String>, Error> {
+    let caller = ic_cdk::caller();
+    namespace::charge_query(&caller, false)?;
+    let results = operations::query_multi_vector(caller, &name, tokens, limit)?;
+    Ok(results.into_iter().map(|(_, doc)| doc).collect())
 }
 
 #[query]
 #[check_authorization]
 fn get_collections() -> Result<Vec<String>, Error> {
-    DB.with(|db| {
-        let db = db.borrow();
-        Ok(db.get_all_collections())
-    })
+    operations::get_collections(ic_cdk::caller())
 }
 
 #[query]
 #[check_authorization]
 fn get_docs(index_name: String) -> Result<Vec<String>, Error> {
+    let index_name = operations::resolved_name(&ic_cdk::caller(), &index_name)?;
     DB.with(|db| {
         let mut db = db.borrow_mut();
         db.get_docs(&index_name)
     })
 }
 
+#[query]
+#[check_authorization]
+fn collection_info(name: String) -> Result<CollectionInfo, Error> {
+    operations::collection_info(ic_cdk::caller(), &name)
+}
+
+/// Returns a page of the collection in the export format described in the
+/// README. Pass the returned `next_offset` to fetch the following page.
+#[query]
+#[check_authorization]
+fn export_collection(name: String, offset: u64, limit: u64) -> Result<ExportPage, Error> {
+    operations::export_collection(ic_cdk::caller(), &name, offset, limit)
+}
+
+/// Returns up to `length` bytes of the encoded HNSW index starting at
+/// `offset`, to be uploaded with `import_index_chunk`.
+#[query]
+#[check_authorization]
+fn export_index(name: String, offset: u64, length: u64) -> Result<IndexChunk, Error> {
+    operations::export_index(ic_cdk::caller(), &name, offset, length)
+}
+
+/// Returns memory usage, the cycles balance and the call counters. Query
+/// calls cannot persist state, so searches made by Candid query calls are
+/// not counted; only those served by an update call are.
+#[query]
+#[check_authorization]
+fn get_metrics() -> Result<CanisterMetrics, Error> {
+    Ok(metrics::collect())
+}
+
 #[pre_upgrade]
 fn pre_upgrade() {
-    // Serialize the state.
-    // This example is using CBOR, but you can use any data format you like.
-    let mut state_bytes = vec![];
-    DB.with(|s| ciborium::ser::into_writer(&*s.borrow(), &mut state_bytes))
-        .expect("failed to encode state");
-
-    // Write the length of the serialized bytes to memory, followed by the
-    // by the bytes themselves.
-    let len = state_bytes.len() as u32;
     let mut memory = get_upgrades_memory();
-    let mut writer = Writer::new(&mut memory, 0);
-    writer.write(&len.to_le_bytes()).unwrap();
-    writer.write(&state_bytes).unwrap()
+    DB.with(|db| state::save(&mut memory, &db.borrow()));
 }
 
 // A post-upgrade hook for deserializing the data back into the heap.
 #[post_upgrade]
-fn post_upgrade(owner: Principal) {
-    OWNER.with(|o| *o.borrow_mut() = owner.to_string());
-
-    let memory = get_upgrades_memory();
-    // Read the length of the state bytes.
-    let mut state_len_bytes = [0; 4];
-    memory.read(0, &mut state_len_bytes);
-    let state_len = u32::from_le_bytes(state_len_bytes) as usize;
-
-    // Read the bytes
-    let mut state_bytes = vec![0; state_len];
-    memory.read(4, &mut state_bytes);
+fn post_upgrade(args: Option<UpgradeArgs>) {
+    users::apply_upgrade_args(args);
 
-    // Deserialize and set the state.
-    let state = ciborium::de::from_reader(&*state_bytes).expect("failed to decode state");
+    // Trapping here rolls the upgrade back, leaving the previous release and
+    // its state in place.
+    let state = state::load(&get_upgrades_memory())
+        .unwrap_or_else(|error| ic_cdk::trap(&error.to_string()));
     DB.with(|s| *s.borrow_mut() = state);
 }
 